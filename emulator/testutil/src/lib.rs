@@ -0,0 +1,103 @@
+//! The rv-testutil crate assembles RISC-V code with clang and llvm-objcopy for the tests of every
+//! version of the emulator, so that they all build their test programs with the same flags. Each
+//! version runs the result on its own `Cpu`.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub fn generate_rv_assembly(c_src: &str) {
+    let cc = "clang";
+    let output = Command::new(cc).arg("-S")
+                        .arg(c_src)
+                        .arg("-nostdlib")
+                        .arg("-march=rv64g")
+                        .arg("-mabi=lp64")
+                        .arg("--target=riscv64")
+                        .arg("-mno-relax")
+                        .output()
+                        .expect("Failed to generate rv assembly");
+    println!("{}", String::from_utf8_lossy(&output.stderr));
+}
+
+pub fn generate_rv_obj(assembly: &str) {
+    let cc = "clang";
+    let pieces: Vec<&str> = assembly.split(".").collect();
+    let output = Command::new(cc).arg("-Wl,-Ttext=0x0")
+                        .arg("-nostdlib")
+                        .arg("-march=rv64g")
+                        .arg("-mabi=lp64")
+                        .arg("--target=riscv64")
+                        .arg("-mno-relax")
+                        .arg("-o")
+                        .arg(pieces[0])
+                        .arg(assembly)
+                        .output()
+                        .expect("Failed to generate rv object");
+    println!("{}", String::from_utf8_lossy(&output.stderr));
+}
+
+pub fn generate_rv_elf(assembly: &str) {
+    let cc = "clang";
+    let pieces: Vec<&str> = assembly.split(".").collect();
+    let output = Command::new(cc).arg("-Wl,-Ttext=0x80000000")
+                        .arg("-nostdlib")
+                        .arg("-march=rv64g")
+                        .arg("-mabi=lp64")
+                        .arg("--target=riscv64")
+                        .arg("-mno-relax")
+                        .arg("-o")
+                        .arg(pieces[0])
+                        .arg(assembly)
+                        .output()
+                        .expect("Failed to generate rv elf");
+    println!("{}", String::from_utf8_lossy(&output.stderr));
+}
+
+pub fn generate_rv_binary(obj: &str) {
+    let objcopy = "llvm-objcopy";
+    let output = Command::new(objcopy).arg("-O")
+                            .arg("binary")
+                            .arg(obj)
+                            .arg(obj.to_owned() + ".bin")
+                            .output()
+                            .expect("Failed to generate rv binary");
+    println!("{}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// Tests run in parallel, so every assembly gets files of its own.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Write `code` to a new assembly file, and return its name without the `.s` suffix.
+fn write_assembly(code: &str) -> String {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let name = format!("test_rv_{}_{}", std::process::id(), id);
+    let mut file = File::create(name.clone() + ".s").unwrap();
+    file.write_all(code.as_bytes()).unwrap();
+    name
+}
+
+/// Read `output` and remove every file generated for `name`.
+fn read_and_clean(name: &str, output: &str) -> Vec<u8> {
+    let bytes = fs::read(output).unwrap();
+    for suffix in [".s", "", ".bin"] {
+        let _ = fs::remove_file(name.to_owned() + suffix);
+    }
+    bytes
+}
+
+/// Assemble `code` into a flat binary to be loaded at DRAM_BASE.
+pub fn compile(code: &str) -> Vec<u8> {
+    let name = write_assembly(code);
+    generate_rv_obj(&(name.clone() + ".s"));
+    generate_rv_binary(&name);
+    read_and_clean(&name, &(name.clone() + ".bin"))
+}
+
+/// Assemble and link `code` into an ELF executable whose text starts at DRAM_BASE.
+pub fn compile_elf(code: &str) -> Vec<u8> {
+    let name = write_assembly(code);
+    generate_rv_elf(&(name.clone() + ".s"));
+    read_and_clean(&name, &name)
+}
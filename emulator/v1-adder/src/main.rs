@@ -1,4 +1,5 @@
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
@@ -11,6 +12,21 @@ struct Cpu {
     regs: [u64; 32],
     pc: u64,
     dram: Vec<u8>,
+    /// Print a line to stderr for every illegal instruction.
+    trace: bool,
+}
+
+#[derive(Debug)]
+enum Exception {
+    IllegalInstruction(u32),
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exception::IllegalInstruction(inst) => write!(f, "Illegal instruction {:#x}", inst),
+        }
+    }
 }
 
 
@@ -25,7 +41,7 @@ impl Cpu {
     fn new(code: Vec<u8>) -> Self {
         let mut regs = [0; 32];
         regs[2] = DRAM_SIZE - 1;
-        Self {regs, pc: 0, dram: code}
+        Self {regs, pc: 0, dram: code, trace: false}
     }
 
     pub fn dump_registers(&mut self) {
@@ -61,7 +77,7 @@ impl Cpu {
         return inst;
     }
 
-    fn execute(&mut self, inst: u32) {
+    fn execute(&mut self, inst: u32) -> Result<(), Exception> {
         let opcode = inst & 0x7f;
         let rd = ((inst >> 7) & 0x1f) as usize;
         let rs1 = ((inst >> 15) & 0x1f) as usize;
@@ -81,9 +97,16 @@ impl Cpu {
             }
 
             _ => {
-                dbg!(format!("Invalid opcode: {:#x}", opcode)); 
+                if self.trace {
+                    let funct3 = (inst >> 12) & 0x7;
+                    let funct7 = inst >> 25;
+                    eprintln!("illegal instruction {:#010x} at pc {:#x}: opcode={:#x} funct3={:#x} funct7={:#x}",
+                        inst, self.pc, opcode, funct3, funct7);
+                }
+                return Err(Exception::IllegalInstruction(inst));
             }
         }
+        Ok(())
     }
 }
 
@@ -91,12 +114,16 @@ impl Cpu {
 
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // `--trace` reports every illegal instruction on stderr.
+    let trace = args.iter().any(|arg| arg == "--trace");
+    args.retain(|arg| arg != "--trace");
 
     if args.len() != 2 {
         println!(
             "Usage:\n\
-            - cargo run <filename>"
+            - cargo run [--trace] <filename>"
         );
         return Ok(());
     }
@@ -106,14 +133,59 @@ fn main() -> io::Result<()> {
     file.read_to_end(&mut code)?;
 
     let mut cpu = Cpu::new(code);
+    cpu.trace = trace;
 
 
     while cpu.pc < cpu.dram.len() as u64 {
         let inst = cpu.fetch();
-        cpu.execute(inst);
+        // Break the loop if an error occurs.
+        if let Err(e) = cpu.execute(inst) {
+            println!("{}", e);
+            break;
+        }
         cpu.pc += 4;
     }
     cpu.dump_registers();
 
     Ok(())
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_addi_negative() {
+        let mut cpu = Cpu::new(vec![]);
+        // addi x5, x0, -2048
+        cpu.execute(0x80000293).unwrap();
+        assert_eq!(cpu.regs[5], -2048i64 as u64);
+        // addi x6, x5, 2047
+        cpu.execute(0x7ff28313).unwrap();
+        assert_eq!(cpu.regs[6], -1i64 as u64);
+    }
+
+    #[test]
+    fn test_illegal_instruction_is_quiet() {
+        // The instruction is executed by this test running in a child process, so that its stderr
+        // can be checked.
+        if let Ok(trace) = env::var("RVEMU_TEST_CHILD") {
+            let mut cpu = Cpu::new(vec![]);
+            cpu.trace = trace == "trace";
+            assert!(matches!(cpu.execute(0xffffffff), Err(Exception::IllegalInstruction(0xffffffff))));
+            return;
+        }
+        let stderr = |trace: &str| {
+            let output = std::process::Command::new(env::current_exe().unwrap())
+                .args(["--exact", "test::test_illegal_instruction_is_quiet", "--nocapture"])
+                .env("RVEMU_TEST_CHILD", trace)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stderr).unwrap()
+        };
+        assert_eq!(stderr(""), "");
+        assert!(stderr("trace").contains("opcode=0x7f funct3=0x7 funct7=0x7f"));
+    }
+}
@@ -1,52 +1,333 @@
 //! The bus module contains the system bus which can access the memroy or memory-mapped peripheral
 //! devices.
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::any::Any;
+
 use crate::param::*;
 use crate::dram::Dram;
+use crate::memory::Memory;
 use crate::plic::Plic;
 use crate::clint::Clint;
+#[cfg(feature = "std")]
 use crate::uart::Uart;
 use crate::virtio::VirtioBlock;
+use crate::finisher::TestFinisher;
+use crate::rng::Rng;
 use crate::exception::*;
 
+/// A memory-mapped peripheral device. The bus routes an access to the device whose range
+/// `[base, base + size)` contains the address, and passes the address unchanged.
+pub trait Device: Any {
+    fn base(&self) -> u64;
+    fn size(&self) -> u64;
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception>;
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception>;
+    /// Append the state of the device to `data`, e.g. with `put_word`. A device without state
+    /// appends nothing.
+    fn snapshot(&self, _data: &mut Vec<u8>) {}
+    /// Restore the state from the bytes that `snapshot` appended.
+    fn restore(&mut self, _data: &[u8]) {}
+    /// Put the device back into its power-on state. What the host configured, e.g. the disk image
+    /// or the seed, is kept.
+    fn reset(&mut self) {}
+}
+
+/// Append `word` to a snapshot as a little-endian u64.
+pub fn put_word(data: &mut Vec<u8>, word: u64) {
+    data.extend_from_slice(&word.to_le_bytes());
+}
+
+/// Reads back the words of a snapshot written by `put_word`.
+pub struct SnapshotReader<'a>(&'a [u8]);
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    /// The next word. Panic if the snapshot is too short.
+    pub fn word(&mut self) -> u64 {
+        assert!(self.0.len() >= 8, "Invalid snapshot");
+        let (word, rest) = self.0.split_at(8);
+        self.0 = rest;
+        u64::from_le_bytes(word.try_into().unwrap())
+    }
+
+    /// The bytes that have not been read.
+    pub fn rest(self) -> &'a [u8] {
+        self.0
+    }
+
+    /// The next `len` bytes. Panic if the snapshot is too short.
+    pub fn bytes(&mut self, len: usize) -> &'a [u8] {
+        assert!(self.0.len() >= len, "Invalid snapshot");
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        bytes
+    }
+}
+
+/// The base address of every device on the bus. The size of each window is fixed by the registers
+/// of its device. The default is the layout of `param`, which follows QEMU's virt machine.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub clint: u64,
+    pub plic: u64,
+    pub uart: u64,
+    pub virtio: u64,
+    pub test_finisher: u64,
+    pub rng: u64,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self {
+            clint: CLINT_BASE,
+            plic: PLIC_BASE,
+            uart: UART_BASE,
+            virtio: VIRTIO_BASE,
+            test_finisher: TEST_FINISHER_BASE,
+            rng: RNG_BASE,
+        }
+    }
+}
+
+/// The indices of the built-in devices that are polled after every instruction, so that they are
+/// not searched for in the device map. The uart comes last, since it only exists with std.
+const CLINT: usize = 0;
+const PLIC: usize = 1;
+const VIRTIO: usize = 2;
+#[cfg(feature = "std")]
+const UART: usize = 3;
+
 pub struct Bus {
-    dram: Dram,
-    plic: Plic,
-    clint: Clint,
-    pub uart: Uart,
-    pub virtio_blk: VirtioBlock,
+    /// The dram is accessed by every fetch, so it is checked before searching the device map.
+    dram: Box<dyn Memory>,
+    devices: Vec<Box<dyn Device>>,
 }
 
 
 // Bus is used to transfer data, so check data access size here is appropriate
 impl Bus {
-    pub fn new(code: Vec<u8>, disk_image: Vec<u8>) -> Bus {
-        Self { 
-            dram: Dram::new(code),
-            clint: Clint::new(),
-            plic: Plic::new(),
-            uart: Uart::new(),
-            virtio_blk: VirtioBlock::new(disk_image),
+    pub fn new(code: Vec<u8>, disk_image: Vec<u8>, map: MemoryMap) -> Bus {
+        Self::with_memory(Box::new(Dram::new(code)), disk_image, map)
+    }
+
+    /// Create a bus whose dram is backed by `memory` instead of a `Dram`.
+    pub fn with_memory(memory: Box<dyn Memory>, disk_image: Vec<u8>, map: MemoryMap) -> Bus {
+        let mut devices: Vec<Box<dyn Device>> = vec![
+            Box::new(Clint::new(map.clint)),
+            Box::new(Plic::new(map.plic)),
+            Box::new(VirtioBlock::new(map.virtio, disk_image)),
+        ];
+        // The uart is attached to stdin/stdout, so it only exists with std.
+        #[cfg(feature = "std")]
+        devices.push(Box::new(Uart::new(map.uart)));
+        devices.push(Box::new(TestFinisher::new(map.test_finisher)));
+        devices.push(Box::new(Rng::new(map.rng)));
+        Self {
+            dram: memory,
+            devices,
+        }
+    }
+
+    /// Add a device to the device map. Fail if its range overlaps the dram or another device.
+    pub fn register_device(&mut self, dev: Box<dyn Device>) -> Result<(), String> {
+        let (base, size) = (dev.base(), dev.size());
+        let end = match base.checked_add(size) {
+            Some(end) if size > 0 => end,
+            _ => return Err(format!("Invalid device range {:#x} + {:#x}", base, size)),
+        };
+        let overlaps = |other_base: u64, other_end: u64| base < other_end && other_base < end;
+        if overlaps(DRAM_BASE, DRAM_END + 1) {
+            return Err(format!("Device at {:#x} overlaps the dram", base));
+        }
+        if let Some(other) = self.devices.iter().find(|other| overlaps(other.base(), other.base() + other.size())) {
+            return Err(format!("Device at {:#x} overlaps the device at {:#x}", base, other.base()));
+        }
+        self.devices.push(dev);
+        Ok(())
+    }
+
+    /// Get the first device of type `T` in the device map.
+    pub fn device<T: Device>(&mut self) -> Option<&mut T> {
+        self.devices.iter_mut().find_map(|dev| (dev.as_mut() as &mut dyn Any).downcast_mut::<T>())
+    }
+
+    /// Get the first device of type `T` like `device`, for a caller that cannot borrow the bus
+    /// mutably.
+    pub fn device_ref<T: Device>(&self) -> Option<&T> {
+        self.devices.iter().find_map(|dev| (dev.as_ref() as &dyn Any).downcast_ref::<T>())
+    }
+
+    /// Get the built-in device of type `T` at `index` of the device map.
+    fn builtin<T: Device>(&mut self, index: usize) -> &mut T {
+        (self.devices[index].as_mut() as &mut dyn Any).downcast_mut::<T>().unwrap()
+    }
+
+    pub fn clint(&mut self) -> &mut Clint {
+        self.builtin(CLINT)
+    }
+
+    pub fn plic(&mut self) -> &mut Plic {
+        self.builtin(PLIC)
+    }
+
+    /// Get the PLIC like `plic`, for a caller that cannot borrow the bus mutably.
+    pub fn plic_ref(&self) -> &Plic {
+        (self.devices[PLIC].as_ref() as &dyn Any).downcast_ref().unwrap()
+    }
+
+    #[cfg(feature = "std")]
+    pub fn uart(&mut self) -> &mut Uart {
+        self.builtin(UART)
+    }
+
+    pub fn virtio_blk(&mut self) -> &mut VirtioBlock {
+        self.builtin(VIRTIO)
+    }
+
+    pub fn rng(&mut self) -> &mut Rng {
+        self.device::<Rng>().unwrap()
+    }
+
+    fn find_device(&mut self, addr: u64) -> Option<&mut Box<dyn Device>> {
+        self.devices.iter_mut().find(|dev| dev.base() <= addr && addr - dev.base() < dev.size())
+    }
+
+    /// Append the dram contents to `data`, then the state of every device prefixed by its length.
+    /// The disk image is not saved, it belongs to the host like the terminal of the uart.
+    pub fn snapshot(&self, data: &mut Vec<u8>) {
+        self.dram.snapshot(data);
+        for dev in self.devices.iter() {
+            let mut state = Vec::new();
+            dev.snapshot(&mut state);
+            put_word(data, state.len() as u64);
+            data.extend_from_slice(&state);
+        }
+    }
+
+    /// Restore the dram contents and the devices from `data` produced by `snapshot`, on a bus with
+    /// the same devices.
+    pub fn restore(&mut self, data: &[u8]) {
+        let mut reader = SnapshotReader::new(data);
+        // The dram snapshot keeps its own length prefix.
+        let len = reader.word() as usize;
+        reader.bytes(len);
+        self.dram.restore(&data[..8 + len]);
+        for dev in self.devices.iter_mut() {
+            let len = reader.word() as usize;
+            dev.restore(reader.bytes(len));
+        }
+        assert!(reader.rest().is_empty(), "Invalid snapshot");
+    }
+
+    /// Reset every device. The dram keeps its contents.
+    pub fn reset(&mut self) {
+        for dev in self.devices.iter_mut() {
+            dev.reset();
         }
     }
+
+    /// Make a load of a dram byte that has not been written fail with an access fault, to catch
+    /// reads of uninitialized memory. Off by default, since every access has to check a bitmap.
+    pub fn detect_uninit(&mut self, enable: bool) {
+        self.dram.detect_uninit(enable);
+    }
+
+    /// Copy the dram at `addr` into `buf`, for host tools. Devices cannot be read this way.
+    pub fn read_phys(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        self.dram.read(addr, buf)
+    }
+
+    /// Copy `buf` to the dram at `addr`, for host tools. Devices cannot be written this way.
+    pub fn write_phys(&mut self, addr: u64, buf: &[u8]) -> Result<(), Exception> {
+        self.dram.write(addr, buf)
+    }
+
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        match addr {
-            CLINT_BASE..=CLINT_END => self.clint.load(addr, size),
-            PLIC_BASE..=PLIC_END => self.plic.load(addr, size),
-            DRAM_BASE..=DRAM_END => self.dram.load(addr, size),
-            UART_BASE..=UART_END => self.uart.load(addr, size),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio_blk.load(addr, size),
-            _ => Err(Exception::LoadAccessFault(addr)),
+        if let DRAM_BASE..=DRAM_END = addr {
+            return self.dram.load(addr, size);
+        }
+        match self.find_device(addr) {
+            Some(dev) => dev.load(addr, size),
+            None => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        match addr {
-            CLINT_BASE..=CLINT_END => self.clint.store(addr, size, value),
-            PLIC_BASE..=PLIC_END => self.plic.store(addr, size, value),
-            DRAM_BASE..=DRAM_END => self.dram.store(addr, size, value),
-            UART_BASE..=UART_END => self.uart.store(addr, size, value),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio_blk.store(addr, size, value),
-            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        if let DRAM_BASE..=DRAM_END = addr {
+            return self.dram.store(addr, size, value);
+        }
+        match self.find_device(addr) {
+            Some(dev) => dev.store(addr, size, value),
+            None => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unmapped_hole() {
+        let mut bus = Bus::new(vec![], vec![], MemoryMap::default());
+        // between the uart and the virtio block device.
+        let addr = UART_END + 1;
+        assert!(matches!(bus.load(addr, 8), Err(Exception::LoadAccessFault(a)) if a == addr));
+        assert!(matches!(bus.store(addr, 8, 0), Err(Exception::StoreAMOAccessFault(a)) if a == addr));
+        assert!(bus.load(UART_BASE + UART_LSR, 8).is_ok());
+    }
+
+    #[test]
+    fn test_relocate_uart() {
+        let map = MemoryMap { uart: 0x2000_0000, ..MemoryMap::default() };
+        let mut bus = Bus::new(vec![], vec![], map);
+        bus.store(map.uart + UART_LCR, 8, 0x83).unwrap();
+        assert_eq!(bus.load(map.uart + UART_LCR, 8).unwrap(), 0x83);
+        assert_eq!(bus.load(map.uart + UART_LSR, 8).unwrap() as u8 & MASK_UART_LSR_TX, MASK_UART_LSR_TX);
+        assert!(matches!(bus.load(UART_BASE + UART_LCR, 8), Err(Exception::LoadAccessFault(_))));
+    }
+
+    struct Window {
+        base: u64,
+        size: u64,
+    }
+
+    impl Device for Window {
+        fn base(&self) -> u64 {
+            self.base
+        }
+
+        fn size(&self) -> u64 {
+            self.size
+        }
+
+        fn load(&mut self, _addr: u64, _size: u64) -> Result<u64, Exception> {
+            Ok(0)
+        }
+
+        fn store(&mut self, _addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_register_overlap() {
+        let mut bus = Bus::new(vec![], vec![], MemoryMap::default());
+        let overlapping = [
+            (UART_BASE + 0x80, 0x100),
+            (UART_BASE - 0x10, 0x11),
+            (DRAM_END, 0x10),
+            (PLIC_BASE - 0x1000, PLIC_SIZE + 0x2000),
+            (0x4000_0000, 0),
+        ];
+        for (base, size) in overlapping {
+            assert!(bus.register_device(Box::new(Window { base, size })).is_err(), "{:#x}", base);
+        }
+        // right after the uart.
+        assert!(bus.register_device(Box::new(Window { base: UART_END + 1, size: 0x100 })).is_ok());
+    }
+}
@@ -2,6 +2,9 @@
 //! block holds memory-mapped control and status registers associated with
 //! software and timer interrupts. It generates per-hart software interrupts and timer.
 
+use alloc::vec::Vec;
+use core::time::Duration;
+
 use crate::bus::*;
 use crate::exception::*;
 use crate::param::*;
@@ -9,35 +12,171 @@ use crate::param::*;
 use Exception::*;
 
 pub struct Clint {
+    base: u64,
+    msip: [u32; MAX_HARTS],
+    /// The timer, which advances by `ticks_per_inst` per executed instruction.
     mtime: u64,
-    mtimecmp: u64,
+    /// A hart has a pending timer interrupt while mtime >= its mtimecmp.
+    mtimecmp: [u64; MAX_HARTS],
+    /// The frequency of mtime that the guest is told about, in Hz.
+    timebase_freq: u64,
+    ticks_per_inst: u64,
 }
 
 impl Clint {
-    pub fn new() -> Self {
-        Self { mtime: 0, mtimecmp: 0 }
+    /// Create a CLINT whose timer runs at TIMEBASE_FREQ and ticks once per instruction, i.e. the
+    /// guest sees a hart that executes 10 million instructions per second. The default address is
+    /// `CLINT_BASE`.
+    pub fn new(base: u64) -> Self {
+        Self {
+            base,
+            msip: [0; MAX_HARTS],
+            mtime: 0,
+            mtimecmp: [0; MAX_HARTS],
+            timebase_freq: TIMEBASE_FREQ,
+            ticks_per_inst: 1,
+        }
+    }
+
+    /// The frequency of mtime in Hz. The timebase-frequency of the device tree given to the guest
+    /// should match it, or the guest's timeouts are scaled by the ratio.
+    pub fn timebase_freq(&self) -> u64 {
+        self.timebase_freq
+    }
+
+    /// Set the frequency of mtime reported by `timebase_freq`, and how many ticks elapse per
+    /// executed instruction. Together they pick the speed of the hart as seen by the guest, at
+    /// `timebase_freq / ticks_per_inst` instructions per second.
+    pub fn set_timebase(&mut self, timebase_freq: u64, ticks_per_inst: u64) {
+        self.timebase_freq = timebase_freq;
+        self.ticks_per_inst = ticks_per_inst;
+    }
+
+    /// Advance mtime by the ticks of one instruction.
+    pub fn tick(&mut self) {
+        self.mtime = self.mtime.wrapping_add(self.ticks_per_inst);
+    }
+
+    /// Advance mtime by the ticks of `duration` at the timebase frequency, for the time the harts
+    /// spend waiting for an interrupt.
+    pub fn advance(&mut self, duration: Duration) {
+        let ticks = duration.as_nanos() * self.timebase_freq as u128 / 1_000_000_000;
+        self.mtime = self.mtime.wrapping_add(ticks as u64);
+    }
+
+    /// Return true if a timer interrupt is pending for hart `hartid`.
+    pub fn mtip(&self, hartid: usize) -> bool {
+        self.mtime >= self.mtimecmp[hartid]
+    }
+
+    /// The current value of the timer, which the `time` csr reads.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Return true if a software interrupt is pending for hart `hartid`. Only bit 0 of msip is used.
+    pub fn msip(&self, hartid: usize) -> bool {
+        self.msip[hartid] & 1 != 0
+    }
+
+    /// The address in the default map of the register at `addr`, which the register constants
+    /// name.
+    fn register(&self, addr: u64) -> u64 {
+        addr - self.base + CLINT_BASE
+    }
+
+    /// Return the hart whose msip register is at `addr`.
+    fn msip_index(addr: u64) -> Option<usize> {
+        let offset = addr.checked_sub(CLINT_MSIP)?;
+        if offset % 4 == 0 && offset / 4 < MAX_HARTS as u64 {
+            Some((offset / 4) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Return the hart whose mtimecmp register is at `addr`.
+    fn mtimecmp_index(addr: u64) -> Option<usize> {
+        let offset = addr.checked_sub(CLINT_MTIMECMP)?;
+        if offset % 8 == 0 && offset / 8 < MAX_HARTS as u64 {
+            Some((offset / 8) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl Device for Clint {
+    fn base(&self) -> u64 {
+        self.base
     }
-    
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+
+    fn size(&self) -> u64 {
+        CLINT_SIZE
+    }
+
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let reg = self.register(addr);
+        if let Some(hart) = Self::msip_index(reg) {
+            return match size {
+                32 => Ok(self.msip[hart] as u64),
+                _ => Err(LoadAccessFault(addr)),
+            };
+        }
         if size != 64 {
             return Err(LoadAccessFault(addr));
         }
-        match addr {
-            CLINT_MTIMECMP => Ok(self.mtimecmp),
+        if let Some(hart) = Self::mtimecmp_index(reg) {
+            return Ok(self.mtimecmp[hart]);
+        }
+        match reg {
             CLINT_MTIME => Ok(self.mtime),
             _ => Err(LoadAccessFault(addr)),
         }
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let reg = self.register(addr);
+        if let Some(hart) = Self::msip_index(reg) {
+            return match size {
+                32 => Ok(self.msip[hart] = value as u32 & 1),
+                _ => Err(StoreAMOAccessFault(addr)),
+            };
+        }
         if size != 64 {
             return Err(LoadAccessFault(addr));
         }
-        match addr {
-            CLINT_MTIMECMP => Ok(self.mtimecmp = value),
+        if let Some(hart) = Self::mtimecmp_index(reg) {
+            return Ok(self.mtimecmp[hart] = value);
+        }
+        match reg {
             CLINT_MTIME => Ok(self.mtime = value),
             _ => Err(StoreAMOAccessFault(addr)),
         }
     }
 
-}
\ No newline at end of file
+    fn snapshot(&self, data: &mut Vec<u8>) {
+        for &msip in self.msip.iter() {
+            put_word(data, msip as u64);
+        }
+        put_word(data, self.mtime);
+        for &mtimecmp in self.mtimecmp.iter() {
+            put_word(data, mtimecmp);
+        }
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let mut reader = SnapshotReader::new(data);
+        for msip in self.msip.iter_mut() {
+            *msip = reader.word() as u32;
+        }
+        self.mtime = reader.word();
+        for mtimecmp in self.mtimecmp.iter_mut() {
+            *mtimecmp = reader.word();
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self { timebase_freq: self.timebase_freq, ticks_per_inst: self.ticks_per_inst, ..Self::new(self.base) };
+    }
+}
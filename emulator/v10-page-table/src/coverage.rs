@@ -0,0 +1,95 @@
+//! The coverage module records which instructions the tests execute. Every instruction executed
+//! by `Cpu::execute` in a test build is recorded by its mnemonic, and `test_coverage` reports the
+//! implemented instructions that no test executes.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// The mnemonic of every instruction that `execute` implements, as `Instruction::name` spells it.
+pub const IMPLEMENTED: &[&str] = &[
+    "lb", "lh", "lw", "ld", "lbu", "lhu", "lwu",
+    "fence", "fence.i",
+    "addi", "slli", "slti", "sltiu", "xori", "srli", "srai", "ori", "andi",
+    "auipc",
+    "addiw", "slliw", "srliw", "sraiw",
+    "sb", "sh", "sw", "sd",
+    "amoadd.w", "amoadd.d", "amoswap.w", "amoswap.d",
+    "add", "mul", "sub", "sll", "slt", "sltu", "xor", "srl", "sra", "or", "and",
+    "lui",
+    "addw", "subw", "sllw", "srlw", "sraw", "divw", "divuw", "remw", "remuw",
+    "beq", "bne", "blt", "bge", "bltu", "bgeu",
+    "jalr", "jal",
+    "ecall", "ebreak", "sret", "mret", "wfi", "sfence.vma",
+    "csrrw", "csrrs", "csrrc", "csrrwi", "csrrsi", "csrrci",
+];
+
+/// The mnemonics executed in this process.
+static COVERED: Mutex<BTreeSet<&str>> = Mutex::new(BTreeSet::new());
+
+thread_local! {
+    /// The mnemonics executed by this thread, so that the lock of `COVERED` is only taken the
+    /// first time a test executes an instruction and not on every instruction.
+    static SEEN: RefCell<BTreeSet<&'static str>> = const { RefCell::new(BTreeSet::new()) };
+    /// Whether this thread records at all. A benchmark turns it off to measure `execute` alone.
+    static ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Turn the recording of the instructions executed by this thread on or off.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.with(|cell| cell.set(enabled));
+}
+
+/// Record that the instruction `name` was executed. If RVEMU_COVERAGE names a file, every newly
+/// covered mnemonic is also appended to it, since a test binary has no hook at exit.
+pub fn record(name: &'static str) {
+    if !ENABLED.with(Cell::get) || !SEEN.with(|seen| seen.borrow_mut().insert(name)) {
+        return;
+    }
+    if !COVERED.lock().unwrap().insert(name) {
+        return;
+    }
+    if let Ok(path) = env::var("RVEMU_COVERAGE") {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        writeln!(file, "{}", name).unwrap();
+    }
+}
+
+mod test {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_coverage() {
+        // The other tests run in a child process which appends their coverage to a file.
+        if env::var("RVEMU_COVERAGE").is_ok() {
+            return;
+        }
+        let path = env::temp_dir().join(format!("rvemu_coverage_{}", std::process::id()));
+        let output = Command::new(env::current_exe().unwrap())
+            .args(["--skip", "coverage::test::test_coverage", "--test-threads=4"])
+            .env("RVEMU_COVERAGE", &path)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        let covered = fs::read_to_string(&path).unwrap();
+        let covered: BTreeSet<&str> = covered.lines().collect();
+        fs::remove_file(&path).unwrap();
+
+        let (hit, missed): (Vec<&str>, Vec<&str>) =
+            IMPLEMENTED.iter().partition(|name| covered.contains(**name));
+        println!("covered {} of {} implemented instructions", hit.len(), IMPLEMENTED.len());
+        println!("not covered: {}", missed.join(" "));
+
+        let core = [
+            "add", "sub", "sll", "slt", "sltu", "xor", "srl", "sra", "or", "and", "addi", "slti",
+            "sltiu", "xori", "ori", "andi", "slli", "srli", "srai", "lui", "auipc", "addw", "subw",
+        ];
+        for name in core {
+            assert!(hit.contains(&name), "{} is not covered", name);
+        }
+    }
+}
@@ -1,28 +1,127 @@
 //! The cpu module contains `Cpu` and implementarion for it.
 
 #![allow(dead_code)]
-use std::mem::size_of;
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::mem::{offset_of, size_of};
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::{collections::HashMap, fs::File, io::{self, BufWriter, Write}, time::Instant};
+// There is no HashMap without std.
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
 
 use crate::bus::*;
+use crate::clint::Clint;
+use crate::dram::Dram;
+use crate::memory::Memory;
+#[cfg(feature = "std")]
+use crate::elf::*;
+#[cfg(feature = "std")]
+use crate::trace::TraceRecord;
+use crate::finisher::*;
 use crate::exception::*;
 use crate::interrupt::*;
 use crate::param::*;
 use crate::csr::*;
+use crate::instruction::{self, Instruction};
+use crate::rvc;
+use crate::stats::*;
+use crate::virtio::DiskError;
 use crate::virtqueue::*;
 
 
-// Riscv Privilege Mode
-type Mode = u64;
-const User: Mode = 0b00;
-const Supervisor: Mode = 0b01;
-const Machine: Mode = 0b11;
+/// Riscv Privilege Mode. The discriminant is the encoding used by the MPP field of mstatus, and
+/// by bits [9:8] of a csr address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Mode {
+    User = 0b00,
+    Supervisor = 0b01,
+    Machine = 0b11,
+}
+
+use Mode::*;
+
+impl From<Mode> for u64 {
+    fn from(mode: Mode) -> u64 {
+        mode as u64
+    }
+}
+
+impl TryFrom<u64> for Mode {
+    type Error = String;
+
+    /// Decode a mode from its bits. 0b10 is reserved (it would be the hypervisor mode).
+    fn try_from(bits: u64) -> Result<Self, Self::Error> {
+        match bits {
+            0b00 => Ok(User),
+            0b01 => Ok(Supervisor),
+            0b11 => Ok(Machine),
+            _ => Err(format!("Invalid privilege mode {:#b}", bits)),
+        }
+    }
+}
 
+#[derive(Clone, Copy)]
 pub enum AccessType {
     Instruction,
     Load,
     Store,
 }
 
+/// The data accesses that a watchpoint stops at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Both reads and writes.
+    Access,
+}
+
+/// A data access that touched a watched address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// The watched address.
+    pub addr: u64,
+    /// The pc of the instruction that made the access.
+    pub pc: u64,
+    /// Read or Write.
+    pub kind: WatchKind,
+    /// The value that was loaded or stored, as wide as the access.
+    pub value: u64,
+    /// The value that a store to the dram overwrote.
+    pub old: Option<u64>,
+}
+
+/// The reason why `Cpu::run` stopped.
+#[derive(Debug)]
+pub enum StopReason {
+    /// The guest powered off through the test finisher with an exit code.
+    Halted(i64),
+    /// A fatal exception happened. It has been trapped like any other exception.
+    FatalException(Exception),
+    /// The instruction budget is used up.
+    InstLimit,
+    /// The wall-clock budget is used up.
+    Timeout,
+    /// The virtio disk was handed a malformed request.
+    DeviceError(DiskError),
+    /// The last instruction accessed a watched address.
+    Watchpoint(WatchHit),
+    /// Every hart is in `wfi` with no interrupt enabled in mie, so none of them can wake up.
+    Idle,
+}
+
+/// How a `Cpu::run` ended.
+#[derive(Debug)]
+pub struct RunResult {
+    pub reason: StopReason,
+    /// The instructions executed by all harts during the run, including the ones that trapped.
+    /// The instruction that raised a fatal exception is not counted.
+    pub instret: u64,
+    /// The pc of the running hart when the run stopped.
+    pub pc: u64,
+}
+
 /// The `Cpu` struct that contains registers, a program coutner, system bus that connects
 /// peripheral devices, and control and status registers.
 pub struct Cpu {
@@ -35,12 +134,242 @@ pub struct Cpu {
     /// System bus that transfers data between CPU and peripheral devices.
     pub bus: Bus,
     /// Control and status registers. RISC-V ISA sets aside a 12-bit encoding space (csr[11:0]) for
-    /// up to 4096 CSRs.
-    pub csr: Csr,
+    /// up to 4096 CSRs. They are boxed so that switching harts is cheap.
+    pub csr: Box<Csr>,
     /// SV39 paging flag.
     pub enable_paging: bool,
     /// physical page number (PPN) × PAGE_SIZE (4096).
     pub page_table: u64,
+    /// Translation cache from a virtual page number to the physical address of its page and its
+    /// leaf PTE, whose permissions are checked on every access.
+    pub tlb: HashMap<u64, (u64, u64)>,
+    /// Set by `wfi`. `run` does not step the hart until an interrupt enabled in mie is pending,
+    /// while `step` resumes it right away.
+    pub waiting_for_interrupt: bool,
+    /// The id of the running hart, whose state is held in the fields above.
+    pub hartid: usize,
+    /// The state of every hart, indexed by hart id. The slot of the running hart is stale until it
+    /// is switched out.
+    pub harts: Vec<Hart>,
+    /// The exit code reported through the test finisher. `run` stops once it is set.
+    pub exit_code: Option<i64>,
+    /// The error of a malformed disk request, which the guest is not notified of. `run` stops once
+    /// it is set.
+    pub device_error: Option<DiskError>,
+    /// The virtual addresses whose loads and/or stores stop `run`.
+    pub watchpoints: Vec<(u64, WatchKind)>,
+    /// The access that hit a watchpoint, taken by `run` when it stops.
+    pub watch_hit: Option<WatchHit>,
+    /// The number of executed instructions by category.
+    pub stats: Stats,
+    /// Called by `ebreak` instead of raising a Breakpoint exception. Execution resumes at the
+    /// instruction after the `ebreak`.
+    pub on_breakpoint: Option<BreakpointHook>,
+    /// Called by an `ecall` in M-mode, e.g. to service a host call. If it returns true, the ecall
+    /// is handled and execution resumes at the next instruction, otherwise it raises
+    /// EnvironmentCallFromMMode as usual.
+    pub on_machine_ecall: Option<EcallHook>,
+    /// The enabled extensions as misa bits, e.g. EXT_C for the compressed instructions. An
+    /// instruction of a disabled extension is illegal, and `misa` reads the enabled ones. F and D
+    /// are not implemented, so setting them has no effect.
+    pub extensions: u64,
+    /// The size in bytes of the instruction being executed, 2 if it is compressed or 4 otherwise.
+    pub inst_len: u64,
+    /// Where `step` appends a record of every executed instruction, in the format of the `trace`
+    /// module. It is buffered, since a guest executes millions of instructions per second. A trace
+    /// that cannot be written is dropped instead of stopping the guest.
+    #[cfg(feature = "std")]
+    pub trace_file: Option<BufWriter<File>>,
+}
+
+/// A host handler of `ebreak`, see `Cpu::on_breakpoint`.
+pub type BreakpointHook = Box<dyn FnMut(&mut Cpu)>;
+
+/// A host handler of M-mode ecalls, see `Cpu::on_machine_ecall`.
+pub type EcallHook = Box<dyn FnMut(&mut Cpu) -> bool>;
+
+/// The per-core state of a hart. All harts share the bus of the `Cpu`.
+pub struct Hart {
+    pub regs: [u64; 32],
+    pub pc: u64,
+    pub mode: Mode,
+    pub csr: Box<Csr>,
+    pub enable_paging: bool,
+    pub page_table: u64,
+    pub tlb: HashMap<u64, (u64, u64)>,
+    pub waiting_for_interrupt: bool,
+}
+
+impl Hart {
+    /// Create a hart in its reset state. Every hart starts at DRAM_BASE in machine mode, and tells
+    /// who it is by `mhartid`.
+    pub fn new(hartid: usize) -> Self {
+        let mut regs = [0; 32];
+        regs[2] = DRAM_END;
+        let mut csr = Box::new(Csr::new());
+        csr.store(MHARTID, hartid as u64);
+        Self {
+            regs,
+            pc: DRAM_BASE,
+            mode: Machine,
+            csr,
+            enable_paging: false,
+            page_table: 0,
+            tlb: HashMap::new(),
+            waiting_for_interrupt: false,
+        }
+    }
+}
+
+/// Build a `Cpu` whose running hart starts in a preset state, e.g. for firmware that expects the
+/// boot loader to have set up some CSRs.
+pub struct CpuBuilder {
+    code: Vec<u8>,
+    disk_image: Vec<u8>,
+    nharts: usize,
+    csrs: Vec<(usize, u64)>,
+    mode: Option<Mode>,
+    pc: Option<u64>,
+    load_offset: u64,
+    detect_uninit: bool,
+    memory_map: MemoryMap,
+    memory: Option<Box<dyn Memory>>,
+}
+
+impl CpuBuilder {
+    pub fn new(code: Vec<u8>) -> Self {
+        Self { code, disk_image: vec![], nharts: 1, csrs: vec![], mode: None, pc: None, load_offset: 0, detect_uninit: false, memory_map: MemoryMap::default(), memory: None }
+    }
+
+    /// Load the code at `DRAM_BASE + offset` instead of DRAM_BASE, and start every hart there.
+    pub fn load_offset(mut self, offset: u64) -> Self {
+        self.load_offset = offset;
+        self
+    }
+
+    pub fn disk_image(mut self, disk_image: Vec<u8>) -> Self {
+        self.disk_image = disk_image;
+        self
+    }
+
+    /// Place the devices at the addresses of `map` instead of the default layout.
+    pub fn memory_map(mut self, map: MemoryMap) -> Self {
+        self.memory_map = map;
+        self
+    }
+
+    /// Back the dram with `memory` instead of a `Dram`, e.g. a `SparseMemory`.
+    pub fn memory(mut self, memory: Box<dyn Memory>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    pub fn harts(mut self, nharts: usize) -> Self {
+        self.nharts = nharts;
+        self
+    }
+
+    /// Preset a CSR of hart 0. Presets are written in order over the `Csr::new()` defaults, with the
+    /// same masking as a guest write.
+    pub fn csr(mut self, addr: usize, value: u64) -> Self {
+        self.csrs.push((addr, value));
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    pub fn pc(mut self, pc: u64) -> Self {
+        self.pc = Some(pc);
+        self
+    }
+
+    /// Fault on loads of dram bytes that have not been written. The code counts as written.
+    pub fn detect_uninit(mut self) -> Self {
+        self.detect_uninit = true;
+        self
+    }
+
+    pub fn build(self) -> Cpu {
+        let memory = self.memory.unwrap_or_else(|| Box::new(Dram::new(vec![])));
+        let mut cpu = Cpu::with_bus(Bus::with_memory(memory, self.disk_image, self.memory_map), self.nharts);
+        cpu.bus.detect_uninit(self.detect_uninit);
+        let entry = DRAM_BASE + self.load_offset;
+        if cpu.write_phys(entry, &self.code).is_err() {
+            panic!("The code does not fit in the dram at offset {:#x}", self.load_offset);
+        }
+        cpu.pc = entry;
+        for hart in cpu.harts.iter_mut() {
+            hart.pc = entry;
+        }
+        for (addr, value) in self.csrs {
+            cpu.write(Reg::Csr(addr), value);
+        }
+        if let Some(mode) = self.mode {
+            cpu.mode = mode;
+        }
+        if let Some(pc) = self.pc {
+            cpu.pc = pc;
+        }
+        cpu
+    }
+}
+
+/// A register of a hart, for test harnesses and debuggers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg {
+    /// An integer register x0-x31.
+    X(u8),
+    Pc,
+    /// A control and status register by its address.
+    Csr(usize),
+    /// The privilege mode, as the bits of `Mode`. It is not visible to the guest.
+    Mode,
+}
+
+impl core::str::FromStr for Reg {
+    type Err = String;
+
+    fn from_str(r: &str) -> Result<Self, Self::Err> {
+        if let Some(i) = RVABI.iter().position(|&x| x == r) {
+            return Ok(Reg::X(i as u8));
+        }
+        let csr = match r {
+            "pc" => return Ok(Reg::Pc),
+            "mode" => return Ok(Reg::Mode),
+            "fp" => return Ok(Reg::X(8)),
+            r if r.starts_with("x") => match r[1..].parse::<u8>() {
+                Ok(i) if i <= 31 => return Ok(Reg::X(i)),
+                _ => return Err(format!("Invalid register {}", r)),
+            },
+            "mhartid" => MHARTID,
+            "mstatus" => MSTATUS,
+            "misa" => MISA,
+            "mtvec" => MTVEC,
+            "mepc" => MEPC,
+            "mcause" => MCAUSE,
+            "mtval" => MTVAL,
+            "medeleg" => MEDELEG,
+            "mideleg" => MIDELEG,
+            "mscratch" => MSCRATCH,
+            "mie" => MIE,
+            "MIP" | "mip" => MIP,
+            "mcounteren" => MCOUNTEREN,
+            "sstatus" => SSTATUS,
+            "stvec" => STVEC,
+            "sepc" => SEPC,
+            "scause" => SCAUSE,
+            "stval" => STVAL,
+            "sscratch" => SSCRATCH,
+            "sie" => SIE,
+            "SIP" | "sip" => SIP,
+            "SATP" | "satp" => SATP,
+            _ => return Err(format!("Invalid register {}", r)),
+        };
+        Ok(Reg::Csr(csr))
+    }
 }
 
 const RVABI: [&str; 32] = [
@@ -53,59 +382,150 @@ const RVABI: [&str; 32] = [
 impl Cpu {
     /// Create a new `Cpu` object.
     pub fn new(code: Vec<u8>, disk_image: Vec<u8>) -> Self {
-        let mut regs = [0; 32];
-        regs[2] = DRAM_END;
-        let pc = DRAM_BASE;
-        let bus = Bus::new(code, disk_image);
-        let csr = Csr::new();
-        let mode = Machine;
-        let page_table = 0;
-        let enable_paging = false;
-
-        Self {regs, pc, bus, csr, mode, page_table, enable_paging}
-    }
-
-    pub fn reg(&self, r: &str) -> u64 {
-        match RVABI.iter().position(|&x| x == r) {
-            Some(i) => self.regs[i],
-            None => match r {
-                "pc" => self.pc,
-                "fp" => self.reg("s0"),
-                r if r.starts_with("x") => {
-                    if let Ok(i) = r[1..].parse::<usize>() {
-                        if i <= 31 { return self.regs[i]; }
-                        panic!("Invalid register {}", r);
-                    }
-                    panic!("Invalid register {}", r);
+        Self::with_harts(code, disk_image, 1)
+    }
+
+    /// Create a new `Cpu` object with `nharts` harts sharing one bus. Hart 0 runs first.
+    pub fn with_harts(code: Vec<u8>, disk_image: Vec<u8>, nharts: usize) -> Self {
+        Self::with_bus(Bus::new(code, disk_image, MemoryMap::default()), nharts)
+    }
+
+    fn with_bus(bus: Bus, nharts: usize) -> Self {
+        assert!(0 < nharts && nharts <= MAX_HARTS, "Invalid number of harts: {}", nharts);
+        let Hart { regs, pc, mode, csr, enable_paging, page_table, tlb, waiting_for_interrupt } = Hart::new(0);
+        let harts = (0..nharts).map(Hart::new).collect();
+
+        Self {
+            regs, pc, bus, csr, mode, page_table, enable_paging, tlb, waiting_for_interrupt, hartid: 0, harts, exit_code: None, device_error: None,
+            watchpoints: vec![], watch_hit: None, stats: Stats::default(), on_breakpoint: None, on_machine_ecall: None, extensions: MISA_EXTENSIONS, inst_len: 4,
+            #[cfg(feature = "std")]
+            trace_file: None,
+        }
+    }
+
+    /// Put every hart, with an empty tlb, and every device back into its reset state and run hart
+    /// 0 again. The dram keeps its contents, so a program that does not modify its own code can be
+    /// run again without reloading it. The pc restarts at DRAM_BASE, not at an ELF entry point.
+    pub fn reset(&mut self) {
+        let Hart { regs, pc, mode, csr, enable_paging, page_table, tlb, waiting_for_interrupt } = Hart::new(0);
+        (self.regs, self.pc, self.mode, self.csr) = (regs, pc, mode, csr);
+        (self.enable_paging, self.page_table, self.tlb) = (enable_paging, page_table, tlb);
+        self.waiting_for_interrupt = waiting_for_interrupt;
+        self.hartid = 0;
+        self.harts = (0..self.harts.len()).map(Hart::new).collect();
+        self.bus.reset();
+        self.exit_code = None;
+        self.device_error = None;
+        self.watch_hit = None;
+        self.stats = Stats::default();
+        self.inst_len = 4;
+    }
+
+    /// Save the state of the running hart and resume hart `hartid`.
+    pub fn switch_hart(&mut self, hartid: usize) {
+        if hartid != self.hartid {
+            self.swap_hart(self.hartid);
+            self.swap_hart(hartid);
+            self.hartid = hartid;
+        }
+    }
+
+    fn swap_hart(&mut self, hartid: usize) {
+        let hart = &mut self.harts[hartid];
+        core::mem::swap(&mut self.regs, &mut hart.regs);
+        core::mem::swap(&mut self.pc, &mut hart.pc);
+        core::mem::swap(&mut self.mode, &mut hart.mode);
+        core::mem::swap(&mut self.csr, &mut hart.csr);
+        core::mem::swap(&mut self.enable_paging, &mut hart.enable_paging);
+        core::mem::swap(&mut self.page_table, &mut hart.page_table);
+        core::mem::swap(&mut self.tlb, &mut hart.tlb);
+        core::mem::swap(&mut self.waiting_for_interrupt, &mut hart.waiting_for_interrupt);
+    }
+
+    /// Create a new `Cpu` object from an ELF executable. The pc starts at its entry point.
+    #[cfg(feature = "std")]
+    pub fn from_elf(elf: &[u8], disk_image: Vec<u8>) -> io::Result<Self> {
+        let elf = Elf::parse(elf)?;
+        let mut cpu = Self::new(elf.image, disk_image);
+        cpu.pc = elf.entry;
+        Ok(cpu)
+    }
+
+    /// Attach a memory-mapped device to the bus. Fail if its range is taken.
+    pub fn register_device(&mut self, dev: Box<dyn Device>) -> Result<(), String> {
+        self.bus.register_device(dev)
+    }
+
+    /// Seed the pseudo-random number generator device, so that a guest reads the same values on
+    /// every run.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.bus.rng().seed(seed);
+    }
+
+    /// Copy a flattened device tree blob into the dram at `addr` and pass its address in a1, where
+    /// a kernel expects it.
+    pub fn load_dtb(&mut self, dtb: &[u8], addr: u64) -> Result<(), Exception> {
+        self.write_phys(addr, dtb)?;
+        self.regs[11] = addr;
+        Ok(())
+    }
+
+    /// Read a register by its name, e.g. "a0", "x10", "pc" or "mstatus".
+    pub fn reg(&self, r: &str) -> Result<u64, String> {
+        Ok(self.read(r.parse()?))
+    }
+
+    pub fn read(&self, r: Reg) -> u64 {
+        match r {
+            Reg::X(i) => self.regs[i as usize],
+            Reg::Pc => self.pc,
+            Reg::Csr(MISA) => self.misa(),
+            // Like a csr instruction, time reads mtime from the CLINT.
+            Reg::Csr(TIME) => self.bus.device_ref::<Clint>().unwrap().mtime(),
+            Reg::Csr(MIP) => self.mip(),
+            Reg::Csr(SIP) => self.mip() & self.csr.load(MIDELEG),
+            Reg::Csr(addr) => self.csr.load(addr),
+            Reg::Mode => u64::from(self.mode),
+        }
+    }
+
+    /// The value of misa, which follows `extensions`.
+    fn misa(&self) -> u64 {
+        MISA_MXL_64 | (self.extensions & MISA_EXTENSIONS)
+    }
+
+    /// Write a register. A write to x0 or of a reserved mode is ignored, and a write to satp takes
+    /// effect immediately.
+    pub fn write(&mut self, r: Reg, value: u64) {
+        match r {
+            Reg::X(0) => (),
+            Reg::X(i) => self.regs[i as usize] = value,
+            Reg::Pc => self.pc = value,
+            Reg::Csr(addr) => {
+                self.csr.store(addr, value);
+                self.on_csr_write(addr);
+            }
+            Reg::Mode => {
+                if let Ok(mode) = Mode::try_from(value) {
+                    self.mode = mode;
                 }
-                "mhartid" => self.csr.load(MHARTID),
-                "mstatus" => self.csr.load(MSTATUS),
-                "mtvec" => self.csr.load(MTVEC),
-                "mepc" => self.csr.load(MEPC),
-                "mcause" => self.csr.load(MCAUSE),
-                "mtval" => self.csr.load(MTVAL),
-                "medeleg" => self.csr.load(MEDELEG),
-                "mscratch" => self.csr.load(MSCRATCH),
-                "MIP" => self.csr.load(MIP),
-                "mcounteren" => self.csr.load(MCOUNTEREN),
-                "sstatus" => self.csr.load(SSTATUS),
-                "stvec" => self.csr.load(STVEC),
-                "sepc" => self.csr.load(SEPC),
-                "scause" => self.csr.load(SCAUSE),
-                "stval" => self.csr.load(STVAL),
-                "sscratch" => self.csr.load(SSCRATCH),
-                "SIP" => self.csr.load(SIP),
-                "SATP" => self.csr.load(SATP),
-                _ => panic!("Invalid register {}", r),
             }
         }
     }
 
+    #[cfg(feature = "std")]
     pub fn dump_pc(&self) {
         println!("{:-^80}", "PC register");
         println!("PC = {:#x}\n", self.pc);
     }
 
+    #[cfg(feature = "std")]
+    pub fn dump_mode(&self) {
+        println!("{:-^80}", "privilege mode");
+        println!("mode = {:?} ({})\n", self.mode, u64::from(self.mode));
+    }
+
+    #[cfg(feature = "std")]
     pub fn dump_registers(&mut self) {
         println!("{:-^80}", "registers");
         let mut output = String::new();
@@ -130,10 +550,75 @@ impl Cpu {
     }
 
     /// Print values in some csrs.
+    #[cfg(feature = "std")]
     pub fn dump_csrs(&self) {
         self.csr.dump_csrs();
     }
 
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Print the number of executed instructions by category.
+    #[cfg(feature = "std")]
+    pub fn dump_stats(&self) {
+        self.stats.dump();
+    }
+
+    /// Serialize the whole machine into bytes: the running hart id and exit code, then every hart
+    /// (regs, pc, mode, paging state, wfi and csrs), then the dram and the devices. Every field is
+    /// stored as a little-endian u64. The tlbs are not saved, they are refilled after `restore`.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let header = [self.hartid as u64, self.harts.len() as u64, self.exit_code.is_some() as u64, self.exit_code.unwrap_or(0) as u64];
+        for word in header {
+            put_word(&mut data, word);
+        }
+        for (hartid, hart) in self.harts.iter().enumerate() {
+            // The slot of the running hart is stale.
+            let (regs, csr, state) = if hartid == self.hartid {
+                (&self.regs, &self.csr, [self.pc, u64::from(self.mode), self.enable_paging as u64, self.page_table, self.waiting_for_interrupt as u64])
+            } else {
+                (&hart.regs, &hart.csr, [hart.pc, u64::from(hart.mode), hart.enable_paging as u64, hart.page_table, hart.waiting_for_interrupt as u64])
+            };
+            for &word in regs.iter().chain(state.iter()) {
+                put_word(&mut data, word);
+            }
+            csr.snapshot(&mut data);
+        }
+        self.bus.snapshot(&mut data);
+        data
+    }
+
+    /// Restore the machine from bytes produced by `snapshot` on a `Cpu` with as many harts and the
+    /// same devices. Panic if `data` is malformed.
+    pub fn restore(&mut self, data: &[u8]) {
+        let mut reader = SnapshotReader::new(data);
+        let hartid = reader.word() as usize;
+        assert!(reader.word() == self.harts.len() as u64 && hartid < self.harts.len(), "Invalid cpu snapshot");
+        let (has_exit_code, exit_code) = (reader.word() != 0, reader.word() as i64);
+        for hart in self.harts.iter_mut() {
+            for reg in hart.regs.iter_mut() {
+                *reg = reader.word();
+            }
+            hart.pc = reader.word();
+            hart.mode = Mode::try_from(reader.word()).expect("Invalid cpu snapshot");
+            hart.enable_paging = reader.word() != 0;
+            hart.page_table = reader.word();
+            hart.waiting_for_interrupt = reader.word() != 0;
+            hart.csr.restore(reader.bytes(NUM_CSRS * 8));
+            hart.tlb.clear();
+        }
+        // Every slot holds its hart now, so swapping the running one in leaves a stale slot as usual.
+        self.swap_hart(hartid);
+        self.hartid = hartid;
+        self.tlb.clear();
+        self.exit_code = has_exit_code.then_some(exit_code);
+        self.device_error = None;
+        self.watch_hit = None;
+        self.bus.restore(reader.rest());
+    }
+
     pub fn handle_exception(&mut self, e: Exception) {
         // the process to handle exception in S-mode and M-mode is similar,
         // includes following steps:
@@ -145,6 +630,9 @@ impl Cpu {
         // 5. set trap value properly (stval in S-mode, mtval in M-mode)
         // 6. set xPIE to xIE (SPIE in S-mode, MPIE in M-mode)
         // 7. clear up xIE (SIE in S-mode, MIE in M-mode)
+        //
+        // `execute` returns the next pc instead of writing it, so on a trap `self.pc` still holds the
+        // address of the instruction that encountered the exception.
         let pc = self.pc; 
         let mode = self.mode;
         let cause = e.code();
@@ -188,7 +676,7 @@ impl Cpu {
         // set SIE = 0 / MIE = 0
         status &= !MASK_IE; 
         // set SPP / MPP = previous mode
-        status = (status & !MASK_PP) | (mode << pp_i);
+        status = (status & !MASK_PP) | (u64::from(mode) << pp_i);
         self.csr.store(STATUS, status);
     }
 
@@ -218,8 +706,10 @@ impl Cpu {
         let tvec_base = tvec & !0b11;
         match tvec_mode { // DIrect
             0 => self.pc = tvec_base,
-            1 => self.pc = tvec_base + cause << 2,
-            _ => unreachable!(),
+            1 => self.pc = tvec_base.wrapping_add(cause << 2),
+            // The modes 2 and 3 are reserved, mtvec and stvec may hold them since they are written
+            // unchecked. Treat them as direct.
+            _ => self.pc = tvec_base,
         };
         // 3.1.14 & 4.1.7
         // When a trap is taken into S-mode (or M-mode), sepc (or mepc) is written with the virtual address 
@@ -242,11 +732,83 @@ impl Cpu {
         // set SIE = 0 / MIE = 0
         status &= !MASK_IE; 
         // set SPP / MPP = previous mode
-        status = (status & !MASK_PP) | (mode << pp_i);
+        status = (status & !MASK_PP) | (u64::from(mode) << pp_i);
         self.csr.store(STATUS, status);
     }
 
 
+    /// Mirror the interrupt lines of this hart into mip. MSIP and MTIP follow the msip register and
+    /// the timer of this hart in the CLINT; the guest clears MTIP by writing a later time to
+    /// mtimecmp. MEIP follows the M-mode context (2 * hartid) in the PLIC: it is cleared once the
+    /// guest claims the interrupt, and set again by the next one after it completes. SSIP and STIP
+    /// are left to software, and so is the SEIP bit in the csr, see `mip`.
+    fn update_mip(&mut self) {
+        let clint = self.bus.clint();
+        let (msip, mtip) = (clint.msip(self.hartid), clint.mtip(self.hartid));
+        let meip = self.bus.plic().is_interrupting(2 * self.hartid);
+        let mut mip = self.csr.load(MIP) & !(MASK_MSIP | MASK_MTIP | MASK_MEIP);
+        for (pending, mask) in [(msip, MASK_MSIP), (mtip, MASK_MTIP), (meip, MASK_MEIP)] {
+            if pending {
+                mip |= mask;
+            }
+        }
+        self.csr.store(MIP, mip);
+    }
+
+    /// The value of mip. SEIP is the bit that M-mode software writes OR'd with the line of the
+    /// S-mode context (2 * hartid + 1) in the PLIC, so the interrupt stays pending as long as
+    /// either of them is set.
+    fn mip(&self) -> u64 {
+        let seip = self.bus.plic_ref().is_interrupting(2 * self.hartid + 1);
+        self.csr.load(MIP) | if seip { MASK_SEIP } else { 0 }
+    }
+
+    /// Let the devices raise their interrupts, and update mip.
+    fn poll_devices(&mut self) {
+        // Devices raise their interrupts in the PLIC, which decides by the priorities, enables and
+        // thresholds whether this hart is interrupted.
+        #[cfg(feature = "std")]
+        if self.bus.uart().is_interrupting() {
+            self.bus.plic().raise(UART_IRQ);
+        }
+        self.serve_disk();
+        self.update_mip();
+    }
+
+    /// Serve the request that the guest has notified the disk of, and raise its interrupt.
+    fn serve_disk(&mut self) {
+        if self.bus.virtio_blk().is_interrupting() {
+            match self.disk_access() {
+                Ok(()) => self.bus.plic().raise(VIRTIO_IRQ),
+                Err(e) => self.device_error = Some(e),
+            }
+        }
+    }
+
+    /// Resume the hart from `wfi` if an interrupt enabled in mie is pending, and take it before the
+    /// next instruction. It resumes even if interrupts are globally disabled, in which case it
+    /// continues after the wfi without a trap.
+    fn wake_up(&mut self) -> bool {
+        self.poll_devices();
+        if self.csr.load(MIE) & self.mip() == 0 {
+            return false;
+        }
+        self.waiting_for_interrupt = false;
+        if let Some(interrupt) = self.check_pending_interrupt() {
+            self.handle_interrupt(interrupt);
+        }
+        true
+    }
+
+    /// Wait a little for an interrupt, since every hart is in `wfi`. The timer runs ahead by the
+    /// time waited. Without std there is no clock to wait on, so the timer just runs ahead.
+    fn idle(&mut self) {
+        let interval = Duration::from_micros(100);
+        #[cfg(feature = "std")]
+        std::thread::sleep(interval);
+        self.bus.clint().advance(interval);
+    }
+
     pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
         use Interrupt::*;
         // 3.1.6.1
@@ -262,30 +824,26 @@ impl Cpu {
         // the following are true: (a) either the current privilege mode is M and the MIE bit in the mstatus
         // register is set, or the current privilege mode has less privilege than M-mode; (b) bit i is set in both
         // mip and mie; and (c) if register mideleg exists, bit i is not set in mideleg.
+
+        // The disk serves a request as soon as it is notified, even if its interrupt cannot be
+        // taken yet.
+        self.serve_disk();
         if (self.mode == Machine) && (self.csr.load(MSTATUS) & MASK_MIE) == 0 {
             return None;
         }
         if (self.mode == Supervisor) && (self.csr.load(SSTATUS) & MASK_SIE) == 0 {
             return None;
         }
-        
-        // In fact, we should using priority to decide which interrupt should be handled first.
-        if self.bus.uart.is_interrupting() {
-            self.bus.store(PLIC_SCLAIM, 32, UART_IRQ).unwrap();
-            self.csr.store(MIP, self.csr.load(MIP) | MASK_SEIP); 
-        } else if self.bus.virtio_blk.is_interrupting() {
-            self.disk_access();
-            self.bus.store(PLIC_SCLAIM, 32, VIRTIO_IRQ).unwrap();  
-            self.csr.store(MIP, self.csr.load(MIP) | MASK_SEIP);
-        }
+        // This runs after every instruction, so the other devices are only polled when an
+        // interrupt can be taken. A device keeps its interrupt until then.
+        self.poll_devices();
 
         // 3.1.9 & 4.1.3
         // Multiple simultaneous interrupts destined for M-mode are handled in the following decreasing
         // priority order: MEI, MSI, MTI, SEI, SSI, STI.
-        let pending = self.csr.load(MIE) & self.csr.load(MIP);
+        let pending = self.csr.load(MIE) & self.mip();
 
         if (pending & MASK_MEIP) != 0 {
-            self.csr.store(MIP, self.csr.load(MIP) & !MASK_MEIP);
             return Some(MachineExternalInterrupt);
         }
         if (pending & MASK_MSIP) != 0 {
@@ -293,11 +851,9 @@ impl Cpu {
             return Some(MachineSoftwareInterrupt);
         }
         if (pending & MASK_MTIP) != 0 {
-            self.csr.store(MIP, self.csr.load(MIP) & !MASK_MTIP);
             return Some(MachineTimerInterrupt);
         }
         if (pending & MASK_SEIP) != 0 {
-            self.csr.store(MIP, self.csr.load(MIP) & !MASK_SEIP);
             return Some(SupervisorExternalInterrupt);
         }
         if (pending & MASK_SSIP) != 0 {
@@ -312,71 +868,108 @@ impl Cpu {
     }
 
 
-    pub fn disk_access(&mut self) {
+    /// Process the request at the head of the available ring. Every descriptor index is checked
+    /// against DESC_NUM, and every buffer against the dram and the disk image, so a malformed ring
+    /// is reported as a `DiskError` before anything is read or written.
+    pub fn disk_access(&mut self) -> Result<(), DiskError> {
         const desc_size: u64 = size_of::<VirtqDesc>() as u64;
         // 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
         // ------------------------------------------------------------------
         // Descriptor Table  | Available Ring | (...padding...) | Used Ring
         // ------------------------------------------------------------------
-        let desc_addr = self.bus.virtio_blk.desc_addr();
+        let desc_addr = self.bus.virtio_blk().desc_addr();
         let avail_addr = desc_addr + DESC_NUM as u64 * desc_size;
         let used_addr = desc_addr + PAGE_SIZE;
+        check_dram(desc_addr, PAGE_SIZE + size_of::<VirtqUsed>() as u64)?;
 
-        // cast addr to a reference to ease field access.
-        let virtq_avail = unsafe { &(*(avail_addr as *const VirtqAvail)) };
-        let virtq_used  = unsafe { &(*(used_addr  as *const VirtqUsed)) };
-
+        // Every field is read by its offset in the guest memory. The structs only describe the
+        // layout: the addresses belong to the dram of the guest, so they must not be dereferenced.
         // The idx field of virtq_avail should be indexed into available ring to get the
         // index of descriptor we need to process.
-        let idx = self.bus.load(&virtq_avail.idx as *const _ as u64, 16).unwrap() as usize;
-        let index = self.bus.load(&virtq_avail.ring[idx % DESC_NUM] as *const _ as u64, 16).unwrap();
+        let idx = self.load_dram(avail_addr + offset_of!(VirtqAvail, idx) as u64, 16)?;
+        let ring_addr = avail_addr + offset_of!(VirtqAvail, ring) as u64 + (idx % DESC_NUM as u64) * 2;
+        let index = check_desc_index(self.load_dram(ring_addr, 16)?)?;
 
         // The first descriptor:
         // which contains the request information and a pointer to the data descriptor.
         let desc_addr0 = desc_addr + desc_size * index;
-        let virtq_desc0 = unsafe { &(*(desc_addr0 as *const VirtqDesc)) };
         // The addr field points to a virtio block request. We need the sector number stored 
         // in the sector field. The iotype tells us whether to read or write.
-        let req_addr = self.bus.load(&virtq_desc0.addr as *const _ as u64, 64).unwrap();
-        let virtq_blk_req = unsafe { &(*(req_addr as *const VirtioBlkRequest)) };
-        let blk_sector = self.bus.load(&virtq_blk_req.sector as *const _ as u64, 64).unwrap();
-        let iotype = self.bus.load(&virtq_blk_req.iotype as *const _ as u64, 32).unwrap() as u32;
+        let req_addr = self.load_dram(desc_addr0 + offset_of!(VirtqDesc, addr) as u64, 64)?;
+        check_dram(req_addr, size_of::<VirtioBlkRequest>() as u64)?;
+        let blk_sector = self.load_dram(req_addr + offset_of!(VirtioBlkRequest, sector) as u64, 64)?;
+        let iotype = self.load_dram(req_addr + offset_of!(VirtioBlkRequest, iotype) as u64, 32)? as u32;
         // The next field points to the second descriptor. (data descriptor)
-        let next0  = self.bus.load(&virtq_desc0.next  as *const _ as u64, 16).unwrap();
+        let next0 = check_desc_index(self.load_dram(desc_addr0 + offset_of!(VirtqDesc, next) as u64, 16)?)?;
 
         // the second descriptor. 
         let desc_addr1 = desc_addr + desc_size * next0;
-        let virtq_desc1 = unsafe { &(*(desc_addr1 as *const VirtqDesc)) };
         // The addr field points to the data to read or write
-        let addr1  = self.bus.load(&virtq_desc1.addr  as *const _ as u64, 64).unwrap();
+        let addr1 = self.load_dram(desc_addr1 + offset_of!(VirtqDesc, addr) as u64, 64)?;
         // the len donates the size of the data
-        let len1   = self.bus.load(&virtq_desc1.len   as *const _ as u64, 32).unwrap();
+        let len1 = self.load_dram(desc_addr1 + offset_of!(VirtqDesc, len) as u64, 32)?;
+        check_dram(addr1, len1)?;
+        let offset = blk_sector.saturating_mul(SECTOR_SIZE);
+        if offset.checked_add(len1).is_none_or(|end| end > self.bus.virtio_blk().disk_size()) {
+            return Err(DiskError::DiskOutOfRange { offset, len: len1 });
+        }
         // the flags mark this buffer as device write-only or read-only.
         // We ignore it here
-        // let flags1 = self.bus.load(&virtq_desc1.flags as *const _ as u64, 16).unwrap();
+        let mut data = vec![0; len1 as usize];
         match iotype {
             VIRTIO_BLK_T_OUT => {
-                for i in 0..len1 {
-                    let data = self.bus.load(addr1 + i, 8).unwrap();
-                    self.bus.virtio_blk.write_disk(blk_sector * SECTOR_SIZE + i, data);
-                }
+                self.bus.read_phys(addr1, &mut data).map_err(|_| DiskError::GuestMemory(addr1))?;
+                self.bus.virtio_blk().write_disk_bytes(offset, &data)?;
+                self.bus.virtio_blk().flush()?;
             }
             VIRTIO_BLK_T_IN => {
-                for i in 0..len1 {
-                    let data = self.bus.virtio_blk.read_disk(blk_sector * SECTOR_SIZE + i);
-                    self.bus.store(addr1 + i, 8, data as u64).unwrap();
-                }
-            } 
-            _ => unreachable!(),
-        }     
+                self.bus.virtio_blk().read_disk_bytes(offset, &mut data)?;
+                self.bus.write_phys(addr1, &data).map_err(|_| DiskError::GuestMemory(addr1))?;
+            }
+            _ => return Err(DiskError::IoType(iotype)),
+        }
 
-        let new_id = self.bus.virtio_blk.get_new_id();
-        self.bus.store(&virtq_used.idx as *const _ as u64, 16, new_id % 8).unwrap();
+        let new_id = self.bus.virtio_blk().get_new_id();
+        let used_idx = used_addr + offset_of!(VirtqUsed, idx) as u64;
+        self.bus.store(used_idx, 16, new_id % 8).map_err(|_| DiskError::GuestMemory(used_idx))?;
+        Ok(())
     }
 
-    fn update_paging(&mut self, csr_addr: usize) {
-        if csr_addr != SATP { return; }
+    /// Load from an address that `disk_access` has already checked to be in the dram. The load
+    /// still fails on a byte that was never written if uninitialized reads are detected.
+    fn load_dram(&mut self, addr: u64, size: u64) -> Result<u64, DiskError> {
+        self.bus.load(addr, size).map_err(|_| DiskError::GuestMemory(addr))
+    }
+
+    /// Read a csr for a csr instruction. `time` is a read-only shadow of mtime in the CLINT, so it
+    /// is read from the bus; the other csrs, including `cycle` and `instret`, are held by `Csr`.
+    fn load_csr(&mut self, csr_addr: usize) -> u64 {
+        match csr_addr {
+            TIME => self.bus.clint().mtime(),
+            MISA => self.misa(),
+            // A device may have raised an interrupt since the last check.
+            MIP => {
+                self.poll_devices();
+                self.mip()
+            }
+            SIP => {
+                self.poll_devices();
+                self.mip() & self.csr.load(MIDELEG)
+            }
+            _ => self.csr.load(csr_addr),
+        }
+    }
+
+    /// Update the state that depends on a csr after it is written.
+    fn on_csr_write(&mut self, csr_addr: usize) {
+        // SUM, MXR and MPRV in mstatus (and sstatus) need nothing: every access reads them, and a
+        // translation from the tlb is checked against them again.
+        if csr_addr == SATP {
+            self.update_paging();
+        }
+    }
 
+    fn update_paging(&mut self) {
         // Read the physical page number (PPN) of the root page table, i.e., its
         // supervisor physical address divided by 4 KiB.
         let satp = self.csr.load(SATP);
@@ -387,14 +980,174 @@ impl Cpu {
 
         // Enable the SV39 paging if the value of the mode field is 8.
         self.enable_paging = mode == 8;
+
+        // The tlb is not tagged with an ASID, so the cached translations belong to the old satp.
+        self.tlb.clear();
+    }
+
+    /// The privilege mode that an access is translated and checked in.
+    fn effective_mode(&self, access_type: &AccessType) -> Mode {
+        // 3.1.6.3 Memory Privilege in mstatus Register
+        // When MPRV=1, load and store memory addresses are translated and protected, and endianness
+        // is applied, as though the current privilege mode were set to MPP. Instruction address-
+        // translation and protection are unaffected by the setting of MPRV.
+        let mstatus = self.csr.load(MSTATUS);
+        match access_type {
+            AccessType::Instruction => self.mode,
+            _ if self.mode == Machine && (mstatus & MASK_MPRV) != 0 => mpp(mstatus),
+            _ => self.mode,
+        }
+    }
+
+    /// Fetch and execute an instruction, then take a pending interrupt. An exception is trapped by
+    /// `handle_exception` and also returned if it is fatal. The CLINT timer ticks once per step.
+    pub fn step(&mut self) -> Result<(), Exception> {
+        self.waiting_for_interrupt = false;
+        self.bus.clint().tick();
+        let inst = match self.fetch() {
+            Ok(inst) => inst,
+            Err(e) => {
+                self.handle_exception(e);
+                return if e.is_fatal() { Err(e) } else { Ok(()) };
+            }
+        };
+
+        #[cfg(feature = "std")]
+        let old_regs = self.trace_file.is_some().then_some(self.regs);
+        match self.execute(inst) {
+            Ok(new_pc) => {
+                #[cfg(feature = "std")]
+                if let Some(old_regs) = old_regs {
+                    self.trace(inst, &old_regs);
+                }
+                self.pc = new_pc;
+            }
+            Err(e) => {
+                self.handle_exception(e);
+                if e.is_fatal() {
+                    return Err(e);
+                }
+            }
+        };
+
+        match self.check_pending_interrupt() {
+            Some(interrupt) => self.handle_interrupt(interrupt),
+            None => (),
+        }
+        Ok(())
+    }
+
+    /// Append the record of `inst`, executed at the current pc with the registers `old_regs`, to
+    /// the trace file.
+    #[cfg(feature = "std")]
+    fn trace(&mut self, inst: u64, old_regs: &[u64; 32]) {
+        let rd = (1..32).find(|&i| self.regs[i] != old_regs[i]).unwrap_or(0);
+        let record = TraceRecord {
+            pc: self.pc,
+            inst: inst as u32,
+            hartid: self.hartid as u8,
+            rd: rd as u8,
+            value: self.regs[rd],
+        };
+        if let Some(file) = &mut self.trace_file {
+            if file.write_all(&record.to_bytes()).is_err() {
+                self.trace_file = None;
+            }
+        }
+    }
+
+    /// Keep stepping until a fatal exception happens, or `max_insts` instructions have been executed,
+    /// or `timeout` has elapsed. `None` means no limit. Harts take turns to execute one instruction
+    /// each, and the budget counts the instructions of all harts. Without std there is no clock, so
+    /// `timeout` is ignored.
+    pub fn run(&mut self, max_insts: Option<u64>, timeout: Option<Duration>) -> RunResult {
+        #[cfg(feature = "std")]
+        let start = Instant::now();
+        #[cfg(not(feature = "std"))]
+        let _ = timeout;
+        let mut count: u64 = 0;
+        let reason = loop {
+            if let Some(e) = self.device_error {
+                break StopReason::DeviceError(e);
+            }
+            if max_insts.is_some_and(|max| count >= max) {
+                break StopReason::InstLimit;
+            }
+            // Reading the clock is slow compared to an instruction, so only check it once in a while.
+            #[cfg(feature = "std")]
+            if (count.is_multiple_of(1024) || self.waiting_for_interrupt) && timeout.is_some_and(|t| start.elapsed() >= t) {
+                break StopReason::Timeout;
+            }
+            if self.waiting_for_interrupt && !self.wake_up() {
+                // Only wait when no other hart has anything to execute.
+                if self.harts.iter().enumerate().all(|(id, hart)| id == self.hartid || hart.waiting_for_interrupt) {
+                    // With no interrupt enabled in mie, no hart can ever wake up.
+                    let mie = |id: usize| if id == self.hartid { self.csr.load(MIE) } else { self.harts[id].csr.load(MIE) };
+                    if (0..self.harts.len()).all(|id| mie(id) == 0) {
+                        break StopReason::Idle;
+                    }
+                    self.idle();
+                }
+            } else {
+                if let Err(e) = self.step() {
+                    break StopReason::FatalException(e);
+                }
+                count += 1;
+            }
+            if let Some(code) = self.exit_code {
+                break StopReason::Halted(code);
+            }
+            if let Some(hit) = self.watch_hit.take() {
+                break StopReason::Watchpoint(hit);
+            }
+            if self.harts.len() > 1 {
+                self.switch_hart((self.hartid + 1) % self.harts.len());
+            }
+        };
+        RunResult { reason, instret: count, pc: self.pc }
     }
 
     /// Translate a virtual address to a physical address for the paged virtual-dram system.
     pub fn translate(&mut self, addr: u64, access_type: AccessType) -> Result<u64, Exception> {
-        if !self.enable_paging {
+        // M-mode accesses are never translated.
+        if !self.enable_paging || self.effective_mode(&access_type) == Machine {
             return Ok(addr);
         }
 
+        let vpage = addr >> 12;
+        if let Some(&(ppage, pte)) = self.tlb.get(&vpage) {
+            if !self.pte_permits(pte, access_type) {
+                return Err(page_fault(addr, access_type));
+            }
+            return Ok(ppage | (addr & 0xfff));
+        }
+        let (p_addr, pte) = self.walk(addr, access_type)?;
+        self.tlb.insert(vpage, (p_addr & !0xfff, pte));
+        Ok(p_addr)
+    }
+
+    /// Whether the leaf `pte` allows an access in the effective mode, given the SUM and MXR
+    /// fields of mstatus.
+    fn pte_permits(&self, pte: u64, access_type: AccessType) -> bool {
+        let (r, w, x, u) = ((pte >> 1) & 1 == 1, (pte >> 2) & 1 == 1, (pte >> 3) & 1 == 1, (pte >> 4) & 1 == 1);
+        let mstatus = self.csr.load(MSTATUS);
+        let allowed = match access_type {
+            AccessType::Instruction => x,
+            // With MXR=1, loads from pages marked executable succeed too.
+            AccessType::Load => r || (x && mstatus & MASK_MXR != 0),
+            AccessType::Store => w,
+        };
+        let accessible = match self.effective_mode(&access_type) {
+            User => u,
+            // S-mode accesses U-mode pages only if SUM=1, and never executes them.
+            _ => !u || (mstatus & MASK_SUM != 0 && !matches!(access_type, AccessType::Instruction)),
+        };
+        allowed && accessible
+    }
+
+    /// Walk the SV39 page table to translate a virtual address. Return the physical address and
+    /// the leaf PTE.
+    fn walk(&mut self, addr: u64, access_type: AccessType) -> Result<(u64, u64), Exception> {
         // The following comments are cited from 4.3.2 Virtual Address Translation Process
         // in "The RISC-V Instruction Set Manual Volume II-Privileged Architecture_20190608".
 
@@ -424,11 +1177,7 @@ impl Cpu {
             let w = (pte >> 2) & 1;
             let x = (pte >> 3) & 1;
             if v == 0 || (r == 0 && w == 1) {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                    AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-                }
+                return Err(page_fault(addr, access_type));
             }
 
             // "4. Otherwise, the PTE is valid. If pte.r = 1 or pte.x = 1, go to step 5.
@@ -443,11 +1192,7 @@ impl Cpu {
             let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
             a = ppn * PAGE_SIZE;
             if i < 0 {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                    AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-                }
+                return Err(page_fault(addr, access_type));
             }
         }
 
@@ -458,15 +1203,21 @@ impl Cpu {
             (pte >> 28) & 0x03ff_ffff,
         ];
 
-        // We skip implementing from step 5 to 7.
+        // We skip implementing step 7.
 
         // "5. A leaf PTE has been found. Determine if the requested dram access is allowed by
         //     the pte.r, pte.w, pte.x, and pte.u bits, given the current privilege mode and the
         //     value of the SUM and MXR fields of the mstatus register. If not, stop and raise a
         //     page-fault exception corresponding to the original access type."
+        if !self.pte_permits(pte, access_type) {
+            return Err(page_fault(addr, access_type));
+        }
 
         // "6. If i > 0 and pte.ppn[i − 1 : 0] ̸= 0, this is a misaligned superpage; stop and
         //     raise a page-fault exception corresponding to the original access type."
+        if (i == 1 && ppn[0] != 0) || (i == 2 && (ppn[1] != 0 || ppn[0] != 0)) {
+            return Err(page_fault(addr, access_type));
+        }
 
         // "7. If pte.a = 0, or if the dram access is a store and pte.d = 0, either raise a
         //     page-fault exception corresponding to the original access type, or:
@@ -483,710 +1234,901 @@ impl Cpu {
         //     va.vpn[i−1:0].
         //     • pa.ppn[LEVELS−1:i] = pte.ppn[LEVELS−1:i]."
         let offset = addr & 0xfff;
-        match i {
+        let p_addr = match i {
             0 => {
                 let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
-                Ok((ppn << 12) | offset)
+                (ppn << 12) | offset
             }
             1 => {
                 // Superpage translation. A superpage is a dram page of larger size than an
                 // ordinary page (4 KiB). It reduces TLB misses and improves performance.
-                Ok((ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset)
+                (ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset
             }
             2 => {
                 // Superpage translation. A superpage is a dram page of larger size than an
                 // ordinary page (4 KiB). It reduces TLB misses and improves performance.
-                Ok((ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset)
+                (ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset
             }
-            _ => match access_type {
-                AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-            },
-        }
+            _ => return Err(page_fault(addr, access_type)),
+        };
+        Ok((p_addr, pte))
     }
 
     /// Load a value from a dram.
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        let p_addr = self.translate(addr, AccessType::Load)?;
-        self.bus.load(p_addr, size)
+        let (p_addr, tail) = self.translate_access(addr, size, AccessType::Load)?;
+        let value = match tail {
+            None => self.bus.load(p_addr, size)?,
+            Some((p_tail, head_len)) => {
+                let head = self.load_bytes(p_addr, head_len)?;
+                let tail = self.load_bytes(p_tail, size / 8 - head_len)?;
+                head | (tail << (8 * head_len))
+            }
+        };
+        let value = match self.is_big_endian() {
+            true => swap_bytes(value, size),
+            false => value,
+        };
+        if let Some(watched) = self.watched(addr, size, WatchKind::Read) {
+            self.watch_hit = Some(WatchHit { addr: watched, pc: self.pc, kind: WatchKind::Read, value, old: None });
+        }
+        Ok(value)
     }
 
     /// Store a value to a dram.
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        let p_addr = self.translate(addr, AccessType::Store)?;
-        self.bus.store(p_addr, size, value)
+        let (p_addr, tail) = self.translate_access(addr, size, AccessType::Store)?;
+        let hit = self.watched(addr, size, WatchKind::Write).map(|watched| {
+            let in_dram = tail.is_none() && (DRAM_BASE..=DRAM_END).contains(&p_addr);
+            let old = if in_dram { self.bus.load(p_addr, size).ok() } else { None };
+            let old = old.map(|old| if self.is_big_endian() { swap_bytes(old, size) } else { old });
+            WatchHit { addr: watched, pc: self.pc, kind: WatchKind::Write, value, old }
+        });
+        let value = match self.is_big_endian() {
+            true => swap_bytes(value, size),
+            false => value,
+        };
+        match tail {
+            None => self.bus.store(p_addr, size, value)?,
+            Some((p_tail, head_len)) => {
+                self.store_bytes(p_addr, head_len, value)?;
+                self.store_bytes(p_tail, size / 8 - head_len, value >> (8 * head_len))?;
+            }
+        }
+        // Only a device can power off the machine, so a store to the dram needs no check.
+        if !(DRAM_BASE..=DRAM_END).contains(&p_addr) {
+            if let Some(finisher) = self.bus.device::<TestFinisher>() {
+                self.exit_code = finisher.exit_code();
+            }
+        }
+        if hit.is_some() {
+            self.watch_hit = hit;
+        }
+        Ok(())
     }
 
-    /// Get an instruction from the dram.
-    pub fn fetch(&mut self) -> Result<u64, Exception> {
-        let p_pc = self.translate(self.pc, AccessType::Instruction)?;
-        match self.bus.load(p_pc, 32) {
-            Ok(inst) => Ok(inst),
-            Err(_e) => Err(Exception::InstructionAccessFault(self.pc)),
-        }
+    /// Return the first watched address in the `size` bits at `addr` that stops an access of
+    /// `kind`.
+    fn watched(&self, addr: u64, size: u64, kind: WatchKind) -> Option<u64> {
+        self.watchpoints
+            .iter()
+            .find(|&&(watched, watch)| (watch == kind || watch == WatchKind::Access) && watched.wrapping_sub(addr) < size / 8)
+            .map(|&(watched, _)| watched)
     }
 
+    /// Translate an access of `size` bits at `addr`. An access that straddles a page boundary is
+    /// split there, and both pages are translated before any byte is accessed, so an unmapped
+    /// second page faults with the address of its first byte and leaves the first page untouched.
+    /// Return the physical address of the access, and if the two pages are not contiguous in
+    /// physical memory, the physical address of the second piece and the byte length of the first.
+    fn translate_access(&mut self, addr: u64, size: u64, access_type: AccessType) -> Result<(u64, Option<(u64, u64)>), Exception> {
+        let p_addr = self.translate(addr, access_type)?;
+        let head_len = PAGE_SIZE - addr % PAGE_SIZE;
+        if head_len >= size / 8 {
+            return Ok((p_addr, None));
+        }
+        let p_tail = self.translate(addr.wrapping_add(head_len), access_type)?;
+        match p_tail == p_addr.wrapping_add(head_len) {
+            true => Ok((p_addr, None)),
+            false => Ok((p_addr, Some((p_tail, head_len)))),
+        }
+    }
 
-    #[inline]
-    pub fn update_pc(&mut self) -> Result<u64, Exception> {
-        return Ok(self.pc + 4);
+    /// Load `len` bytes at physical address `addr` as a little-endian value.
+    fn load_bytes(&mut self, addr: u64, len: u64) -> Result<u64, Exception> {
+        let mut value = 0;
+        for i in 0..len {
+            value |= self.bus.load(addr + i, 8)? << (8 * i);
+        }
+        Ok(value)
     }
 
-    /// Execute an instruction after decoding. Return true if an error happens, otherwise false.
-    pub fn execute(&mut self, inst: u64) -> Result<u64, Exception> {
-        let opcode = inst & 0x0000007f;
-        let rd = ((inst & 0x00000f80) >> 7) as usize;
-        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
-        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
-        let funct3 = (inst & 0x00007000) >> 12;
-        let funct7 = (inst & 0xfe000000) >> 25;
+    /// Store the low `len` bytes of `value` at physical address `addr` in little-endian order.
+    fn store_bytes(&mut self, addr: u64, len: u64, value: u64) -> Result<(), Exception> {
+        for i in 0..len {
+            self.bus.store(addr + i, 8, (value >> (8 * i)) & 0xff)?;
+        }
+        Ok(())
+    }
 
-        // Emulate that register x0 is hardwired with all bits equal to 0.
-        self.regs[0] = 0;
+    /// Copy the dram at physical address `addr` into `buf`.
+    pub fn read_phys(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        self.bus.read_phys(addr, buf)
+    }
 
-        match opcode {
-            0x03 => {
-                // imm[11:0] = inst[31:20]
-                let imm = ((inst as i32 as i64) >> 20) as u64;
-                let addr = self.regs[rs1].wrapping_add(imm);
-                match funct3 {
-                    0x0 => {
-                        // lb
-                        let val = self.load(addr, 8)?;
-                        self.regs[rd] = val as i8 as i64 as u64;
-                        return self.update_pc();
-                    }
-                    0x1 => {
-                        // lh
-                        let val = self.load(addr, 16)?;
-                        self.regs[rd] = val as i16 as i64 as u64;
-                        return self.update_pc();
-                    }
-                    0x2 => {
-                        // lw
-                        let val = self.load(addr, 32)?;
-                        self.regs[rd] = val as i32 as i64 as u64;
-                        return self.update_pc();
-                    }
-                    0x3 => {
-                        // ld
-                        let val = self.load(addr, 64)?;
-                        self.regs[rd] = val;
-                        return self.update_pc();
-                    }
-                    0x4 => {
-                        // lbu
-                        let val = self.load(addr, 8)?;
-                        self.regs[rd] = val;
-                        return self.update_pc();
-                    }
-                    0x5 => {
-                        // lhu
-                        let val = self.load(addr, 16)?;
-                        self.regs[rd] = val;
-                        return self.update_pc();
-                    }
-                    0x6 => {
-                        // lwu
-                        let val = self.load(addr, 32)?;
-                        self.regs[rd] = val;
-                        return self.update_pc();
-                    }
-                    _ => Err(Exception::IllegalInstruction(inst)),
-                    
-                }
-            }
-            0x0f => {
-                // A fence instruction does nothing because this emulator executes an
-                // instruction sequentially on a single thread.
-                match funct3 {
-                    0x0 => { // fence
-                        return self.update_pc();
-                    }
-                    _ => Err(Exception::IllegalInstruction(inst)),
-                }
+    /// Format `len` bytes of the dram at physical address `addr` as lines of
+    /// `address: 16 hex bytes  ascii`. A line that would leave the dram ends the dump with an
+    /// error line, since devices cannot be read without side effects.
+    pub fn hexdump(&self, addr: u64, len: u64) -> String {
+        let mut dump = String::new();
+        let end = addr.saturating_add(len);
+        let mut line_addr = addr;
+        while line_addr < end {
+            let mut bytes = vec![0; (end - line_addr).min(16) as usize];
+            if self.read_phys(line_addr, &mut bytes).is_err() {
+                dump.push_str(&format!("{:#018x}: out of the dram\n", line_addr));
+                break;
             }
-            0x13 => {
-                // imm[11:0] = inst[31:20]
-                let imm = ((inst & 0xfff00000) as i32 as i64 >> 20) as u64;
-                // "The shift amount is encoded in the lower 6 bits of the I-immediate field for RV64I."
-                let shamt = (imm & 0x3f) as u32;
-                match funct3 {
-                    0x0 => {
-                        // addi
-                        self.regs[rd] = self.regs[rs1].wrapping_add(imm);
-                        return self.update_pc();
-                    }
-                    0x1 => {
-                        // slli
-                        self.regs[rd] = self.regs[rs1] << shamt;
-                        return self.update_pc();
-                    }
-                    0x2 => {
-                        // slti
-                        self.regs[rd] = if (self.regs[rs1] as i64) < (imm as i64) { 1 } else { 0 };
-                        return self.update_pc();
-                    }
-                    0x3 => {
-                        // sltiu
-                        self.regs[rd] = if self.regs[rs1] < imm { 1 } else { 0 };
-                        return self.update_pc();
-                    }
-                    0x4 => {
-                        // xori
-                        self.regs[rd] = self.regs[rs1] ^ imm;
-                        return self.update_pc();
-                    }
-                    0x5 => {
-                        match funct7 >> 1 {
-                            // srli
-                            0x00 => {
-                                self.regs[rd] = self.regs[rs1].wrapping_shr(shamt);
-                                return self.update_pc();
-                            },
-                            // srai
-                            0x10 => {
-                                self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shamt) as u64;
-                                return self.update_pc();
-                            }
-                            _ => Err(Exception::IllegalInstruction(inst)),
-                        }
-                    }
-                    0x6 => {
-                        self.regs[rd] = self.regs[rs1] | imm;
-                        return self.update_pc();
-                    }, // ori
-                    0x7 => {
-                        self.regs[rd] = self.regs[rs1] & imm; // andi
-                        return self.update_pc();
-                    }
-                    _ => Err(Exception::IllegalInstruction(inst)),
-                }
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            dump.push_str(&format!("{:#018x}: {:<47}  {}\n", line_addr, hex.join(" "), ascii));
+            line_addr += 16;
+        }
+        dump
+    }
+
+    /// Copy `buf` to the dram at physical address `addr`.
+    pub fn write_phys(&mut self, addr: u64, buf: &[u8]) -> Result<(), Exception> {
+        self.bus.write_phys(addr, buf)
+    }
+
+    /// Load a program at physical address `addr`, over whatever the dram holds there, e.g. to stage
+    /// a second program next to a running one. Panic if it does not fit in the dram.
+    pub fn load_binary_at(&mut self, bytes: &[u8], addr: u64) {
+        if self.write_phys(addr, bytes).is_err() {
+            panic!("The binary does not fit in the dram at {:#x}", addr);
+        }
+    }
+
+    /// Transfer control of the running hart to `pc`, e.g. the entry of a program loaded by
+    /// `load_binary_at`.
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+
+    /// Copy the memory at virtual address `addr` into `buf`, translated as a load of the running
+    /// hart. The buffer may span several pages.
+    pub fn read_virt(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        let mut done = 0;
+        while done < buf.len() {
+            let v_addr = addr.wrapping_add(done as u64);
+            let len = ((PAGE_SIZE - v_addr % PAGE_SIZE) as usize).min(buf.len() - done);
+            let p_addr = self.translate(v_addr, AccessType::Load)?;
+            self.bus.read_phys(p_addr, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Copy `buf` to the memory at virtual address `addr`, translated as a store of the running
+    /// hart. The buffer may span several pages.
+    pub fn write_virt(&mut self, addr: u64, buf: &[u8]) -> Result<(), Exception> {
+        let mut done = 0;
+        while done < buf.len() {
+            let v_addr = addr.wrapping_add(done as u64);
+            let len = ((PAGE_SIZE - v_addr % PAGE_SIZE) as usize).min(buf.len() - done);
+            let p_addr = self.translate(v_addr, AccessType::Store)?;
+            self.bus.write_phys(p_addr, &buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Return true if loads and stores are big-endian in the effective mode. The memory is
+    /// little-endian, so a big-endian access reverses the bytes of the value.
+    fn is_big_endian(&self) -> bool {
+        // 3.1.6.4 Endianness Control in mstatus and mstatush Registers
+        // Instruction fetches are always little-endian.
+        let mask = match self.effective_mode(&AccessType::Load) {
+            Machine => MASK_MBE,
+            Supervisor => MASK_SBE,
+            _ => MASK_UBE,
+        };
+        (self.csr.load(MSTATUS) & mask) != 0
+    }
+
+    /// Get an instruction from the dram. Instructions are not cached, only their translation is, so a
+    /// store to the code is visible to the very next fetch.
+    ///
+    /// With the C extension an instruction is only 2-byte aligned, so it is fetched in two halves
+    /// which may lie on different pages. The upper half is not fetched for a compressed instruction.
+    pub fn fetch(&mut self) -> Result<u64, Exception> {
+        if !self.has_extension(EXT_C) {
+            return self.fetch_parcel(self.pc, 32);
+        }
+        // Within a page both halves are fetched at once with a single translation. If that fails,
+        // they are fetched one by one, since the bytes after a compressed instruction need not be
+        // readable.
+        if self.pc % PAGE_SIZE <= PAGE_SIZE - 4 {
+            if let Ok(word) = self.fetch_parcel(self.pc, 32) {
+                return Ok(if word & 0b11 != 0b11 { word & 0xffff } else { word });
             }
-            0x17 => {
-                // auipc
-                let imm = (inst & 0xfffff000) as i32 as i64 as u64;
-                self.regs[rd] = self.pc.wrapping_add(imm);
-                return self.update_pc();
-            }
-            0x1b => {
-                let imm = ((inst as i32 as i64) >> 20) as u64;
-                // "SLLIW, SRLIW, and SRAIW encodings with imm[5] ̸= 0 are reserved."
-                let shamt = (imm & 0x1f) as u32;
-                match funct3 {
-                    0x0 => {
-                        // addiw
-                        self.regs[rd] = self.regs[rs1].wrapping_add(imm) as i32 as i64 as u64;
-                        return self.update_pc();
-                    }
-                    0x1 => {
-                        // slliw
-                        self.regs[rd] = self.regs[rs1].wrapping_shl(shamt) as i32 as i64 as u64;
-                        return self.update_pc();
+        }
+        let low = self.fetch_parcel(self.pc, 16)?;
+        if low & 0b11 != 0b11 {
+            return Ok(low);
+        }
+        let high = self.fetch_parcel(self.pc.wrapping_add(2), 16)?;
+        Ok((high << 16) | low)
+    }
+
+    fn fetch_parcel(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let p_addr = self.translate(addr, AccessType::Instruction)?;
+        // Only the dram is executable. A device is not read at all, since a read can have side
+        // effects, e.g. claiming an interrupt from the plic.
+        if !(DRAM_BASE..=DRAM_END).contains(&p_addr) {
+            return Err(Exception::InstructionAccessFault(addr));
+        }
+        match self.bus.load(p_addr, size) {
+            Ok(parcel) => Ok(parcel),
+            Err(_e) => Err(Exception::InstructionAccessFault(addr)),
+        }
+    }
+
+    /// Return true if the extension `ext` (one of the EXT_* misa bits) is enabled.
+    pub fn has_extension(&self, ext: u64) -> bool {
+        self.extensions & ext != 0
+    }
+
+    /// The mask of the valid bits of an epc. With the C extension IALIGN=16, and only bit 0 is
+    /// always 0, otherwise IALIGN=32 and bit 1 is masked too.
+    pub(crate) fn epc_mask(&self) -> u64 {
+        if self.has_extension(EXT_C) { !1 } else { !0b11 }
+    }
+
+    /// Return `target` if it is a valid jump target, which must be 2-byte aligned with the C
+    /// extension and 4-byte aligned without it.
+    pub(crate) fn jump_target(&self, target: u64) -> Result<u64, Exception> {
+        let align = if self.has_extension(EXT_C) { 2 } else { 4 };
+        match target % align {
+            0 => Ok(target),
+            _ => Err(Exception::InstructionAddrMisaligned(target)),
+        }
+    }
+
+    #[inline]
+    pub fn update_pc(&mut self) -> Result<u64, Exception> {
+        return Ok(self.pc.wrapping_add(self.inst_len));
+    }
+
+    /// Execute an instruction after decoding. Return the pc of the next instruction on success.
+    /// `self.pc` is left untouched so that a trap can record the faulting instruction in epc.
+    pub fn execute(&mut self, inst: u64) -> Result<u64, Exception> {
+        // Emulate that register x0 is hardwired with all bits equal to 0.
+        self.regs[0] = 0;
+
+        // A compressed instruction is executed as the 32-bit instruction it expands to.
+        let inst = if inst & 0b11 != 0b11 {
+            let inst = inst & 0xffff;
+            self.inst_len = 2;
+            match rvc::expand(inst as u16) {
+                Some(expanded) if self.has_extension(EXT_C) => expanded as u64,
+                _ => return Err(Exception::IllegalInstruction(inst)),
+            }
+        } else {
+            self.inst_len = 4;
+            inst
+        };
+        if !self.has_extension(required_extension(inst)) {
+            return Err(Exception::IllegalInstruction(inst));
+        }
+
+        // The instruction is decoded once, then its variant selects what to do.
+        let instruction = instruction::decode(inst)?;
+        let result = self.execute_instruction(inst, instruction);
+        // A HINT, e.g. addi zero, zero, 5, writes x0 like any other instruction. Clear it again so
+        // that the write is never observed.
+        self.regs[0] = 0;
+        #[cfg(test)]
+        if !matches!(result, Err(Exception::IllegalInstruction(_))) {
+            crate::coverage::record(instruction.name());
+        }
+        let new_pc = result?;
+        self.stats.record(instruction, new_pc != self.pc.wrapping_add(self.inst_len));
+        Ok(new_pc)
+    }
+
+    /// Execute the decoded `instruction`. `inst` is the raw instruction, which an illegal
+    /// instruction exception records.
+    fn execute_instruction(&mut self, inst: u64, instruction: Instruction) -> Result<u64, Exception> {
+        use Instruction::*;
+        match instruction {
+            Lb { rd, rs1, imm } => {
+                let val = self.load(self.regs[rs1].wrapping_add(imm as u64), 8)?;
+                self.regs[rd] = val as i8 as i64 as u64;
+                self.update_pc()
+            }
+            Lh { rd, rs1, imm } => {
+                let val = self.load(self.regs[rs1].wrapping_add(imm as u64), 16)?;
+                self.regs[rd] = val as i16 as i64 as u64;
+                self.update_pc()
+            }
+            Lw { rd, rs1, imm } => {
+                let val = self.load(self.regs[rs1].wrapping_add(imm as u64), 32)?;
+                self.regs[rd] = val as i32 as i64 as u64;
+                self.update_pc()
+            }
+            Ld { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 64)?;
+                self.update_pc()
+            }
+            Lbu { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 8)?;
+                self.update_pc()
+            }
+            Lhu { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 16)?;
+                self.update_pc()
+            }
+            Lwu { rd, rs1, imm } => {
+                self.regs[rd] = self.load(self.regs[rs1].wrapping_add(imm as u64), 32)?;
+                self.update_pc()
+            }
+            // A fence instruction does nothing because this emulator executes an
+            // instruction sequentially on a single thread.
+            // pause (pred=W, succ=0) and the other fences with a zero pred or succ are HINTs
+            // for a spin-wait loop. There is nothing to wait for, so they all do nothing too.
+            Fence => self.update_pc(),
+            // Every fetch reads the memory, there is no instruction cache to flush.
+            FenceI => self.update_pc(),
+            Addi { rd, rs1, imm } => {
+                self.regs[rd] = self.regs[rs1].wrapping_add(imm as u64);
+                self.update_pc()
+            }
+            Slli { rd, rs1, shamt } => {
+                self.regs[rd] = self.regs[rs1] << shamt;
+                self.update_pc()
+            }
+            Slti { rd, rs1, imm } => {
+                self.regs[rd] = slt(self.regs[rs1], imm as u64);
+                self.update_pc()
+            }
+            Sltiu { rd, rs1, imm } => {
+                self.regs[rd] = sltu(self.regs[rs1], imm as u64);
+                self.update_pc()
+            }
+            Xori { rd, rs1, imm } => {
+                self.regs[rd] = self.regs[rs1] ^ imm as u64;
+                self.update_pc()
+            }
+            Srli { rd, rs1, shamt } => {
+                self.regs[rd] = self.regs[rs1].wrapping_shr(shamt);
+                self.update_pc()
+            }
+            Srai { rd, rs1, shamt } => {
+                self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shamt) as u64;
+                self.update_pc()
+            }
+            Ori { rd, rs1, imm } => {
+                self.regs[rd] = self.regs[rs1] | imm as u64;
+                self.update_pc()
+            }
+            Andi { rd, rs1, imm } => {
+                self.regs[rd] = self.regs[rs1] & imm as u64;
+                self.update_pc()
+            }
+            Auipc { rd, imm } => {
+                self.regs[rd] = self.pc.wrapping_add(imm as u64);
+                self.update_pc()
+            }
+            Addiw { rd, rs1, imm } => {
+                self.regs[rd] = self.regs[rs1].wrapping_add(imm as u64) as i32 as i64 as u64;
+                self.update_pc()
+            }
+            Slliw { rd, rs1, shamt } => {
+                self.regs[rd] = self.regs[rs1].wrapping_shl(shamt) as i32 as i64 as u64;
+                self.update_pc()
+            }
+            Srliw { rd, rs1, shamt } => {
+                self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32 as i64 as u64;
+                self.update_pc()
+            }
+            Sraiw { rd, rs1, shamt } => {
+                self.regs[rd] = (self.regs[rs1] as i32).wrapping_shr(shamt) as i64 as u64;
+                self.update_pc()
+            }
+            Sb { rs1, rs2, imm } => {
+                self.store(self.regs[rs1].wrapping_add(imm as u64), 8, self.regs[rs2])?;
+                self.update_pc()
+            }
+            Sh { rs1, rs2, imm } => {
+                self.store(self.regs[rs1].wrapping_add(imm as u64), 16, self.regs[rs2])?;
+                self.update_pc()
+            }
+            Sw { rs1, rs2, imm } => {
+                self.store(self.regs[rs1].wrapping_add(imm as u64), 32, self.regs[rs2])?;
+                self.update_pc()
+            }
+            Sd { rs1, rs2, imm } => {
+                self.store(self.regs[rs1].wrapping_add(imm as u64), 64, self.regs[rs2])?;
+                self.update_pc()
+            }
+            // RV64A: "A" standard extension for atomic instructions
+            AmoaddW { rd, rs1, rs2 } => {
+                let t = self.load_amo(self.regs[rs1], 32)?;
+                self.store(self.regs[rs1], 32, t.wrapping_add(self.regs[rs2]))?;
+                self.regs[rd] = t;
+                self.update_pc()
+            }
+            AmoaddD { rd, rs1, rs2 } => {
+                let t = self.load_amo(self.regs[rs1], 64)?;
+                self.store(self.regs[rs1], 64, t.wrapping_add(self.regs[rs2]))?;
+                self.regs[rd] = t;
+                self.update_pc()
+            }
+            AmoswapW { rd, rs1, rs2 } => {
+                let t = self.load_amo(self.regs[rs1], 32)?;
+                self.store(self.regs[rs1], 32, self.regs[rs2])?;
+                self.regs[rd] = t;
+                self.update_pc()
+            }
+            AmoswapD { rd, rs1, rs2 } => {
+                let t = self.load_amo(self.regs[rs1], 64)?;
+                self.store(self.regs[rs1], 64, self.regs[rs2])?;
+                self.regs[rd] = t;
+                self.update_pc()
+            }
+            Add { rd, rs1, rs2 } => {
+                self.regs[rd] = self.regs[rs1].wrapping_add(self.regs[rs2]);
+                self.update_pc()
+            }
+            Mul { rd, rs1, rs2 } => {
+                self.regs[rd] = self.regs[rs1].wrapping_mul(self.regs[rs2]);
+                self.update_pc()
+            }
+            Sub { rd, rs1, rs2 } => {
+                self.regs[rd] = self.regs[rs1].wrapping_sub(self.regs[rs2]);
+                self.update_pc()
+            }
+            // "SLL, SRL, and SRA perform logical left, logical right, and arithmetic right
+            // shifts on the value in register rs1 by the shift amount held in register rs2.
+            // In RV64I, only the low 6 bits of rs2 are considered for the shift amount."
+            Sll { rd, rs1, rs2 } => {
+                self.regs[rd] = self.regs[rs1].wrapping_shl((self.regs[rs2] & 0x3f) as u32);
+                self.update_pc()
+            }
+            Slt { rd, rs1, rs2 } => {
+                self.regs[rd] = slt(self.regs[rs1], self.regs[rs2]);
+                self.update_pc()
+            }
+            Sltu { rd, rs1, rs2 } => {
+                self.regs[rd] = sltu(self.regs[rs1], self.regs[rs2]);
+                self.update_pc()
+            }
+            Xor { rd, rs1, rs2 } => {
+                self.regs[rd] = self.regs[rs1] ^ self.regs[rs2];
+                self.update_pc()
+            }
+            Srl { rd, rs1, rs2 } => {
+                self.regs[rd] = self.regs[rs1].wrapping_shr((self.regs[rs2] & 0x3f) as u32);
+                self.update_pc()
+            }
+            Sra { rd, rs1, rs2 } => {
+                self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr((self.regs[rs2] & 0x3f) as u32) as u64;
+                self.update_pc()
+            }
+            Or { rd, rs1, rs2 } => {
+                self.regs[rd] = self.regs[rs1] | self.regs[rs2];
+                self.update_pc()
+            }
+            And { rd, rs1, rs2 } => {
+                self.regs[rd] = self.regs[rs1] & self.regs[rs2];
+                self.update_pc()
+            }
+            Lui { rd, imm } => {
+                self.regs[rd] = imm as u64;
+                self.update_pc()
+            }
+            Addw { rd, rs1, rs2 } => {
+                self.regs[rd] = self.regs[rs1].wrapping_add(self.regs[rs2]) as i32 as i64 as u64;
+                self.update_pc()
+            }
+            Subw { rd, rs1, rs2 } => {
+                self.regs[rd] = ((self.regs[rs1].wrapping_sub(self.regs[rs2])) as i32) as u64;
+                self.update_pc()
+            }
+            // "The shift amount is given by rs2[4:0]."
+            Sllw { rd, rs1, rs2 } => {
+                let shamt = (self.regs[rs2] & 0x1f) as u32;
+                self.regs[rd] = (self.regs[rs1] as u32).wrapping_shl(shamt) as i32 as u64;
+                self.update_pc()
+            }
+            Srlw { rd, rs1, rs2 } => {
+                let shamt = (self.regs[rs2] & 0x1f) as u32;
+                self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32 as u64;
+                self.update_pc()
+            }
+            Sraw { rd, rs1, rs2 } => {
+                let shamt = (self.regs[rs2] & 0x1f) as u32;
+                self.regs[rd] = ((self.regs[rs1] as i32) >> (shamt as i32)) as u64;
+                self.update_pc()
+            }
+            Divw { rd, rs1, rs2 } => {
+                let dividend = self.regs[rs1] as i32;
+                let divisor = self.regs[rs2] as i32;
+                self.regs[rd] = match divisor {
+                    0 => 0xffffffff_ffffffff,
+                    // The overflow case i32::MIN / -1 returns the dividend.
+                    _ => dividend.wrapping_div(divisor) as i64 as u64,
+                };
+                self.update_pc()
+            }
+            Remw { rd, rs1, rs2 } => {
+                let dividend = self.regs[rs1] as i32;
+                let divisor = self.regs[rs2] as i32;
+                self.regs[rd] = match divisor {
+                    0 => dividend as i64 as u64,
+                    // The overflow case i32::MIN % -1 returns 0.
+                    _ => dividend.wrapping_rem(divisor) as i64 as u64,
+                };
+                self.update_pc()
+            }
+            // divuw and remuw only use the low 32 bits of their operands.
+            Divuw { rd, rs1, rs2 } => {
+                let dividend = self.regs[rs1] as u32;
+                let divisor = self.regs[rs2] as u32;
+                self.regs[rd] = match divisor {
+                    0 => 0xffffffff_ffffffff,
+                    _ => (dividend / divisor) as i32 as i64 as u64,
+                };
+                self.update_pc()
+            }
+            Remuw { rd, rs1, rs2 } => {
+                let dividend = self.regs[rs1] as u32;
+                let divisor = self.regs[rs2] as u32;
+                self.regs[rd] = match divisor {
+                    0 => dividend as i32 as i64 as u64,
+                    _ => (dividend % divisor) as i32 as i64 as u64,
+                };
+                self.update_pc()
+            }
+            Beq { rs1, rs2, imm } => self.branch(self.regs[rs1] == self.regs[rs2], imm),
+            Bne { rs1, rs2, imm } => self.branch(self.regs[rs1] != self.regs[rs2], imm),
+            Blt { rs1, rs2, imm } => self.branch(slt(self.regs[rs1], self.regs[rs2]) == 1, imm),
+            Bge { rs1, rs2, imm } => self.branch(slt(self.regs[rs1], self.regs[rs2]) == 0, imm),
+            Bltu { rs1, rs2, imm } => self.branch(sltu(self.regs[rs1], self.regs[rs2]) == 1, imm),
+            Bgeu { rs1, rs2, imm } => self.branch(sltu(self.regs[rs1], self.regs[rs2]) == 0, imm),
+            Jalr { rd, rs1, imm } => {
+                let t = self.pc.wrapping_add(self.inst_len);
+                let new_pc = self.jump_target((self.regs[rs1].wrapping_add(imm as u64)) & !1)?;
+                self.regs[rd] = t;
+                Ok(new_pc)
+            }
+            Jal { rd, imm } => {
+                let new_pc = self.jump_target(self.pc.wrapping_add(imm as u64))?;
+                self.regs[rd] = self.pc.wrapping_add(self.inst_len);
+                Ok(new_pc)
+            }
+            // ECALL and EBREAK cause the receiving privilege mode’s epc register to be set to the address of
+            // the ECALL or EBREAK instruction itself, not the address of the following instruction.
+            Ecall => {
+                // Makes a request of the execution environment by raising an environment call exception.
+                // The hook is taken out while it runs, like the one of ebreak.
+                if self.mode == Machine {
+                    if let Some(mut hook) = self.on_machine_ecall.take() {
+                        let pc = self.pc;
+                        let handled = hook(self);
+                        if self.on_machine_ecall.is_none() {
+                            self.on_machine_ecall = Some(hook);
+                        }
+                        if handled {
+                            return Ok(pc.wrapping_add(self.inst_len));
+                        }
                     }
-                    0x5 => {
-                        match funct7 {
-                            0x00 => {
-                                // srliw
-                                self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32
-                                    as i64 as u64;
-                                return self.update_pc();
-                            }
-                            0x20 => {
-                                // sraiw
-                                self.regs[rd] =
-                                    (self.regs[rs1] as i32).wrapping_shr(shamt) as i64 as u64;
-                                return self.update_pc();
-                            }
-                            _ => Err(Exception::IllegalInstruction(inst)),
+                }
+                match self.mode {
+                    User => Err(Exception::EnvironmentCallFromUMode(self.pc)),
+                    Supervisor => Err(Exception::EnvironmentCallFromSMode(self.pc)),
+                    Machine => Err(Exception::EnvironmentCallFromMMode(self.pc)),
+                }
+            }
+            Ebreak => {
+                // Makes a request of the debugger bu raising a Breakpoint exception.
+                // The hook is taken out while it runs so that it can borrow the cpu.
+                match self.on_breakpoint.take() {
+                    Some(mut hook) => {
+                        let pc = self.pc;
+                        hook(self);
+                        if self.on_breakpoint.is_none() {
+                            self.on_breakpoint = Some(hook);
                         }
+                        Ok(pc.wrapping_add(self.inst_len))
                     }
-                    _ => Err(Exception::IllegalInstruction(inst)),
-                    
+                    None => Err(Exception::Breakpoint(self.pc)),
                 }
             }
-            0x23 => {
-                // imm[11:5|4:0] = inst[31:25|11:7]
-                let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f);
-                let addr = self.regs[rs1].wrapping_add(imm);
-                match funct3 {
-                    0x0 => {self.store(addr, 8, self.regs[rs2])?;  self.update_pc()}, // sb
-                    0x1 => {self.store(addr, 16, self.regs[rs2])?; self.update_pc()}, // sh
-                    0x2 => {self.store(addr, 32, self.regs[rs2])?; self.update_pc()}, // sw
-                    0x3 => {self.store(addr, 64, self.regs[rs2])?; self.update_pc()}, // sd
-                    _ => unreachable!(),
+            Sret => {
+                // When the SRET instruction is executed to return from the trap
+                // handler, the privilege level is set to user mode if the SPP
+                // bit is 0, or supervisor mode if the SPP bit is 1. The SPP bit
+                // is SSTATUS[8].
+                let mut sstatus = self.csr.load(SSTATUS);
+                self.mode = if sstatus & MASK_SPP != 0 { Supervisor } else { User };
+                // The SPIE bit is SSTATUS[5] and the SIE bit is the SSTATUS[1]
+                let spie = (sstatus & MASK_SPIE) >> 5;
+                // set SIE = SPIE
+                sstatus = (sstatus & !MASK_SIE) | (spie << 1);
+                // set SPIE = 1
+                sstatus |= MASK_SPIE;
+                // set SPP the least privilege mode (u-mode)
+                sstatus &= !MASK_SPP;
+                self.csr.store(SSTATUS, sstatus);
+                // set the pc to CSRs[sepc].
+                // whenever IALIGN=32, bit sepc[1] is masked on reads so that it appears to be 0. This
+                // masking occurs also for the implicit read by the SRET instruction. 
+                Ok(self.csr.load(SEPC) & self.epc_mask())
+            }
+            Mret => {
+                let mut mstatus = self.csr.load(MSTATUS);
+                // MPP is two bits wide at MSTATUS[12:11]
+                self.mode = mpp(mstatus);
+                // The MPIE bit is MSTATUS[7] and the MIE bit is the MSTATUS[3].
+                let mpie = (mstatus & MASK_MPIE) >> 7;
+                // set MIE = MPIE
+                mstatus = (mstatus & !MASK_MIE) | (mpie << 3);
+                // set MPIE = 1
+                mstatus |= MASK_MPIE;
+                // set MPP the least privilege mode (u-mode)
+                mstatus &= !MASK_MPP;
+                // If MPP != M, sets MPRV=0
+                mstatus &= !MASK_MPRV;
+                self.csr.store(MSTATUS, mstatus);
+                // set the pc to CSRs[mepc].
+                Ok(self.csr.load(MEPC) & self.epc_mask())
+            }
+            Wfi => {
+                // The hart resumes after the wfi once an interrupt is pending, see `run`. When
+                // TW=1, wfi in a mode less privileged than M traps, as if the wait timed out.
+                if self.mode != Machine && self.csr.load(MSTATUS) & MASK_TW != 0 {
+                    return Err(Exception::IllegalInstruction(inst));
                 }
+                self.waiting_for_interrupt = true;
+                self.update_pc()
             }
-            0x2f => {
-                // RV64A: "A" standard extension for atomic instructions
-                let funct5 = (funct7 & 0b1111100) >> 2;
-                let _aq = (funct7 & 0b0000010) >> 1; // acquire access
-                let _rl = funct7 & 0b0000001; // release access
-                match (funct3, funct5) {
-                    (0x2, 0x00) => {
-                        // amoadd.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
-                        return self.update_pc();
-                    }
-                    (0x3, 0x00) => {
-                        // amoadd.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
-                        return self.update_pc();
-                    }
-                    (0x2, 0x01) => {
-                        // amoswap.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, self.regs[rs2])?;
-                        self.regs[rd] = t;
-                        return self.update_pc();
-                    }
-                    (0x3, 0x01) => {
-                        // amoswap.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, self.regs[rs2])?;
-                        self.regs[rd] = t;
-                        return self.update_pc();
-                    }
-                    _ => Err(Exception::IllegalInstruction(inst)),
-                    
+            SfenceVma { rs1, .. } => {
+                // If rs1=x0, the fence orders all reads and writes made to any level of the page
+                // tables, otherwise only those to the leaf PTE for the virtual address in rs1.
+                // The tlb does not track ASIDs, so a fence on a single address space (rs2!=x0)
+                // flushes as much as a global one. Like satp, it traps in S-mode when TVM=1.
+                if self.mode == Supervisor && self.csr.load(MSTATUS) & MASK_TVM != 0 {
+                    return Err(Exception::IllegalInstruction(inst));
                 }
+                if rs1 == 0 {
+                    self.tlb.clear();
+                } else {
+                    self.tlb.remove(&(self.regs[rs1] >> 12));
+                }
+                self.update_pc()
             }
-            0x33 => {
-                // "SLL, SRL, and SRA perform logical left, logical right, and arithmetic right
-                // shifts on the value in register rs1 by the shift amount held in register rs2.
-                // In RV64I, only the low 6 bits of rs2 are considered for the shift amount."
-                let shamt = ((self.regs[rs2] & 0x3f) as u64) as u32;
-                match (funct3, funct7) {
-                    (0x0, 0x00) => {
-                        // add
-                        self.regs[rd] = self.regs[rs1].wrapping_add(self.regs[rs2]);
-                        return self.update_pc();
-                    }
-                    (0x0, 0x01) => {
-                        // mul
-                        self.regs[rd] = self.regs[rs1].wrapping_mul(self.regs[rs2]);
-                        return self.update_pc();
-                    }
-                    (0x0, 0x20) => {
-                        // sub
-                        self.regs[rd] = self.regs[rs1].wrapping_sub(self.regs[rs2]);
-                        return self.update_pc();
-                    }
-                    (0x1, 0x00) => {
-                        // sll
-                        self.regs[rd] = self.regs[rs1].wrapping_shl(shamt);
-                        return self.update_pc();
-                    }
-                    (0x2, 0x00) => {
-                        // slt
-                        self.regs[rd] = if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) { 1 } else { 0 };
-                        return self.update_pc();
-                    }
-                    (0x3, 0x00) => {
-                        // sltu
-                        self.regs[rd] = if self.regs[rs1] < self.regs[rs2] { 1 } else { 0 };
-                        return self.update_pc();
-                    }
-                    (0x4, 0x00) => {
-                        // xor
-                        self.regs[rd] = self.regs[rs1] ^ self.regs[rs2];
-                        return self.update_pc();
-                    }
-                    (0x5, 0x00) => {
-                        // srl
-                        self.regs[rd] = self.regs[rs1].wrapping_shr(shamt);
-                        return self.update_pc();
-                    }
-                    (0x5, 0x20) => {
-                        // sra
-                        self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shamt) as u64;
-                        return self.update_pc();
-                    }
-                    (0x6, 0x00) => {
-                        // or
-                        self.regs[rd] = self.regs[rs1] | self.regs[rs2];
-                        return self.update_pc();
-                    }
-                    (0x7, 0x00) => {
-                        // and
-                        self.regs[rd] = self.regs[rs1] & self.regs[rs2];
-                        return self.update_pc();
-                    }
-                    _ => Err(Exception::IllegalInstruction(inst)),
+            Csrrw { rd, rs1, csr } => {
+                self.check_csr_access(inst, csr, true)?;
+                // If rd=x0, the csr is not read, so a read side effect does not happen. rs1 is
+                // read first, since it may be rd.
+                let value = self.regs[rs1];
+                if rd != 0 {
+                    self.regs[rd] = self.load_csr(csr);
                 }
+                self.csr.store(csr, value);
+                self.on_csr_write(csr);
+                self.update_pc()
             }
-            0x37 => {
-                // lui
-                self.regs[rd] = (inst & 0xfffff000) as i32 as i64 as u64;
-                return self.update_pc();
+            Csrrs { rd, rs1, csr } => {
+                // If rs1=x0, the csr is not written, so a write side effect does not happen.
+                self.check_csr_access(inst, csr, rs1 != 0)?;
+                let t = self.load_csr(csr);
+                if rs1 != 0 {
+                    // Only the bits that software wrote are modified, e.g. a read of mip includes
+                    // the SEIP line of the PLIC, which is not written back.
+                    self.csr.store(csr, self.csr.load(csr) | self.regs[rs1]);
+                    self.on_csr_write(csr);
+                }
+                self.regs[rd] = t;
+                self.update_pc()
             }
-            0x3b => {
-                // "The shift amount is given by rs2[4:0]."
-                let shamt = (self.regs[rs2] & 0x1f) as u32;
-                match (funct3, funct7) {
-                    (0x0, 0x00) => {
-                        // addw
-                        self.regs[rd] =
-                            self.regs[rs1].wrapping_add(self.regs[rs2]) as i32 as i64 as u64;
-                        return self.update_pc();
-                    }
-                    (0x0, 0x20) => {
-                        // subw
-                        self.regs[rd] =
-                            ((self.regs[rs1].wrapping_sub(self.regs[rs2])) as i32) as u64;
-                        return self.update_pc();
-                    }
-                    (0x1, 0x00) => {
-                        // sllw
-                        self.regs[rd] = (self.regs[rs1] as u32).wrapping_shl(shamt) as i32 as u64;
-                        return self.update_pc();
-                    }
-                    (0x5, 0x00) => {
-                        // srlw
-                        self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32 as u64;
-                        return self.update_pc();
-                    }
-                    (0x5, 0x01) => {
-                        // divu
-                        self.regs[rd] = match self.regs[rs2] {
-                            0 => 0xffffffff_ffffffff,
-                            _ => {
-                                let dividend = self.regs[rs1];
-                                let divisor = self.regs[rs2];
-                                dividend.wrapping_div(divisor)
-                            }
-                        };
-                        return self.update_pc();
-                    }
-                    (0x5, 0x20) => {
-                        // sraw
-                        self.regs[rd] = ((self.regs[rs1] as i32) >> (shamt as i32)) as u64;
-                        return self.update_pc();
-                    }
-                    (0x7, 0x01) => {
-                        // remuw
-                        self.regs[rd] = match self.regs[rs2] {
-                            0 => self.regs[rs1],
-                            _ => {
-                                let dividend = self.regs[rs1] as u32;
-                                let divisor = self.regs[rs2] as u32;
-                                dividend.wrapping_rem(divisor) as i32 as u64
-                            }
-                        };
-                        return self.update_pc();
-                    }
-                    _ => Err(Exception::IllegalInstruction(inst)),
+            Csrrc { rd, rs1, csr } => {
+                self.check_csr_access(inst, csr, rs1 != 0)?;
+                let t = self.load_csr(csr);
+                if rs1 != 0 {
+                    self.csr.store(csr, self.csr.load(csr) & (!self.regs[rs1]));
+                    self.on_csr_write(csr);
                 }
+                self.regs[rd] = t;
+                self.update_pc()
             }
-            0x63 => {
-                // imm[12|10:5|4:1|11] = inst[31|30:25|11:8|7]
-                let imm = (((inst & 0x80000000) as i32 as i64 >> 19) as u64)
-                    | ((inst & 0x80) << 4) // imm[11]
-                    | ((inst >> 20) & 0x7e0) // imm[10:5]
-                    | ((inst >> 7) & 0x1e); // imm[4:1]
-
-                match funct3 {
-                    0x0 => {
-                        // beq
-                        if self.regs[rs1] == self.regs[rs2] {
-                            return Ok(self.pc.wrapping_add(imm));
-                        }
-                        return self.update_pc();
-                    }
-                    0x1 => {
-                        // bne
-                        if self.regs[rs1] != self.regs[rs2] {
-                            return Ok(self.pc.wrapping_add(imm));
-                        }
-                        return self.update_pc();
-                    }
-                    0x4 => {
-                        // blt
-                        if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) {
-                            return Ok(self.pc.wrapping_add(imm));
-                        }
-                        return self.update_pc();
-                    }
-                    0x5 => {
-                        // bge
-                        if (self.regs[rs1] as i64) >= (self.regs[rs2] as i64) {
-                            return Ok(self.pc.wrapping_add(imm));
-                        }
-                        return self.update_pc();
-                    }
-                    0x6 => {
-                        // bltu
-                        if self.regs[rs1] < self.regs[rs2] {
-                            return Ok(self.pc.wrapping_add(imm));
-                        }
-                        return self.update_pc();
-                    }
-                    0x7 => {
-                        // bgeu
-                        if self.regs[rs1] >= self.regs[rs2] {
-                            return Ok(self.pc.wrapping_add(imm));
-                        }
-                        return self.update_pc();
-                    }
-                    _ => Err(Exception::IllegalInstruction(inst)),
-                    
+            Csrrwi { rd, uimm, csr } => {
+                self.check_csr_access(inst, csr, true)?;
+                if rd != 0 {
+                    self.regs[rd] = self.load_csr(csr);
                 }
+                self.csr.store(csr, uimm);
+                self.on_csr_write(csr);
+                self.update_pc()
             }
-            0x67 => {
-                // jalr
-                let t = self.pc + 4;
-
-                let imm = ((((inst & 0xfff00000) as i32) as i64) >> 20) as u64;
-                let new_pc = (self.regs[rs1].wrapping_add(imm)) & !1;
-
+            Csrrsi { rd, uimm, csr } => {
+                // The same as csrrs, zimm=0 does not write the csr.
+                self.check_csr_access(inst, csr, uimm != 0)?;
+                let t = self.load_csr(csr);
+                if uimm != 0 {
+                    self.csr.store(csr, self.csr.load(csr) | uimm);
+                    self.on_csr_write(csr);
+                }
                 self.regs[rd] = t;
-                return Ok(new_pc);
-            }
-            0x6f => {
-                // jal
-                self.regs[rd] = self.pc + 4;
-
-                // imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
-                let imm = (((inst & 0x80000000) as i32 as i64 >> 11) as u64) // imm[20]
-                    | (inst & 0xff000) // imm[19:12]
-                    | ((inst >> 9) & 0x800) // imm[11]
-                    | ((inst >> 20) & 0x7fe); // imm[10:1]
-
-                return Ok(self.pc.wrapping_add(imm));
-            }
-            0x73 => {
-                let csr_addr = ((inst & 0xfff00000) >> 20) as usize;
-                match funct3 {
-                    0x0 => {
-                        match (rs2, funct7) {
-                            // ECALL and EBREAK cause the receiving privilege mode’s epc register to be set to the address of
-                            // the ECALL or EBREAK instruction itself, not the address of the following instruction.
-                            (0x0, 0x0) => {
-                                // ecall
-                                // Makes a request of the execution environment by raising an environment call exception.
-                                match self.mode {
-                                    User => Err(Exception::EnvironmentCallFromUMode(self.pc)),
-                                    Supervisor => Err(Exception::EnvironmentCallFromSMode(self.pc)),
-                                    Machine => Err(Exception::EnvironmentCallFromMMode(self.pc)),
-                                    _ => unreachable!(),
-                                }
-                            }
-                            (0x1, 0x0) => {
-                                // ebreak
-                                // Makes a request of the debugger bu raising a Breakpoint exception.
-                                return Err(Exception::Breakpoint(self.pc));
-                            }
-                             (0x2, 0x8) => {
-                                // sret
-                                // When the SRET instruction is executed to return from the trap
-                                // handler, the privilege level is set to user mode if the SPP
-                                // bit is 0, or supervisor mode if the SPP bit is 1. The SPP bit
-                                // is SSTATUS[8].
-                                let mut sstatus = self.csr.load(SSTATUS);
-                                self.mode = (sstatus & MASK_SPP) >> 8;
-                                // The SPIE bit is SSTATUS[5] and the SIE bit is the SSTATUS[1]
-                                let spie = (sstatus & MASK_SPIE) >> 5;
-                                // set SIE = SPIE
-                                sstatus = (sstatus & !MASK_SIE) | (spie << 1);
-                                // set SPIE = 1
-                                sstatus |= MASK_SPIE;
-                                // set SPP the least privilege mode (u-mode)
-                                sstatus &= !MASK_SPP;
-                                self.csr.store(SSTATUS, sstatus);
-                                // set the pc to CSRs[sepc].
-                                // whenever IALIGN=32, bit sepc[1] is masked on reads so that it appears to be 0. This
-                                // masking occurs also for the implicit read by the SRET instruction. 
-                                let new_pc = self.csr.load(SEPC) & !0b11;
-                                return Ok(new_pc);
-                            }
-                            (0x2, 0x18) => {
-                                // mret
-                                let mut mstatus = self.csr.load(MSTATUS);
-                                // MPP is two bits wide at MSTATUS[12:11]
-                                self.mode = (mstatus & MASK_MPP) >> 11;
-                                // The MPIE bit is MSTATUS[7] and the MIE bit is the MSTATUS[3].
-                                let mpie = (mstatus & MASK_MPIE) >> 7;
-                                // set MIE = MPIE
-                                mstatus = (mstatus & !MASK_MIE) | (mpie << 3);
-                                // set MPIE = 1
-                                mstatus |= MASK_MPIE;
-                                // set MPP the least privilege mode (u-mode)
-                                mstatus &= !MASK_MPP;
-                                // If MPP != M, sets MPRV=0
-                                mstatus &= !MASK_MPRV;
-                                self.csr.store(MSTATUS, mstatus);
-                                // set the pc to CSRs[mepc].
-                                let new_pc = self.csr.load(MEPC) & !0b11;
-                                return Ok(new_pc);
-                            }
-                            (_, 0x9) => {
-                                // sfence.vma
-                                // Do nothing.
-                                return self.update_pc();
-                            }
-                            _ => Err(Exception::IllegalInstruction(inst)),
-                        }
-                    }
-                    0x1 => {
-                        // csrrw
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, self.regs[rs1]);
-                        self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
-                        return self.update_pc();
-                    }
-                    0x2 => {
-                        // csrrs
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t | self.regs[rs1]);
-                        self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
-                        return self.update_pc();
-                    }
-                    0x3 => {
-                        // csrrc
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t & (!self.regs[rs1]));
-                        self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
-                        return self.update_pc();
-                    }
-                    0x5 => {
-                        // csrrwi
-                        let zimm = rs1 as u64;
-                        self.regs[rd] = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, zimm);
-
-                        self.update_paging(csr_addr);
-                        return self.update_pc();
-                    }
-                    0x6 => {
-                        // csrrsi
-                        let zimm = rs1 as u64;
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t | zimm);
-                        self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
-                        return self.update_pc();
-                    }
-                    0x7 => {
-                        // csrrci
-                        let zimm = rs1 as u64;
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t & (!zimm));
-                        self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
-                        return self.update_pc();
-                    }
-                    _ => Err(Exception::IllegalInstruction(inst)),
+                self.update_pc()
+            }
+            Csrrci { rd, uimm, csr } => {
+                self.check_csr_access(inst, csr, uimm != 0)?;
+                let t = self.load_csr(csr);
+                if uimm != 0 {
+                    self.csr.store(csr, self.csr.load(csr) & (!uimm));
+                    self.on_csr_write(csr);
                 }
+                self.regs[rd] = t;
+                self.update_pc()
             }
-            _ => Err(Exception::IllegalInstruction(inst)),
         }
     }
+
+    /// Go to the branch target at pc + `imm` if `taken`, otherwise to the next instruction.
+    fn branch(&mut self, taken: bool, imm: i64) -> Result<u64, Exception> {
+        if taken {
+            return self.jump_target(self.pc.wrapping_add(imm as u64));
+        }
+        self.update_pc()
+    }
+
+    /// The load of an AMO. An AMO to an address that is not naturally aligned raises a
+    /// store/AMO address-misaligned exception, even though its load comes first.
+    fn load_amo(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if !addr.is_multiple_of(size / 8) {
+            return Err(Exception::StoreAMOAddrMisaligned(addr));
+        }
+        self.load(addr, size)
+    }
+
+    /// Check that the current mode may access `csr`, and write it if `write` is set.
+    fn check_csr_access(&self, inst: u64, csr: usize, write: bool) -> Result<(), Exception> {
+        // 2.1 CSR Address Mapping Conventions
+        // The top two bits (csr[11:10]) indicate whether the register is read/write (00, 01, or 10) or
+        // read-only (11). The next two bits (csr[9:8]) encode the lowest privilege level that can access
+        // the CSR. Attempts to access a CSR without appropriate privilege level or to write a read-only
+        // register raise illegal instruction exceptions. So does an access to a CSR that does not
+        // exist.
+        let read_only = (csr >> 10) & 0b11 == 0b11;
+        let privilege = ((csr >> 8) & 0b11) as u64;
+        if !is_implemented(csr) || u64::from(self.mode) < privilege || (write && read_only) {
+            return Err(Exception::IllegalInstruction(inst));
+        }
+        // When TVM=1, S-mode access to satp traps so that M-mode can virtualize the page tables.
+        if csr == SATP && self.mode == Supervisor && self.csr.load(MSTATUS) & MASK_TVM != 0 {
+            return Err(Exception::IllegalInstruction(inst));
+        }
+        Ok(())
+    }
+}
+
+/// Check that a descriptor index read from the guest names one of the DESC_NUM descriptors.
+fn check_desc_index(index: u64) -> Result<u64, DiskError> {
+    if index < DESC_NUM as u64 { Ok(index) } else { Err(DiskError::DescIndex(index)) }
 }
 
+/// Check that the `len` bytes at `addr` are all inside the dram.
+fn check_dram(addr: u64, len: u64) -> Result<(), DiskError> {
+    match addr.checked_add(len) {
+        Some(end) if addr >= DRAM_BASE && end <= DRAM_END + 1 => Ok(()),
+        _ => Err(DiskError::BufferOutOfRange { addr, len }),
+    }
+}
 
+/// The extension that a 32-bit instruction belongs to, as a misa bit. The base instructions,
+/// Zicsr and Zifencei belong to I.
+pub(crate) fn required_extension(inst: u64) -> u64 {
+    match inst & 0x7f {
+        0x33 | 0x3b if (inst >> 25) == 0x01 => EXT_M,
+        0x2f => EXT_A,
+        _ => EXT_I,
+    }
+}
 
-#[cfg(test)]
-mod test {
-    use std::fs::File;
-    use std::io::{Write, Read};
-    use std::process::Command;
-    use super::*;
+/// The page fault that corresponds to `access_type`.
+fn page_fault(addr: u64, access_type: AccessType) -> Exception {
+    match access_type {
+        AccessType::Instruction => Exception::InstructionPageFault(addr),
+        AccessType::Load => Exception::LoadPageFault(addr),
+        AccessType::Store => Exception::StoreAMOPageFault(addr),
+    }
+}
 
-    fn generate_rv_assembly(c_src: &str) {
-        let cc = "clang";
-        let output = Command::new(cc).arg("-S")
-                            .arg(c_src)
-                            .arg("-nostdlib")
-                            .arg("-march=rv64g")
-                            .arg("-mabi=lp64")
-                            .arg("--target=riscv64")
-                            .arg("-mno-relax")
-                            .output()
-                            .expect("Failed to generate rv assembly");
-        println!("{}", String::from_utf8_lossy(&output.stderr));
-    }
-
-    fn generate_rv_obj(assembly: &str) {
-        let cc = "clang";
-        let pieces: Vec<&str> = assembly.split(".").collect();
-        let output = Command::new(cc).arg("-Wl,-Ttext=0x0")
-                            .arg("-nostdlib")
-                            .arg("-march=rv64g")
-                            .arg("-mabi=lp64")
-                            .arg("--target=riscv64")
-                            .arg("-mno-relax")
-                            .arg("-o")
-                            .arg(&pieces[0])
-                            .arg(assembly)
-                            .output()
-                            .expect("Failed to generate rv object");
-        println!("{}", String::from_utf8_lossy(&output.stderr));
-    }
-
-    fn generate_rv_binary(obj: &str) {
-        let objcopy = "llvm-objcopy";
-        let output = Command::new(objcopy).arg("-O")
-                                .arg("binary")
-                                .arg(obj)
-                                .arg(obj.to_owned() + ".bin")
-                                .output()
-                                .expect("Failed to generate rv binary");
-        println!("{}", String::from_utf8_lossy(&output.stderr));
-    }
-
-    fn rv_helper(code: &str, testname: &str, n_clock: usize) -> Result<Cpu, std::io::Error> {
-        let filename = testname.to_owned() + ".s";
-        let mut file = File::create(&filename)?;
-        file.write(&code.as_bytes())?;
-        generate_rv_obj(&filename);
-        generate_rv_binary(testname);
-        let mut file_bin = File::open(testname.to_owned() + ".bin")?;
-        let mut code = Vec::new();
-        file_bin.read_to_end(&mut code)?;
-        let mut cpu = Cpu::new(code, vec![]);
+/// The mode in the MPP field of `mstatus`. The reserved value 0b10 is read as U-mode.
+fn mpp(mstatus: u64) -> Mode {
+    Mode::try_from((mstatus & MASK_MPP) >> 11).unwrap_or(User)
+}
 
-        for _i in 0..n_clock {
-            let inst = match cpu.fetch() {
-                Ok(inst) => inst,
-                Err(_err) => break,
-            };
-            match cpu.execute(inst) {
-                Ok(new_pc) => cpu.pc = new_pc,
-                Err(err) => println!("{}", err),
-            };
-        }
+/// 1 if `a` is less than `b` as signed integers, otherwise 0.
+#[inline]
+fn slt(a: u64, b: u64) -> u64 {
+    ((a as i64) < (b as i64)) as u64
+}
 
-        return Ok(cpu);
+/// 1 if `a` is less than `b` as unsigned integers, otherwise 0.
+#[inline]
+fn sltu(a: u64, b: u64) -> u64 {
+    (a < b) as u64
+}
+
+/// Reverse the byte order of the lowest `size` bits of `value`.
+fn swap_bytes(value: u64, size: u64) -> u64 {
+    match size {
+        16 => (value as u16).swap_bytes() as u64,
+        32 => (value as u32).swap_bytes() as u64,
+        64 => value.swap_bytes(),
+        _ => value,
     }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use std::time::Instant;
+    use super::*;
+    use crate::testutil::*;
 
     macro_rules! riscv_test {
-        ( $code:expr, $name:expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
-            match rv_helper($code, $name, $clock) {
-                Ok(cpu) => { 
-                    $(assert_eq!(cpu.reg($real), $expect);)*
-                }
-                Err(e) => { println!("error: {}", e); assert!(false); }
-            } 
+        ( $code:expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
+            let cpu = compile_and_run($code, $clock);
+            $(assert_eq!(cpu.reg($real).unwrap(), $expect);)*
         };
     }
 
     #[test]
     fn test_addi() {
         let code = "addi x31, x0, 42";
-        riscv_test!(code, "test_addi", 1, "x31" => 42);
+        riscv_test!(code, 1, "x31" => 42);
+    }
+
+    // The byte-level tests run hand-encoded instructions, so they need no toolchain.
+    #[test]
+    fn test_bytes_add() {
+        // addi a0, zero, 40; addi a1, zero, 2; add a2, a0, a1; sub a3, a0, a1
+        let cpu = run_binary(encode(&[0x02800513, 0x00200593, 0x00b50633, 0x40b506b3]), 4);
+        assert_eq!(cpu.reg("a2"), Ok(42));
+        assert_eq!(cpu.reg("a3"), Ok(38));
+    }
+
+    #[test]
+    fn test_bytes_load() {
+        // auipc t0, 0; ld a0, 16(t0); lw a1, 20(t0); lbu a2, 23(t0), followed by a dword of data.
+        let code = encode(&[0x00000297, 0x0102b503, 0x0142a583, 0x0172c603, 0x44332211, 0x88776655]);
+        let cpu = run_binary(code, 4);
+        assert_eq!(cpu.reg("a0"), Ok(0x8877665544332211));
+        assert_eq!(cpu.reg("a1"), Ok(0xffffffff88776655));
+        assert_eq!(cpu.reg("a2"), Ok(0x88));
+    }
+
+    #[test]
+    fn test_bytes_branch() {
+        // addi a0, zero, 1; beqz a0, 8 (not taken); addi a1, zero, 5; bnez a0, 8 (taken);
+        // addi a2, zero, 7 (skipped); addi a3, zero, 9
+        let code = encode(&[0x00100513, 0x00050463, 0x00500593, 0x00051463, 0x00700613, 0x00900693]);
+        let cpu = run_binary(code, 5);
+        assert_eq!(cpu.reg("a1"), Ok(5));
+        assert_eq!(cpu.reg("a2"), Ok(0));
+        assert_eq!(cpu.reg("a3"), Ok(9));
+        assert_eq!(cpu.pc, DRAM_BASE + 24);
     }
 
     #[test]
@@ -1202,25 +2144,64 @@ mod test {
             addi	sp,sp,16
             jr	ra
         ";
-        riscv_test!(code, "test_simple", 20, "a0" => 42);
+        riscv_test!(code, 20, "a0" => 42);
     }
 
     #[test]
     fn test_lui() {
         let code = "lui a0, 42";
-        riscv_test!(code, "test_lui", 1, "a0" => 42 << 12);
+        riscv_test!(code, 1, "a0" => 42 << 12);
     }
 
     #[test]
     fn test_auipc() {
         let code = "auipc a0, 42";
-        riscv_test!(code, "test_auipc", 1, "a0" => DRAM_BASE + (42 << 12));
+        riscv_test!(code, 1, "a0" => DRAM_BASE + (42 << 12));
+    }
+
+    #[test]
+    fn test_lui_negative() {
+        // bit 31 of the result is set, so it is sign-extended to 64 bits.
+        let code = "lui a0, 0x80000";
+        riscv_test!(code, 1, "a0" => 0xffff_ffff_8000_0000);
+    }
+
+    #[test]
+    fn test_divw_remw() {
+        // Only the low 32 bits of a2 are the divisor, which is zero.
+        let code = "
+            lui a0, 0x80000
+            addi a1, zero, -1
+            addi a2, zero, 1
+            slli a2, a2, 32
+            addi a3, zero, 7
+            divw a4, a0, a1
+            remw a5, a0, a1
+            divw a6, a3, a2
+            remw a7, a3, a2
+            divw s2, a3, a1
+            remw s3, a0, a3
+        ";
+        riscv_test!(code, 11,
+            "a4" => 0xffff_ffff_8000_0000,
+            "a5" => 0,
+            "a6" => u64::MAX,
+            "a7" => 7,
+            "s2" => -7i64 as u64,
+            "s3" => -2i64 as u64
+        );
+    }
+
+    #[test]
+    fn test_auipc_negative() {
+        let code = "auipc a0, 0xfffff";
+        riscv_test!(code, 1, "a0" => DRAM_BASE - 0x1000);
     }
 
     #[test]
     fn test_jal() {
         let code = "jal a0, 42";
-        riscv_test!(code, "test_jal", 1, "a0" => DRAM_BASE + 4, "pc" => DRAM_BASE + 42);
+        riscv_test!(code, 1, "a0" => DRAM_BASE + 4, "pc" => DRAM_BASE + 42);
     }
 
     #[test]
@@ -1229,7 +2210,7 @@ mod test {
             addi a1, zero, 42
             jalr a0, -8(a1)
         ";
-        riscv_test!(code, "test_jalr", 2, "a0" => DRAM_BASE + 8, "pc" => 34);
+        riscv_test!(code, 2, "a0" => DRAM_BASE + 8, "pc" => 34);
     }
 
     #[test]
@@ -1237,7 +2218,7 @@ mod test {
         let code = "
             beq  x0, x0, 42
         ";
-        riscv_test!(code, "test_beq", 3, "pc" => DRAM_BASE + 42);
+        riscv_test!(code, 3, "pc" => DRAM_BASE + 42);
     }
 
     #[test]
@@ -1246,7 +2227,7 @@ mod test {
             addi x1, x0, 10
             bne  x0, x1, 42
         ";
-        riscv_test!(code, "test_bne", 5, "pc" => DRAM_BASE + 42 + 4);
+        riscv_test!(code, 5, "pc" => DRAM_BASE + 42 + 4);
     }
 
     #[test]
@@ -1256,7 +2237,7 @@ mod test {
             addi x2, x0, 20
             blt  x1, x2, 42
         ";
-        riscv_test!(code, "test_blt", 10, "pc" => DRAM_BASE + 42 + 8);
+        riscv_test!(code, 10, "pc" => DRAM_BASE + 42 + 8);
     }
 
     #[test]
@@ -1266,7 +2247,7 @@ mod test {
             addi x2, x0, 20
             bge  x2, x1, 42
         ";
-        riscv_test!(code, "test_bge", 10, "pc" => DRAM_BASE + 42 + 8);
+        riscv_test!(code, 10, "pc" => DRAM_BASE + 42 + 8);
     }
 
     #[test]
@@ -1276,7 +2257,7 @@ mod test {
             addi x2, x0, 20
             bltu x1, x2, 42
         ";
-        riscv_test!(code, "test_bltu", 10, "pc" => DRAM_BASE + 42 + 8);
+        riscv_test!(code, 10, "pc" => DRAM_BASE + 42 + 8);
     }
 
     #[test]
@@ -1286,7 +2267,7 @@ mod test {
             addi x2, x0, 20
             bgeu x2, x1, 42
         ";
-        riscv_test!(code, "test_bgeu", 10, "pc" => DRAM_BASE + 42 + 8);
+        riscv_test!(code, 10, "pc" => DRAM_BASE + 42 + 8);
     }
 
     #[test]
@@ -1298,7 +2279,7 @@ mod test {
             lb   t1, 8(sp)
             lh   t2, 8(sp)
         ";
-        riscv_test!(code, "test_store_load1", 10, "t1" => 0, "t2" => 256);
+        riscv_test!(code, 10, "t1" => 0, "t2" => 256);
     }
 
     #[test]
@@ -1310,7 +2291,20 @@ mod test {
             slti t3, t0, 42
             sltiu t4, t0, 84
         ";
-        riscv_test!(code, "test_slt", 7, "t2" => 1, "t3" => 1, "t4" => 1);
+        riscv_test!(code, 7, "t2" => 1, "t3" => 1, "t4" => 1);
+    }
+
+    #[test]
+    fn test_sub() {
+        let code = "
+            addi t0, zero, 5
+            addi t1, zero, 7
+            sub  t2, t0, t1
+            subw t3, t0, t1
+            sltu t4, t0, t2
+            sltu t5, t2, t0
+        ";
+        riscv_test!(code, 6, "t2" => -2i64 as u64, "t3" => -2i64 as u64, "t4" => 1, "t5" => 0);
     }
 
     #[test]
@@ -1320,7 +2314,7 @@ mod test {
             xori a1, a0, 0b01
             xor a2, a1, a1 
         ";
-        riscv_test!(code, "test_xor", 5, "a1" => 3, "a2" => 0);
+        riscv_test!(code, 5, "a1" => 3, "a2" => 0);
     }
 
     #[test]
@@ -1330,7 +2324,7 @@ mod test {
             ori  a1, a0, 0b01
             or   a2, a0, a0
         ";
-        riscv_test!(code, "test_or", 3, "a1" => 0b11, "a2" => 0b10);
+        riscv_test!(code, 3, "a1" => 0b11, "a2" => 0b10);
     }
 
     #[test]
@@ -1340,7 +2334,7 @@ mod test {
             andi a1, a0, 0b11
             and  a2, a0, a1
         ";
-        riscv_test!(code, "test_and", 3, "a1" => 0b10, "a2" => 0b10);
+        riscv_test!(code, 3, "a1" => 0b10, "a2" => 0b10);
     }
 
     #[test]
@@ -1353,7 +2347,7 @@ mod test {
             addi s0, zero, 64
             sll  a4, a0, s0
         ";
-        riscv_test!(code, "test_sll", 10, "a2" => 1 << 5, "a3" => 1 << 5, "a4" => 1);
+        riscv_test!(code, 10, "a2" => 1 << 5, "a3" => 1 << 5, "a4" => 1);
     }
 
     #[test]
@@ -1366,7 +2360,7 @@ mod test {
             srli a4, a0, 2
             srl  a5, a0, a1
         ";
-        riscv_test!(code, "test_sra_srl", 10, "a2" => -4 as i64 as u64, "a3" => -2 as i64 as u64, 
+        riscv_test!(code, 10, "a2" => -4 as i64 as u64, "a3" => -2 as i64 as u64, 
                                               "a4" => -8 as i64 as u64 >> 2, "a5" => -8 as i64 as u64 >> 1);
     }
 
@@ -1377,7 +2371,7 @@ mod test {
             lui  a1, 0x7f000
             addw a2, a0, a1
         ";
-        riscv_test!(code, "test_word_op", 29, "a2" => 0x7f00002a);
+        riscv_test!(code, 29, "a2" => 0x7f00002a);
     }
 
     #[test]
@@ -1395,10 +2389,1706 @@ mod test {
             csrrwi zero, sepc, 6
             csrrci zero, sepc, 0 
         ";
-        riscv_test!(code, "test_csrs1", 20, "mstatus" => 1, "mtvec" => 2, "mepc" => 3,
+        riscv_test!(code, 20, "mstatus" => 1, "mtvec" => 2, "mepc" => 3,
                                             "sstatus" => 0, "stvec" => 5, "sepc" => 6);
     }
 
+    #[test]
+    fn test_load_page_fault_tval() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        // The root page table sits at DRAM_BASE and is all zero, so every PTE is invalid.
+        cpu.csr.store(SATP, (8 << 60) | (DRAM_BASE >> 12));
+        cpu.on_csr_write(SATP);
+        let vaddr = 0x1234_5000;
+
+        // trap in M-mode
+        cpu.mode = Supervisor;
+        let e = cpu.load(vaddr, 64).unwrap_err();
+        cpu.handle_exception(e);
+        assert_eq!(cpu.csr.load(MCAUSE), 13);
+        assert_eq!(cpu.csr.load(MTVAL), vaddr);
+
+        // trap in S-mode when the load page fault is delegated
+        cpu.mode = Supervisor;
+        cpu.csr.store(MEDELEG, 1 << 13);
+        let e = cpu.load(vaddr + 8, 64).unwrap_err();
+        cpu.handle_exception(e);
+        assert_eq!(cpu.csr.load(SCAUSE), 13);
+        assert_eq!(cpu.csr.load(STVAL), vaddr + 8);
+    }
+
+    #[test]
+    fn test_mepc_on_exception() {
+        let code = "
+            addi t0, zero, 1
+            addi t1, zero, 2
+            .word 0xffffffff
+            addi t2, zero, 3
+        ";
+        let mut cpu = compile_and_run(code, 2);
+        let inst = cpu.fetch().unwrap();
+        let e = cpu.execute(inst).unwrap_err();
+        cpu.handle_exception(e);
+        assert_eq!(cpu.reg("mepc").unwrap(), DRAM_BASE + 8);
+        assert_eq!(cpu.reg("mcause").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_word_shift_reserved_shamt() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.regs[10] = 1;
+        // slliw a0, a0, 1
+        assert!(cpu.execute(0x0015151b).is_ok());
+        assert_eq!(cpu.regs[10], 2);
+        // slliw a0, a0, 33 and srliw a0, a0, 33 are reserved.
+        assert!(matches!(cpu.execute(0x0215151b), Err(Exception::IllegalInstruction(0x0215151b))));
+        assert!(matches!(cpu.execute(0x0215551b), Err(Exception::IllegalInstruction(0x0215551b))));
+        assert_eq!(cpu.regs[10], 2);
+    }
+
+    #[test]
+    fn test_signed_unsigned_compare() {
+        // -1 has the high bit set, so it is the smallest signed value here and the largest unsigned.
+        assert_eq!((slt(!0, 1), sltu(!0, 1)), (1, 0));
+        assert_eq!((slt(1, !0), sltu(1, !0)), (0, 1));
+        assert_eq!((slt(1, 1), sltu(1, 1)), (0, 0));
+        let code = "
+            li t0, -1
+            li t1, 1
+            slt a0, t0, t1
+            sltu a1, t0, t1
+            slti a2, t0, 1
+            sltiu a3, t1, -1
+            blt t0, t1, 1f
+            li a4, 1
+            1:
+            bltu t0, t1, 2f
+            li a5, 1
+            2:
+            bge t1, t0, 3f
+            li a6, 1
+            3:
+            bgeu t1, t0, 4f
+            li a7, 1
+            4:
+        ";
+        let cpu = compile_and_run(code, 16);
+        let regs = ["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"].map(|r| cpu.reg(r).unwrap());
+        assert_eq!(regs, [1, 0, 1, 1, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_execute_never_panics() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        // A store of a1 to 0(a0) with funct3=0x7, which has no width.
+        cpu.regs[10] = DRAM_BASE;
+        assert!(matches!(cpu.execute(0x00b57023), Err(Exception::IllegalInstruction(0x00b57023))));
+
+        // Some bugs only show with some operands, so the registers are random too, with a bias to
+        // the edge cases of zero, all ones, and values that are zero in their low 32 bits.
+        let mut x: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            // xorshift64
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        for _ in 0..100_000 {
+            for reg in cpu.regs.iter_mut() {
+                *reg = match next() % 4 {
+                    0 => 0,
+                    1 => !0,
+                    2 => next() << 32,
+                    _ => next(),
+                };
+            }
+            cpu.pc = next();
+            let _ = cpu.execute(next() & 0xffff_ffff);
+        }
+    }
+
+    #[test]
+    fn test_divuw_remuw() {
+        // divuw a0, a1, a2 and remuw a0, a1, a2 ignore the upper halves of a1 and a2.
+        let (divuw, remuw) = (0x02c5d53b, 0x02c5f53b);
+        let cases = [
+            // A divisor that is only 0 in its low 32 bits divides by zero.
+            (7, 1 << 32, 0xffffffff_ffffffff, 7),
+            (0xffff_ffff_8000_0007, 1 << 32, 0xffffffff_ffffffff, 0xffffffff_80000007),
+            (0x1_0000_0010, 0x5_0000_0003, 5, 1),
+            // The 32-bit results are sign-extended.
+            (0xffff_fffe, 1, 0xffffffff_fffffffe, 0),
+            (0xffff_ffff, 0x1_ffff_fffe, 1, 0x00000000_00000001),
+            (0xffff_fffe, 0x1_ffff_ffff, 0, 0xffffffff_fffffffe),
+        ];
+        let mut cpu = Cpu::new(vec![], vec![]);
+        for (dividend, divisor, quotient, remainder) in cases {
+            cpu.regs[11] = dividend;
+            cpu.regs[12] = divisor;
+            cpu.execute(divuw).unwrap();
+            assert_eq!(cpu.regs[10], quotient, "divuw {:#x}, {:#x}", dividend, divisor);
+            cpu.execute(remuw).unwrap();
+            assert_eq!(cpu.regs[10], remainder, "remuw {:#x}, {:#x}", dividend, divisor);
+        }
+    }
+
+    #[test]
+    fn test_compressed_jump_target() {
+        // Jump over a c.nop to a c.li that is only 2-byte aligned.
+        let code = "
+            auipc t0, 0
+            addi t0, t0, 14
+            jalr zero, 0(t0)
+            .half 0x0001
+            .half 0x4529
+        ";
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.reg("a0"), Ok(10));
+        assert_eq!(cpu.pc, DRAM_BASE + 16);
+
+        // Without the C extension the target is misaligned, and rd is not written.
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        cpu.extensions &= !EXT_C;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(matches!(cpu.execute(0x000280e7), Err(Exception::InstructionAddrMisaligned(a)) if a == DRAM_BASE + 14));
+        assert_eq!(cpu.reg("ra"), Ok(0));
+        // and a compressed instruction is illegal.
+        assert!(matches!(cpu.execute(0x4529), Err(Exception::IllegalInstruction(0x4529))));
+    }
+
+    #[test]
+    fn test_compressed_trap_return() {
+        // The ecall after the c.nop is only 2-byte aligned, and mret returns right after it.
+        let code = "
+            .half 0x0001
+            ecall
+            addi a0, zero, 1
+            .half 0x0001
+            addi zero, zero, 0
+            csrr t0, mepc
+            addi t0, t0, 4
+            csrw mepc, t0
+            mret
+        ";
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        cpu.csr.store(MTVEC, DRAM_BASE + 16);
+        for _ in 0..7 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 6);
+        assert_eq!(cpu.reg("a0"), Ok(1));
+        assert_eq!(cpu.pc, DRAM_BASE + 10);
+
+        // sret masks sepc the same way, and both mask bit 1 too without the C extension.
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.csr.store(SEPC, DRAM_BASE + 6);
+        assert!(matches!(cpu.execute(0x10200073), Ok(pc) if pc == DRAM_BASE + 6));
+        cpu.extensions &= !EXT_C;
+        cpu.csr.store(MEPC, DRAM_BASE + 6);
+        assert!(matches!(cpu.execute(0x30200073), Ok(pc) if pc == DRAM_BASE + 4));
+    }
+
+    #[test]
+    fn test_compressed_ebreak() {
+        let mut cpu = Cpu::new(vec![0x02, 0x90, 0x01, 0x00], vec![]);
+        cpu.on_breakpoint = Some(Box::new(|cpu: &mut Cpu| cpu.regs[10] = 1));
+        cpu.step().unwrap();
+        // c.ebreak resumes at the next 2-byte instruction.
+        assert_eq!(cpu.regs[10], 1);
+        assert_eq!(cpu.pc, DRAM_BASE + 2);
+    }
+
+    #[test]
+    fn test_reset() {
+        let code = "
+            addi sp, sp, -16
+            addi a0, zero, 42
+            sd a0, 8(sp)
+            csrw mscratch, a0
+            ld a1, 8(sp)
+            addi a1, a1, 1
+            csrw mstatus, a1
+        ";
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        let run = |cpu: &mut Cpu| {
+            for _ in 0..7 {
+                cpu.step().unwrap();
+            }
+            (cpu.regs, cpu.pc, cpu.csr.load(MSCRATCH), cpu.csr.load(MSTATUS))
+        };
+        let first = run(&mut cpu);
+        assert_eq!(first.0[11], 43);
+
+        cpu.reset();
+        assert_eq!((cpu.pc, cpu.mode, cpu.reg("sp"), cpu.reg("a1")), (DRAM_BASE, Machine, Ok(DRAM_END), Ok(0)));
+        assert_eq!((cpu.csr.load(MSCRATCH), cpu.csr.load(MSTATUS)), (0, 0));
+        assert_eq!(run(&mut cpu), first);
+        assert_eq!(cpu.stats.total(), 7);
+    }
+
+    #[test]
+    fn test_reset_devices() {
+        // The devices forget what the first run left in them.
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.bus.store(CLINT_MTIMECMP, 64, 30).unwrap();
+        cpu.bus.plic().raise(VIRTIO_IRQ);
+        cpu.reset();
+        assert_eq!(cpu.bus.load(CLINT_MTIMECMP, 64).unwrap(), 0);
+        assert_eq!(cpu.bus.load(PLIC_PENDING, 32).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reset_pending_timer() {
+        // The timer fires at mtime 30. Before the reset it is long past, so it would be taken as
+        // soon as the second run enables it, if the CLINT kept its state.
+        let code = "
+            li t0, 0x2004000
+            li t1, 30
+            sd t1, 0(t0)
+            li t1, 0x80
+            csrw mie, t1
+            csrsi mstatus, 8
+            1:
+            j 1b
+        ";
+        let handler = DRAM_BASE + 0x100;
+        let mut cpu = CpuBuilder::new(compile(code)).harts(2).csr(MTVEC, handler).build();
+        let taken = |cpu: &mut Cpu, steps: usize| {
+            (0..steps).any(|_| {
+                cpu.step().unwrap();
+                cpu.pc == handler
+            })
+        };
+        assert!(taken(&mut cpu, 40));
+        assert_eq!(cpu.csr.load(MCAUSE), (1 << 63) | 7);
+        cpu.bus.plic().raise(VIRTIO_IRQ);
+        cpu.tlb.insert(0, (0, 0));
+
+        cpu.reset();
+        assert!(cpu.tlb.is_empty() && cpu.harts.iter().all(|hart| hart.tlb.is_empty()));
+        assert_eq!(cpu.bus.clint().mtime(), 0);
+        assert_eq!(cpu.bus.load(PLIC_PENDING, 32).unwrap(), 0);
+        cpu.csr.store(MTVEC, handler);
+        assert!(!taken(&mut cpu, 20));
+        assert_eq!(cpu.csr.load(MCAUSE), 0);
+    }
+
+    #[test]
+    fn test_amo_alignment() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        let addr = DRAM_BASE + 0x1000;
+        cpu.write_phys(addr, &5u32.to_le_bytes()).unwrap();
+        cpu.regs[11] = 2;
+        // amoadd.w a0, a1, (a2)
+        cpu.regs[12] = addr;
+        assert!(cpu.execute(0x00b6252f).is_ok());
+        assert_eq!(cpu.regs[10], 5);
+        assert_eq!(cpu.load(addr, 32).unwrap(), 7);
+
+        cpu.regs[10] = 0;
+        cpu.regs[12] = addr + 2;
+        assert!(matches!(cpu.execute(0x00b6252f), Err(Exception::StoreAMOAddrMisaligned(a)) if a == addr + 2));
+        // amoadd.d a0, a1, (a2) must be 8-byte aligned.
+        cpu.regs[12] = addr + 4;
+        assert!(matches!(cpu.execute(0x00b6352f), Err(Exception::StoreAMOAddrMisaligned(a)) if a == addr + 4));
+        assert_eq!(cpu.regs[10], 0);
+        assert_eq!(cpu.load(addr, 32).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_builder() {
+        let mtvec = DRAM_BASE + 0x100;
+        // ecall
+        let mut cpu = CpuBuilder::new(vec![0x73, 0x00, 0x00, 0x00]).csr(MTVEC, mtvec).csr(MSCRATCH, 7).build();
+        assert_eq!(cpu.csr.load(MSCRATCH), 7);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, mtvec);
+        assert_eq!(cpu.csr.load(MCAUSE), 11);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE);
+
+        let cpu = CpuBuilder::new(vec![]).mode(Supervisor).pc(DRAM_BASE + 8).csr(MEDELEG, !0).build();
+        assert_eq!((cpu.mode, cpu.pc), (Supervisor, DRAM_BASE + 8));
+        assert_eq!(cpu.csr.load(MEDELEG), MASK_MEDELEG);
+    }
+
+    #[test]
+    fn test_custom_device() {
+        struct Counter(u64);
+        impl Device for Counter {
+            fn base(&self) -> u64 { 0x3000_0000 }
+            fn size(&self) -> u64 { 0x100 }
+            fn load(&mut self, _addr: u64, _size: u64) -> Result<u64, Exception> {
+                self.0 += 1;
+                Ok(self.0)
+            }
+            fn store(&mut self, addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+                Err(Exception::StoreAMOAccessFault(addr))
+            }
+        }
+
+        let code = "
+            li  t0, 0x30000000
+            lw  a0, 0(t0)
+            lw  a1, 0(t0)
+            lw  a2, 4(t0)
+        ";
+        let mut cpu = compile_and_run(code, 0);
+        cpu.register_device(Box::new(Counter(0))).unwrap();
+        assert!(matches!(cpu.run(Some(4), None).reason, StopReason::InstLimit));
+        assert_eq!(cpu.reg("a0").unwrap(), 1);
+        assert_eq!(cpu.reg("a1").unwrap(), 2);
+        assert_eq!(cpu.reg("a2").unwrap(), 3);
+    }
+
+    #[test]
+    fn test_load_dtb() {
+        let code = "lw a0, 0(a1)";
+        let mut cpu = compile_and_run(code, 0);
+        cpu.load_dtb(&[0xd0, 0x0d, 0xfe, 0xed, 0, 0, 0, 0x38], DTB_BASE).unwrap();
+        assert_eq!(cpu.reg("a1").unwrap(), DTB_BASE);
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0").unwrap(), 0xffff_ffff_edfe_0dd0);
+        assert!(cpu.load_dtb(&[0; 16], DRAM_END - 8).is_err());
+    }
+
+    #[test]
+    fn test_csr_read_only_forms() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.csr.store(MIDELEG, MASK_SSIP);
+        cpu.csr.store(MIE, MASK_MTIP);
+        cpu.bus.store(CLINT_MTIMECMP, 64, u64::MAX).unwrap();
+        // MSIP would follow the CLINT, so a bit left to software is used.
+        cpu.csr.store(MIP, MASK_STIP | MASK_SSIP);
+        cpu.tlb.insert(0x1000, (DRAM_BASE, 0b111));
+        // csrrs t0, sip, zero; csrrsi t0, sip, 0; csrrc t0, satp, zero
+        for inst in [0x144022f3, 0x144062f3, 0x180032f3] {
+            cpu.execute(inst).unwrap();
+        }
+        assert_eq!(cpu.csr.load(MIP), MASK_STIP | MASK_SSIP);
+        // satp was not written, so the tlb was not flushed.
+        assert_eq!(cpu.tlb.len(), 1);
+        cpu.execute(0x144022f3).unwrap();
+        assert_eq!(cpu.reg("t0"), Ok(MASK_SSIP));
+
+        // csrrs t0, mstatus, t1 still writes.
+        cpu.regs[6] = MASK_MIE;
+        cpu.execute(0x300322f3).unwrap();
+        assert_eq!(cpu.csr.load(MSTATUS), MASK_MIE);
+    }
+
+    #[test]
+    fn test_rdtime() {
+        let code = "
+            rdtime a0
+            addi t0, zero, 1
+            addi t0, t0, 1
+            rdtime a1
+            rdcycle a2
+        ";
+        // `step` ticks the timer, which `execute` alone does not.
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        assert!(matches!(cpu.run(Some(5), None).reason, StopReason::InstLimit));
+        let (t0, t1) = (cpu.reg("a0").unwrap(), cpu.reg("a1").unwrap());
+        assert!(t1 > t0);
+        assert_eq!(t1 - t0, 3);
+        // cycle is not an alias of time.
+        assert_eq!(cpu.reg("a2"), Ok(0));
+        // The debugger view of time follows the CLINT too.
+        assert!(cpu.read(Reg::Csr(TIME)) > t1);
+        assert_eq!(cpu.read(Reg::Csr(TIME)), cpu.bus.clint().mtime());
+    }
+
+    #[test]
+    fn test_load_offset() {
+        // addi a0, zero, 42
+        let mut cpu = CpuBuilder::new(encode(&[0x02a00513])).load_offset(0x1000).harts(2).build();
+        assert_eq!(cpu.pc, DRAM_BASE + 0x1000);
+        assert_eq!(cpu.fetch().unwrap(), 0x02a00513);
+        assert_eq!(cpu.bus.load(DRAM_BASE, 32).unwrap(), 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0").unwrap(), 42);
+        cpu.switch_hart(1);
+        assert_eq!(cpu.pc, DRAM_BASE + 0x1000);
+    }
+
+    #[test]
+    fn test_detect_uninit() {
+        let code = "
+            addi sp, sp, -16
+            sd   zero, 0(sp)
+            ld   a0, 0(sp)
+            ld   a1, 8(sp)
+        ";
+        let mut cpu = CpuBuilder::new(compile(code)).detect_uninit().build();
+        cpu.regs[2] = DRAM_BASE + 0x1000;
+        let result = cpu.run(Some(10), None);
+        // The ld of a0 reads the bytes written by sd, the one of a1 reads never written bytes.
+        assert!(matches!(result.reason, StopReason::FatalException(Exception::LoadAccessFault(a)) if a == DRAM_BASE + 0xff8));
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 12);
+        assert_eq!(cpu.csr.load(MTVAL), DRAM_BASE + 0xff8);
+
+        // Without the option, the memory reads as zero.
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        cpu.regs[2] = DRAM_BASE + 0x1000;
+        assert!(matches!(cpu.run(Some(4), None).reason, StopReason::InstLimit));
+
+        // A compressed instruction is fetched without the bytes after it, which were never written.
+        let mut cpu = CpuBuilder::new(vec![0x29, 0x45]).detect_uninit().build();
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), Ok(10));
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        // loop: addi a0, a0, 1; j loop
+        let code = encode(&[0x00150513, 0xffdff06f]);
+        for ticks_per_inst in [1, 10] {
+            let handler = DRAM_BASE + 0x100;
+            let mut cpu = CpuBuilder::new(code.clone())
+                .csr(MTVEC, handler)
+                .csr(MIE, MASK_MTIP)
+                .csr(MSTATUS, MASK_MIE)
+                .build();
+            cpu.bus.clint().set_timebase(TIMEBASE_FREQ, ticks_per_inst);
+            cpu.bus.store(CLINT_MTIMECMP, 64, 100).unwrap();
+
+            // mtime reaches mtimecmp during the 100 / ticks_per_inst th instruction.
+            let expected = 100 / ticks_per_inst;
+            assert!(matches!(cpu.run(Some(expected - 1), None).reason, StopReason::InstLimit));
+            assert_ne!(cpu.pc, handler);
+            cpu.step().unwrap();
+            assert_eq!(cpu.pc, handler);
+            assert_eq!(cpu.csr.load(MCAUSE), MASK_INTERRUPT_BIT | 7);
+            assert_eq!(cpu.bus.load(CLINT_MTIME, 64).unwrap(), 100);
+        }
+        assert_eq!(crate::clint::Clint::new(CLINT_BASE).timebase_freq(), 10_000_000);
+    }
+
+    #[test]
+    fn test_mode_conversion() {
+        for mode in [User, Supervisor, Machine] {
+            assert_eq!(Mode::try_from(u64::from(mode)), Ok(mode));
+        }
+        assert!(Mode::try_from(0b10).is_err());
+
+        let mut cpu = Cpu::new(vec![], vec![]);
+        for mode in [User, Supervisor, Machine] {
+            cpu.mode = Machine;
+            cpu.csr.store(MSTATUS, u64::from(mode) << 11);
+            cpu.csr.store(MEPC, DRAM_BASE + 0x100);
+            // mret
+            assert_eq!(cpu.execute(0x30200073).unwrap(), DRAM_BASE + 0x100);
+            assert_eq!(cpu.mode, mode);
+            assert_eq!(cpu.csr.load(MSTATUS) & MASK_MPP, 0);
+        }
+    }
+
+    #[test]
+    fn test_csrrw_swap() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.csr.store(MSCRATCH, 1);
+        cpu.regs[10] = 2;
+        // csrrw a0, mscratch, a0
+        cpu.execute(0x34051573).unwrap();
+        assert_eq!((cpu.regs[10], cpu.csr.load(MSCRATCH)), (1, 2));
+    }
+
+    #[test]
+    fn test_misa() {
+        let code = "
+            csrr a0, misa
+            csrw misa, zero
+            csrr a1, misa
+        ";
+        let mut cpu = compile_and_run(code, 3);
+        let misa = cpu.reg("a0").unwrap();
+        assert_eq!(misa >> 62, 2);
+        for ext in ['I', 'M', 'A', 'C'] {
+            assert_ne!(misa & (1 << (ext as u8 - b'A')), 0, "{}", ext);
+        }
+        assert_eq!(misa & (EXT_F | EXT_D), 0);
+        // The write is ignored.
+        assert_eq!(cpu.reg("a1").unwrap(), misa);
+        cpu.write(Reg::Csr(MISA), 0);
+        assert_eq!(cpu.reg("misa").unwrap(), misa);
+    }
+
+    #[test]
+    fn test_hints() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        // pause, addi zero, zero, 5, slli zero, a0, 3, and c.li zero, 1
+        for inst in [0x0100000f, 0x00500013, 0x00351013, 0x4005] {
+            cpu.regs[10] = 7;
+            let len = if inst & 0b11 == 0b11 { 4 } else { 2 };
+            assert_eq!(cpu.execute(inst).unwrap(), cpu.pc + len, "{:#x}", inst);
+            assert_eq!(cpu.read(Reg::X(0)), 0, "{:#x}", inst);
+            assert_eq!(cpu.regs[10], 7);
+        }
+    }
+
+    #[test]
+    fn test_disable_extension() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.extensions &= !EXT_M;
+        (cpu.regs[11], cpu.regs[12]) = (6, 7);
+        // mul a0, a1, a2 and divw a0, a1, a2
+        for inst in [0x02c58533, 0x02c5c53b] {
+            assert!(matches!(cpu.execute(inst), Err(Exception::IllegalInstruction(i)) if i == inst));
+        }
+        // add a0, a1, a2
+        cpu.execute(0x00c58533).unwrap();
+        assert_eq!(cpu.regs[10], 13);
+        assert_eq!(cpu.reg("misa").unwrap() & EXT_M, 0);
+        // csrr a0, misa
+        cpu.execute(0x30102573).unwrap();
+        assert_eq!(cpu.regs[10], MISA_MXL_64 | EXT_I | EXT_A | EXT_C);
+
+        // amoadd.w a0, a2, (a3)
+        cpu.extensions &= !EXT_A;
+        cpu.regs[13] = DRAM_BASE;
+        assert!(matches!(cpu.execute(0x00c6a52f), Err(Exception::IllegalInstruction(0x00c6a52f))));
+        cpu.extensions = MISA_EXTENSIONS;
+        cpu.execute(0x02c58533).unwrap();
+        assert_eq!(cpu.regs[10], 42);
+    }
+
+    #[test]
+    fn test_delegation_mask() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        // csrw medeleg, t0; csrw mideleg, t0
+        cpu.regs[5] = !0;
+        cpu.execute(0x30229073).unwrap();
+        cpu.execute(0x30329073).unwrap();
+        assert_eq!(cpu.reg("medeleg").unwrap(), MASK_MEDELEG);
+        assert_eq!(cpu.reg("medeleg").unwrap() & (1 << 11), 0);
+        assert_eq!(cpu.reg("mideleg").unwrap(), MASK_SSIP | MASK_STIP | MASK_SEIP);
+
+        // Even from S-mode, an M-mode ecall cannot be delegated.
+        cpu.mode = Supervisor;
+        cpu.handle_exception(Exception::EnvironmentCallFromMMode(DRAM_BASE));
+        assert_eq!(cpu.mode, Machine);
+        assert_eq!(cpu.reg("mcause").unwrap(), 11);
+        assert_eq!(cpu.reg("scause").unwrap(), 0);
+
+        cpu.mode = Supervisor;
+        cpu.handle_exception(Exception::EnvironmentCallFromSMode(DRAM_BASE));
+        assert_eq!(cpu.mode, Supervisor);
+        assert_eq!(cpu.reg("scause").unwrap(), 9);
+    }
+
+    #[test]
+    fn test_ecall_epc() {
+        // The handler returns past the ecall, as a syscall handler does.
+        let code = "
+            jal  t0, setup
+        handler:
+            csrr t1, mepc
+            addi t1, t1, 4
+            csrw mepc, t1
+            mret
+        setup:
+            csrw mtvec, t0
+            ecall
+            addi a0, zero, 1
+        ";
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        // The ecall is at DRAM_BASE + 24.
+        assert_eq!(cpu.pc, DRAM_BASE + 4);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 24);
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 28);
+        assert_eq!(cpu.reg("a0").unwrap(), 1);
+        assert_eq!(cpu.pc, DRAM_BASE + 32);
+
+        // ebreak sets the epc to its own address too.
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.pc = DRAM_BASE + 8;
+        let e = cpu.execute(0x00100073).unwrap_err();
+        cpu.handle_exception(e);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 8);
+    }
+
+    #[test]
+    fn test_illegal_instruction_tval() {
+        // an unknown opcode, and a known opcode with an unknown funct7.
+        for inst in [0xdeadbe7f, 0xfe000033] {
+            let mut cpu = Cpu::new(vec![], vec![]);
+            let e = cpu.execute(inst).unwrap_err();
+            assert!(matches!(e, Exception::IllegalInstruction(i) if i == inst));
+            assert_eq!(e.value(), inst);
+            cpu.handle_exception(e);
+            assert_eq!(cpu.reg("mtval").unwrap(), inst);
+            assert_eq!(cpu.reg("mcause").unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_devices() {
+        // The registers of the devices are saved with the dram.
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.bus.store(CLINT_MTIMECMP, 64, 20).unwrap();
+        let snapshot = cpu.snapshot();
+        let mut restored = Cpu::new(vec![], vec![]);
+        restored.restore(&snapshot);
+        assert_eq!(restored.bus.load(CLINT_MTIMECMP, 64).unwrap(), 20);
+    }
+
+    #[test]
+    fn test_snapshot_harts() {
+        // The harts that are not running are saved too, and so is their wfi.
+        let mut cpu = Cpu::with_harts(vec![], vec![], 2);
+        cpu.switch_hart(1);
+        cpu.regs[10] = 7;
+        cpu.waiting_for_interrupt = true;
+        cpu.switch_hart(0);
+        let snapshot = cpu.snapshot();
+        let mut restored = Cpu::with_harts(vec![], vec![], 2);
+        restored.restore(&snapshot);
+        restored.switch_hart(1);
+        assert_eq!(restored.regs[10], 7);
+        assert!(restored.waiting_for_interrupt);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let code = "
+            addi t0, zero, 0
+            addi t1, zero, 1
+        loop:
+            add  t0, t0, t1
+            addi t1, t1, 1
+            sd   t0, -16(sp)
+            j    loop
+        ";
+        fn step(cpu: &mut Cpu, n: usize) {
+            for _i in 0..n {
+                let inst = cpu.fetch().unwrap();
+                cpu.pc = cpu.execute(inst).unwrap();
+            }
+        }
+        let mut cpu = compile_and_run(code, 10);
+        let snapshot = cpu.snapshot();
+        step(&mut cpu, 25);
+        let (regs, pc, mem) = (cpu.regs, cpu.pc, cpu.load(DRAM_END - 16, 64).unwrap());
+
+        cpu.restore(&snapshot);
+        step(&mut cpu, 25);
+        assert_eq!(cpu.regs, regs);
+        assert_eq!(cpu.pc, pc);
+        assert_eq!(cpu.load(DRAM_END - 16, 64).unwrap(), mem);
+    }
+
+    #[test]
+    fn test_snapshot_pending_timer() {
+        // The timer fires at mtime 20, but mstatus.MIE holds it pending until the loop is done.
+        let code = "
+            li t0, 0x2004000
+            li t1, 20
+            sd t1, 0(t0)
+            li t1, 0x80
+            csrw mie, t1
+            li t2, 50
+            1:
+            addi t2, t2, -1
+            bnez t2, 1b
+            csrsi mstatus, 8
+            2:
+            j 2b
+        ";
+        let handler = DRAM_BASE + 0x100;
+        let deliver = |cpu: &mut Cpu| {
+            let steps = (0..200).position(|_| {
+                cpu.step().unwrap();
+                cpu.pc == handler
+            });
+            (steps, cpu.csr.load(MCAUSE), cpu.csr.load(MEPC))
+        };
+        let binary = compile(code);
+        let mut cpu = CpuBuilder::new(binary.clone()).csr(MTVEC, handler).build();
+        for _ in 0..40 {
+            cpu.step().unwrap();
+        }
+        assert!(cpu.bus.clint().mtip(0));
+        let snapshot = cpu.snapshot();
+        let expected = deliver(&mut cpu);
+        assert!(expected.0.is_some());
+        assert_eq!(expected.1, (1 << 63) | 7);
+
+        // The timer of the restored machine would never fire by itself.
+        let mut restored = Cpu::new(binary, vec![]);
+        restored.bus.store(CLINT_MTIMECMP, 64, !0).unwrap();
+        restored.restore(&snapshot);
+        assert_eq!(deliver(&mut restored), expected);
+    }
+
+    // Map the virtual page `va` (< 2 MiB) to the physical page `pa` with a three-level page table.
+    const PT_ROOT: u64 = DRAM_BASE + 0x10_0000;
+    fn map_page(cpu: &mut Cpu, va: u64, pa: u64, flags: u64) {
+        let l1 = PT_ROOT + PAGE_SIZE;
+        let l0 = PT_ROOT + 2 * PAGE_SIZE;
+        cpu.bus.store(PT_ROOT, 64, ((l1 >> 12) << 10) | 1).unwrap();
+        cpu.bus.store(l1, 64, ((l0 >> 12) << 10) | 1).unwrap();
+        cpu.bus.store(l0 + ((va >> 12) & 0x1ff) * 8, 64, ((pa >> 12) << 10) | flags).unwrap();
+    }
+
+    fn enable_sv39(cpu: &mut Cpu) {
+        cpu.csr.store(SATP, (8 << 60) | (PT_ROOT >> 12));
+        cpu.on_csr_write(SATP);
+    }
+
+    #[test]
+    fn test_sfence_vma() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        let (va, pa0, pa1) = (0x1000, DRAM_BASE + 0x20_0000, DRAM_BASE + 0x30_0000);
+        map_page(&mut cpu, va, pa0, 0b111);
+        enable_sv39(&mut cpu);
+        cpu.mode = Supervisor;
+        assert_eq!(cpu.translate(va + 8, AccessType::Load).unwrap(), pa0 + 8);
+
+        // the stale translation stays cached until a fence.
+        map_page(&mut cpu, va, pa1, 0b111);
+        assert_eq!(cpu.translate(va + 8, AccessType::Load).unwrap(), pa0 + 8);
+        // sfence.vma a0, zero
+        cpu.regs[10] = va;
+        cpu.execute(0x12050073).unwrap();
+        assert_eq!(cpu.translate(va + 8, AccessType::Load).unwrap(), pa1 + 8);
+
+        map_page(&mut cpu, va, pa0, 0b111);
+        // sfence.vma zero, zero
+        cpu.execute(0x12000073).unwrap();
+        assert_eq!(cpu.translate(va + 8, AccessType::Load).unwrap(), pa0 + 8);
+    }
+
+    #[test]
+    fn test_misaligned_superpage() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        let l1 = PT_ROOT + PAGE_SIZE;
+        cpu.bus.store(PT_ROOT, 64, ((l1 >> 12) << 10) | 1).unwrap();
+        // A 2 MiB megapage at va 0x20_0000. Its ppn[0] must be 0.
+        let pa = DRAM_BASE + 0x40_0000;
+        cpu.bus.store(l1 + 8, 64, ((pa >> 12) << 10) | 0b111).unwrap();
+        enable_sv39(&mut cpu);
+        cpu.mode = Supervisor;
+        assert_eq!(cpu.translate(0x20_1234, AccessType::Load).unwrap(), pa + 0x1234);
+
+        cpu.bus.store(l1 + 8, 64, (((pa >> 12) | 1) << 10) | 0b111).unwrap();
+        cpu.tlb.clear();
+        assert!(matches!(cpu.load(0x20_1234, 64), Err(Exception::LoadPageFault(0x20_1234))));
+    }
+
+    #[test]
+    fn test_page_straddling_access() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        let (pa0, pa1) = (DRAM_BASE + 0x20_0000, DRAM_BASE + 0x30_0000);
+        map_page(&mut cpu, 0x1000, pa0, 0b111);
+        map_page(&mut cpu, 0x2000, pa1, 0b111);
+        enable_sv39(&mut cpu);
+        cpu.mode = Supervisor;
+
+        // The dword at 0x1ffc has its low half at the end of pa0 and its high half at pa1.
+        cpu.bus.store(pa0 + 0xffc, 32, 0x44332211).unwrap();
+        cpu.bus.store(pa1, 32, 0x88776655).unwrap();
+        assert_eq!(cpu.load(0x1ffc, 64).unwrap(), 0x8877665544332211);
+        assert_eq!(cpu.load(0x1ffe, 32).unwrap(), 0x66554433);
+
+        cpu.store(0x1ffa, 64, 0x0102030405060708).unwrap();
+        assert_eq!(cpu.bus.load(pa0 + 0xffa, 32).unwrap(), 0x05060708);
+        assert_eq!(cpu.bus.load(pa0 + 0xffe, 16).unwrap(), 0x0304);
+        assert_eq!(cpu.bus.load(pa1, 16).unwrap(), 0x0102);
+
+        // The second page is unmapped, so nothing is stored to the first one.
+        assert!(matches!(cpu.load(0x2ffc, 64), Err(Exception::LoadPageFault(0x3000))));
+        assert!(matches!(cpu.store(0x2ffc, 64, u64::MAX), Err(Exception::StoreAMOPageFault(0x3000))));
+        assert_eq!(cpu.bus.load(pa1 + 0xffc, 32).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_fetch_faults() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        // 0x1000 is executable but its physical page does not exist, 0x2000 is not mapped.
+        map_page(&mut cpu, 0x1000, 0x4000_0000, 0b1011);
+        enable_sv39(&mut cpu);
+        cpu.mode = Supervisor;
+        cpu.pc = 0x2000;
+        assert!(matches!(cpu.fetch(), Err(Exception::InstructionPageFault(0x2000))));
+        cpu.pc = 0x1000;
+        assert!(matches!(cpu.fetch(), Err(Exception::InstructionAccessFault(0x1000))));
+
+        // A fetch from the claim register of context 1 does not claim its interrupt.
+        cpu.mode = Machine;
+        cpu.extensions &= !EXT_C;
+        cpu.bus.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 1).unwrap();
+        cpu.bus.store(PLIC_ENABLE + PLIC_ENABLE_STRIDE, 32, 1 << UART_IRQ).unwrap();
+        cpu.bus.plic().raise(UART_IRQ);
+        cpu.pc = PLIC_CLAIM + PLIC_CONTEXT_STRIDE;
+        assert!(matches!(cpu.fetch(), Err(Exception::InstructionAccessFault(a)) if a == cpu.pc));
+        assert!(cpu.bus.plic().is_interrupting(1));
+    }
+
+    #[test]
+    fn test_phys_access() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        let pattern: Vec<u8> = (0..=255).collect();
+        cpu.write_phys(DRAM_BASE + 0x1000, &pattern).unwrap();
+        let mut buf = vec![0; 256];
+        cpu.read_phys(DRAM_BASE + 0x1000, &mut buf).unwrap();
+        assert_eq!(buf, pattern);
+        assert_eq!(cpu.bus.load(DRAM_BASE + 0x1004, 32).unwrap(), 0x07060504);
+
+        // The whole range must be in the dram.
+        assert!(cpu.read_phys(DRAM_END - 3, &mut buf[..4]).is_ok());
+        assert!(matches!(cpu.read_phys(DRAM_END - 2, &mut buf[..4]), Err(Exception::LoadAccessFault(_))));
+        assert!(matches!(cpu.write_phys(UART_BASE, &[0]), Err(Exception::StoreAMOAccessFault(_))));
+    }
+
+    #[test]
+    fn test_load_binary_at() {
+        // The first program jumps to the second one, which adds to its result and halts.
+        let first = compile("
+            li a0, 1
+            li t0, 0x80001000
+            jr t0
+        ");
+        let second = compile("
+            addi a0, a0, 2
+            li t0, 0x100000
+            li t1, 0x5555
+            sw t1, 0(t0)
+        ");
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.load_binary_at(&first, DRAM_BASE + 0x2000);
+        cpu.load_binary_at(&second, DRAM_BASE + 0x1000);
+        cpu.set_pc(DRAM_BASE + 0x2000);
+        assert!(matches!(cpu.run(Some(100), None).reason, StopReason::Halted(0)));
+        assert_eq!(cpu.reg("a0"), Ok(3));
+    }
+
+    #[test]
+    fn test_sparse_memory() {
+        // Fill the top of the dram with a sequence, then sum it back with loads of every size.
+        let code = compile("
+            li sp, 0x88000000
+            li t0, 64
+            1:
+            addi sp, sp, -8
+            mul t1, t0, t0
+            sd t1, 0(sp)
+            addi t0, t0, -1
+            bnez t0, 1b
+            li t0, 64
+            2:
+            ld t1, 0(sp)
+            lw t2, 4(sp)
+            lhu t3, 2(sp)
+            lb t4, 1(sp)
+            add a0, a0, t1
+            add a0, a0, t2
+            add a1, a1, t3
+            add a1, a1, t4
+            sb t4, 0(sp)
+            addi sp, sp, 8
+            addi t0, t0, -1
+            bnez t0, 2b
+        ");
+        let mut dense = CpuBuilder::new(code.clone()).build();
+        let mut sparse = CpuBuilder::new(code).memory(Box::new(crate::memory::SparseMemory::new())).build();
+        let dense_result = dense.run(Some(2000), None);
+        let sparse_result = sparse.run(Some(2000), None);
+        assert_eq!(dense_result.instret, sparse_result.instret);
+        assert_eq!(dense.regs, sparse.regs);
+        assert_eq!(dense.reg("a0"), Ok((1..=64).map(|i| i * i).sum()));
+        let (mut dense_bytes, mut sparse_bytes) = ([0; 512], [0; 512]);
+        dense.read_phys(DRAM_END + 1 - 512, &mut dense_bytes).unwrap();
+        sparse.read_phys(DRAM_END + 1 - 512, &mut sparse_bytes).unwrap();
+        assert_eq!(dense_bytes, sparse_bytes);
+    }
+
+    #[test]
+    fn test_load_to_x0() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        // lw zero, 0(a0) still accesses the memory, so a bad address faults.
+        cpu.regs[10] = UART_END + 1;
+        assert!(matches!(cpu.execute(0x00052003), Err(Exception::LoadAccessFault(a)) if a == UART_END + 1));
+        cpu.regs[10] = DRAM_BASE;
+        cpu.write_phys(DRAM_BASE, &[0xff; 4]).unwrap();
+        assert!(cpu.execute(0x00052003).is_ok());
+        // x0 is cleared before every instruction, so mv a1, zero never observes the loaded value.
+        cpu.execute(0x00000593).unwrap();
+        assert_eq!(cpu.regs[11], 0);
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.write_phys(DRAM_BASE + 0x100, b"Hello, rvemu!\n\x00\xffAB").unwrap();
+        let dump = cpu.hexdump(DRAM_BASE + 0x100, 18);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "0x0000000080000100: 48 65 6c 6c 6f 2c 20 72 76 65 6d 75 21 0a 00 ff  Hello, rvemu!...");
+        assert_eq!(lines[1], format!("0x0000000080000110: {:<47}  AB", "41 42"));
+
+        let dump = cpu.hexdump(DRAM_END - 0xf, 32);
+        assert!(dump.ends_with(&format!("{:#018x}: out of the dram\n", DRAM_END + 1)));
+    }
+
+    #[test]
+    fn test_virt_access() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        let (pa0, pa1) = (DRAM_BASE + 0x20_0000, DRAM_BASE + 0x30_0000);
+        map_page(&mut cpu, 0x1000, pa0, 0b111);
+        map_page(&mut cpu, 0x2000, pa1, 0b111);
+        enable_sv39(&mut cpu);
+        cpu.mode = Supervisor;
+
+        // 8 bytes at the end of the first page and 8 at the start of the second.
+        let pattern: Vec<u8> = (1..=16).collect();
+        cpu.write_virt(0x1ff8, &pattern).unwrap();
+        let mut buf = vec![0; 8];
+        cpu.read_phys(pa0 + 0xff8, &mut buf).unwrap();
+        assert_eq!(buf, &pattern[..8]);
+        cpu.read_phys(pa1, &mut buf).unwrap();
+        assert_eq!(buf, &pattern[8..]);
+        let mut buf = vec![0; 16];
+        cpu.read_virt(0x1ff8, &mut buf).unwrap();
+        assert_eq!(buf, pattern);
+    }
+
+    #[test]
+    fn test_elf() {
+        let code = "
+            .globl _start
+        _start:
+            addi a0, zero, 40
+            addi a0, a0, 2
+        ";
+        let elf = compile_elf(code);
+        assert!(is_elf(&elf));
+
+        let mut cpu = Cpu::from_elf(&elf, vec![]).unwrap();
+        assert_eq!(cpu.pc, DRAM_BASE);
+        for _i in 0..2 {
+            let inst = cpu.fetch().unwrap();
+            cpu.pc = cpu.execute(inst).unwrap();
+        }
+        assert_eq!(cpu.reg("a0").unwrap(), 42);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let code = "
+            li    t0, 0x02a00513 # addi a0, zero, 42
+            auipc t1, 0
+            addi  t1, t1, 16
+            sw    t0, 0(t1)
+            jalr  zero, 0(t1)
+            .word 0xffffffff
+        ";
+        riscv_test!(code, 7, "a0" => 42, "pc" => DRAM_BASE + 28);
+    }
+
+    #[test]
+    fn test_fence_i() {
+        let code = "
+            li      t0, 0x02a00513 # addi a0, zero, 42
+            auipc   t1, 0
+            addi    t1, t1, 20
+            sw      t0, 0(t1)
+            fence.i
+            jalr    zero, 0(t1)
+            .word   0xffffffff
+        ";
+        riscv_test!(code, 8, "a0" => 42, "pc" => DRAM_BASE + 32);
+    }
+
+    #[test]
+    fn test_csr_access_legality() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        // csrrw t0, cycle, t1
+        assert!(matches!(cpu.execute(0xc00312f3), Err(Exception::IllegalInstruction(0xc00312f3))));
+        // csrrs t0, cycle, zero
+        assert!(cpu.execute(0xc00022f3).is_ok());
+
+        cpu.mode = User;
+        // csrrw t0, mhartid, t1
+        assert!(matches!(cpu.execute(0xf14312f3), Err(Exception::IllegalInstruction(0xf14312f3))));
+        // csrrw t0, sstatus, t1
+        assert!(matches!(cpu.execute(0x100312f3), Err(Exception::IllegalInstruction(0x100312f3))));
+
+        cpu.mode = Supervisor;
+        assert!(cpu.execute(0x100312f3).is_ok());
+        // csrrw t0, mstatus, t1
+        assert!(matches!(cpu.execute(0x300312f3), Err(Exception::IllegalInstruction(0x300312f3))));
+    }
+
+    #[test]
+    fn test_tvm() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.csr.store(MSTATUS, MASK_TVM);
+        // csrrw t0, satp, zero
+        assert!(cpu.execute(0x180012f3).is_ok());
+        cpu.mode = Supervisor;
+        assert!(matches!(cpu.execute(0x180012f3), Err(Exception::IllegalInstruction(0x180012f3))));
+        // sfence.vma zero, zero
+        assert!(matches!(cpu.execute(0x12000073), Err(Exception::IllegalInstruction(0x12000073))));
+
+        cpu.csr.store(MSTATUS, 0);
+        assert!(cpu.execute(0x180012f3).is_ok());
+        assert!(cpu.execute(0x12000073).is_ok());
+    }
+
+    #[test]
+    fn test_tw() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.mode = Supervisor;
+        // wfi
+        assert_eq!(cpu.execute(0x10500073).unwrap(), DRAM_BASE + 4);
+        cpu.csr.store(MSTATUS, MASK_TW);
+        assert!(matches!(cpu.execute(0x10500073), Err(Exception::IllegalInstruction(0x10500073))));
+        cpu.mode = Machine;
+        assert!(cpu.execute(0x10500073).is_ok());
+    }
+
+    #[test]
+    fn test_wfi_idle() {
+        // The timer interrupt is enabled in mie but not in mstatus, so the hart resumes after the
+        // wfi without a trap once it is pending.
+        let code = "
+            li t0, 0x80
+            csrw mie, t0
+            li t0, 0x2004000
+            li t1, 100000
+            sd t1, 0(t0)
+            wfi
+            li a0, 42
+            li t0, 0x100000
+            li t1, 0x5555
+            sw t1, 0(t0)
+        ";
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        assert!(matches!(cpu.run(None, Some(Duration::from_secs(10))).reason, StopReason::Halted(0)));
+        assert_eq!(cpu.reg("a0"), Ok(42));
+
+        // A hart waiting for an interrupt that never comes executes next to nothing.
+        let window = Some(Duration::from_millis(50));
+        let idle = CpuBuilder::new(compile("1: wfi\n j 1b")).csr(MIE, MASK_MSIP).build().run(None, window);
+        let busy = Cpu::new(compile("1: j 1b"), vec![]).run(None, window);
+        assert!(matches!(idle.reason, StopReason::Timeout));
+        assert!(idle.instret * 100 < busy.instret, "{} vs {}", idle.instret, busy.instret);
+
+        // With no interrupt enabled in mie nothing can wake it up, so the run stops even without
+        // a timeout.
+        let result = Cpu::new(compile("1: wfi\n j 1b"), vec![]).run(Some(100), None);
+        assert!(matches!(result.reason, StopReason::Idle));
+        assert_eq!((result.instret, result.pc), (1, DRAM_BASE + 4));
+    }
+
+    #[test]
+    fn test_unimplemented_csr() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        // csrrs t0, 0x7c5, zero and csrrw zero, 0x5a0, t1 name no csr, even in M-mode.
+        for inst in [0x7c5022f3, 0x5a031073] {
+            assert!(matches!(cpu.execute(inst), Err(Exception::IllegalInstruction(i)) if i == inst));
+        }
+        // csrrs t0, pmpcfg0, zero and csrrs t0, mvendorid, zero are implemented.
+        for inst in [0x3a0022f3, 0xf11022f3] {
+            assert!(cpu.execute(inst).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_sum() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        let (va, pa) = (0x1000, DRAM_BASE + 0x20_0000);
+        // A U-mode page that is readable, writable and executable.
+        map_page(&mut cpu, va, pa, 0b11111);
+        enable_sv39(&mut cpu);
+        cpu.mode = Supervisor;
+        assert!(matches!(cpu.load(va, 64), Err(Exception::LoadPageFault(0x1000))));
+
+        // csrrs zero, sstatus, t0 with t0 = SUM, then the translation cached by the first access
+        // is allowed right away.
+        cpu.regs[5] = MASK_SUM;
+        cpu.execute(0x1002a073).unwrap();
+        cpu.store(va, 64, 42).unwrap();
+        assert_eq!(cpu.load(va, 64).unwrap(), 42);
+        // S-mode never executes a U-mode page.
+        assert!(matches!(cpu.translate(va, AccessType::Instruction), Err(Exception::InstructionPageFault(0x1000))));
+        // csrrc zero, sstatus, t0
+        cpu.execute(0x1002b073).unwrap();
+        assert!(matches!(cpu.store(va, 64, 0), Err(Exception::StoreAMOPageFault(0x1000))));
+
+        // An execute-only page is readable only with MXR=1.
+        map_page(&mut cpu, 0x2000, pa, 0b1001);
+        assert!(cpu.load(0x2000, 64).is_err());
+        cpu.csr.store(MSTATUS, MASK_MXR);
+        assert_eq!(cpu.load(0x2000, 64).unwrap(), 42);
+        assert!(cpu.store(0x2000, 64, 0).is_err());
+        cpu.mode = User;
+        assert!(cpu.load(0x2000, 64).is_err());
+        assert_eq!(cpu.load(va, 64).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_mprv() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        let (va, pa) = (0x1000, DRAM_BASE + 0x20_0000);
+        map_page(&mut cpu, va, pa, 0b111);
+        enable_sv39(&mut cpu);
+        cpu.bus.store(pa, 64, 0xdead_beef).unwrap();
+
+        // without MPRV, M-mode loads use physical addresses.
+        assert_eq!(cpu.load(pa, 64).unwrap(), 0xdead_beef);
+        assert!(cpu.load(va, 64).is_err());
+
+        // MPRV=1 and MPP=S, loads go through the S-mode page table but fetches do not.
+        cpu.csr.store(MSTATUS, MASK_MPRV | (u64::from(Supervisor) << 11));
+        assert_eq!(cpu.load(va, 64).unwrap(), 0xdead_beef);
+        cpu.store(va + 8, 64, 42).unwrap();
+        assert_eq!(cpu.bus.load(pa + 8, 64).unwrap(), 42);
+        assert_eq!(cpu.translate(pa, AccessType::Instruction).unwrap(), pa);
+
+        // MPP=M behaves as if MPRV were clear.
+        cpu.csr.store(MSTATUS, MASK_MPRV | (u64::from(Machine) << 11));
+        assert_eq!(cpu.load(pa, 64).unwrap(), 0xdead_beef);
+    }
+
+    #[test]
+    fn test_run_budget() {
+        let code = "
+        loop:
+            addi a0, a0, 1
+            j    loop
+        ";
+        let mut cpu = compile_and_run(code, 0);
+        assert!(matches!(cpu.run(Some(1000), None).reason, StopReason::InstLimit));
+        assert_eq!(cpu.reg("a0").unwrap(), 500);
+        assert!(matches!(cpu.run(None, Some(Duration::from_millis(10))).reason, StopReason::Timeout));
+        assert!(cpu.reg("a0").unwrap() > 500);
+    }
+
+    #[test]
+    fn test_stats() {
+        let code = "
+            addi a0, zero, 10
+        loop:
+            addi a0, a0, -1
+            bnez a0, loop
+            mul  a1, a0, a0
+            sd   a1, -8(sp)
+        ";
+        let cpu = compile_and_run(code, 23);
+        let stats = cpu.stats();
+        assert_eq!(stats.branches_taken, 9);
+        assert_eq!(stats.branches_not_taken, 1);
+        assert_eq!(stats.alu, 11);
+        assert_eq!(stats.mul_div, 1);
+        assert_eq!(stats.stores, 1);
+        assert_eq!(stats.total(), 23);
+    }
+
+    #[test]
+    fn test_reg_accessors() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.write(Reg::X(10), 42);
+        cpu.write(Reg::X(0), 42);
+        cpu.write(Reg::Pc, DRAM_BASE + 8);
+        cpu.write(Reg::Csr(MEPC), 0x1234);
+        assert_eq!(cpu.read(Reg::X(10)), 42);
+        assert_eq!(cpu.read(Reg::X(0)), 0);
+        assert_eq!(cpu.reg("a0").unwrap(), 42);
+        assert_eq!(cpu.reg("x10").unwrap(), 42);
+        assert_eq!(cpu.reg("pc").unwrap(), DRAM_BASE + 8);
+        assert_eq!(cpu.reg("mepc").unwrap(), 0x1234);
+        assert_eq!("fp".parse::<Reg>(), Ok(Reg::X(8)));
+        assert_eq!("satp".parse::<Reg>(), Ok(Reg::Csr(SATP)));
+
+        // a write to satp switches paging on.
+        cpu.write(Reg::Csr(SATP), 8 << 60);
+        assert!(cpu.enable_paging);
+    }
+
+    #[test]
+    fn test_reg_parse_error() {
+        let cpu = Cpu::new(vec![], vec![]);
+        assert!(cpu.reg("a8").is_err());
+        assert!(cpu.reg("x32").is_err());
+        assert!(cpu.reg("x").is_err());
+        assert!("mstatuss".parse::<Reg>().is_err());
+    }
+
+    #[test]
+    fn test_big_endian() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        let addr = DRAM_BASE + 0x1000;
+        cpu.csr.store(MSTATUS, cpu.csr.load(MSTATUS) | MASK_MBE);
+        cpu.store(addr, 32, 0x11223344).unwrap();
+        assert_eq!(cpu.load(addr, 32).unwrap(), 0x11223344);
+        let bytes: Vec<u64> = (0..4).map(|i| cpu.load(addr + i, 8).unwrap()).collect();
+        assert_eq!(bytes, vec![0x11, 0x22, 0x33, 0x44]);
+
+        // SBE does not apply to M-mode.
+        cpu.csr.store(MSTATUS, (cpu.csr.load(MSTATUS) & !MASK_MBE) | MASK_SBE);
+        assert_eq!(cpu.load(addr, 32).unwrap(), 0x44332211);
+        cpu.store(addr, 16, 0xaabb).unwrap();
+        assert_eq!(cpu.load(addr, 8).unwrap(), 0xbb);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let code = "
+            addi sp, sp, -16
+            addi a0, zero, 42
+            sd   a0, 8(sp)
+            ld   a1, 8(sp)
+            addi a2, zero, 1
+        ";
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        let slot = cpu.regs[2] - 8;
+        cpu.write_phys(slot, &7u64.to_le_bytes()).unwrap();
+        // Watch the last byte of the slot, which the sd covers.
+        cpu.watchpoints.push((slot + 7, WatchKind::Write));
+        match cpu.run(Some(10), None).reason {
+            StopReason::Watchpoint(hit) => {
+                assert_eq!(hit, WatchHit { addr: slot + 7, pc: DRAM_BASE + 8, kind: WatchKind::Write, value: 42, old: Some(7) });
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(cpu.pc, DRAM_BASE + 12);
+
+        // The ld does not stop at a write watchpoint.
+        assert!(matches!(cpu.run(Some(2), None).reason, StopReason::InstLimit));
+        assert_eq!(cpu.reg("a1"), Ok(42));
+        cpu.watchpoints = vec![(slot, WatchKind::Access)];
+        cpu.pc = DRAM_BASE + 12;
+        assert!(matches!(cpu.run(Some(2), None).reason, StopReason::Watchpoint(WatchHit { kind: WatchKind::Read, value: 42, .. })));
+    }
+
+    #[test]
+    fn test_on_breakpoint() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        let code = "
+            addi a0, zero, 1
+            ebreak
+            addi a0, a0, 1
+        ";
+        let cpu = compile_and_run(code, 2);
+        assert_eq!(cpu.reg("a0").unwrap(), 1);
+        assert_eq!(cpu.pc, DRAM_BASE + 4);
+
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = compile_and_run(code, 0);
+        let recorder = hits.clone();
+        cpu.on_breakpoint = Some(Box::new(move |cpu: &mut Cpu| recorder.borrow_mut().push(cpu.pc)));
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(*hits.borrow(), vec![DRAM_BASE + 4]);
+        assert_eq!(cpu.reg("a0").unwrap(), 2);
+        assert!(cpu.on_breakpoint.is_some());
+    }
+
+    #[test]
+    fn test_on_machine_ecall() {
+        // Command 1 in a0 adds a1 to a2, any other is not handled.
+        let code = "
+            addi a0, zero, 1
+            addi a1, zero, 5
+            ecall
+            addi a2, a2, 1
+            addi a0, zero, 2
+            ecall
+        ";
+        let mut cpu = compile_and_run(code, 0);
+        cpu.on_machine_ecall = Some(Box::new(|cpu: &mut Cpu| match cpu.regs[10] {
+            1 => {
+                cpu.regs[12] += cpu.regs[11];
+                true
+            }
+            _ => false,
+        }));
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.reg("a2"), Ok(6));
+        // The second ecall traps.
+        assert_eq!(cpu.csr.load(MCAUSE), 11);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 20);
+        assert!(cpu.on_machine_ecall.is_some());
+    }
+
+    #[test]
+    fn test_reg_mode() {
+        let code = "
+            ecall
+            addi a0, zero, 1
+            nop
+            nop
+            csrr t0, mepc
+            addi t0, t0, 4
+            csrw mepc, t0
+            mret
+        ";
+        let mut cpu = CpuBuilder::new(compile(code)).mode(User).csr(MTVEC, DRAM_BASE + 16).build();
+        assert_eq!(cpu.reg("mode"), Ok(0));
+        // The ecall from U-mode traps into M-mode.
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("mode"), Ok(3));
+        // mret returns to U-mode, since MPP holds the mode of the trap.
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.reg("mode"), Ok(0));
+        assert_eq!(cpu.pc, DRAM_BASE + 4);
+        cpu.write(Reg::Mode, 0b10);
+        assert_eq!(cpu.read(Reg::Mode), 0);
+        cpu.write(Reg::Mode, 1);
+        assert_eq!(cpu.mode, Supervisor);
+    }
+
+    #[test]
+    fn test_finisher_halts() {
+        let code = "
+            li   t0, 0x100000
+            li   t1, 0x5555
+            sw   t1, 0(t0)
+        loop:
+            j    loop
+        ";
+        let mut cpu = compile_and_run(code, 0);
+        assert!(matches!(cpu.run(Some(1000), None).reason, StopReason::Halted(0)));
+        assert_eq!(cpu.exit_code, Some(0));
+        assert_eq!(cpu.pc, DRAM_BASE + 16);
+    }
+
+    #[test]
+    fn test_run_result() {
+        // The ecall handler exits with the code in a0.
+        let code = "
+            jal  t0, setup
+        handler:
+            slli a0, a0, 16
+            li   a1, 0x3333
+            or   a0, a0, a1
+            li   t1, 0x100000
+            sw   a0, 0(t1)
+        setup:
+            csrw mtvec, t0
+            li   a0, 3
+            ecall
+        ";
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        let result = cpu.run(Some(1000), None);
+        assert!(matches!(result.reason, StopReason::Halted(3)), "{:?}", result);
+        // jal, csrw, li, ecall, then the 6 instructions of the handler.
+        assert_eq!(result.instret, 10);
+        assert_eq!(result.pc, DRAM_BASE + 28);
+
+        let mut cpu = Cpu::new(compile("loop: addi a0, a0, 1\n j loop"), vec![]);
+        let result = cpu.run(Some(7), None);
+        assert!(matches!(result.reason, StopReason::InstLimit));
+        assert_eq!((result.instret, result.pc), (7, DRAM_BASE + 4));
+        assert_eq!(cpu.reg("a0").unwrap(), 4);
+    }
+
+    #[test]
+    fn test_trace_file() {
+        use crate::trace::*;
+        let code = "
+            addi a0, zero, 1
+            addi a1, a0, 2
+            addi a2, zero, 0
+        ";
+        let path = std::env::temp_dir().join(format!("rvemu_trace_{}", std::process::id()));
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        cpu.trace_file = Some(BufWriter::new(File::create(&path).unwrap()));
+        assert!(matches!(cpu.run(Some(3), None).reason, StopReason::InstLimit));
+        cpu.trace_file.take().unwrap().flush().unwrap();
+
+        let trace = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<TraceRecord> =
+            trace.chunks_exact(RECORD_SIZE).map(|bytes| TraceRecord::from_bytes(bytes.try_into().unwrap())).collect();
+        assert_eq!(trace.len(), 3 * RECORD_SIZE);
+        assert_eq!(records.iter().map(|r| r.pc).collect::<Vec<_>>(), [DRAM_BASE, DRAM_BASE + 4, DRAM_BASE + 8]);
+        assert_eq!(records[0], TraceRecord { pc: DRAM_BASE, inst: 0x00100513, hartid: 0, rd: 10, value: 1 });
+        assert_eq!((records[1].rd, records[1].value), (11, 3));
+        // a2 was already 0.
+        assert_eq!((records[2].rd, records[2].value), (0, 0));
+    }
+
+    #[test]
+    fn test_seed_rng() {
+        let code = "
+            li   t0, 0x101000
+            ld   a0, 0(t0)
+            ld   a1, 0(t0)
+            lw   a2, 0(t0)
+        ";
+        let values = |seed| {
+            let mut cpu = Cpu::new(compile(code), vec![]);
+            cpu.seed_rng(seed);
+            cpu.run(Some(5), None);
+            (cpu.reg("a0").unwrap(), cpu.reg("a1").unwrap(), cpu.reg("a2").unwrap())
+        };
+        let (a0, a1, a2) = values(42);
+        assert_eq!(values(42), (a0, a1, a2));
+        assert_ne!(a0, a1);
+        assert_eq!(a2 >> 32, if a2 & 0x8000_0000 != 0 { 0xffff_ffff } else { 0 });
+        assert_ne!(values(43).0, a0);
+    }
+
+    #[test]
+    fn test_software_interrupt_between_harts() {
+        let code = "
+            csrr  t0, mhartid
+            bnez  t0, secondary
+            li    t1, 0x2000004     # msip of hart 1
+            li    t2, 1
+            sw    t2, 0(t1)
+        park:
+            j     park
+        secondary:
+            auipc t3, 0
+            addi  t3, t3, -4        # park
+            csrw  mtvec, t3
+            li    t1, 8             # MSIE
+            csrw  mie, t1
+            csrsi mstatus, 8        # MIE
+            j     park
+        ";
+        let mut cpu = Cpu::with_harts(compile(code), vec![], 2);
+        assert!(matches!(cpu.run(Some(100), None).reason, StopReason::InstLimit));
+        cpu.switch_hart(0);
+        assert_eq!(cpu.csr.load(MCAUSE), 0);
+        cpu.switch_hart(1);
+        assert_eq!(cpu.reg("mhartid").unwrap(), 1);
+        assert_eq!(cpu.csr.load(MCAUSE), (1 << 63) | 3);
+        assert_eq!(cpu.pc, DRAM_BASE + 24);
+    }
+
+    #[test]
+    fn test_live_mip() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.csr.store(MIDELEG, MASK_SEIP);
+        cpu.bus.store(CLINT_MTIMECMP, 64, u64::MAX).unwrap();
+        // Route the uart to the S-mode context of hart 0.
+        cpu.bus.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 1).unwrap();
+        cpu.bus.store(PLIC_ENABLE + PLIC_ENABLE_STRIDE, 32, 1 << UART_IRQ).unwrap();
+        cpu.bus.uart().raise_interrupt();
+
+        // csrr a0, mip and csrr a1, sip see SEIP without a check for interrupts in between.
+        cpu.execute(0x34402573).unwrap();
+        cpu.execute(0x144025f3).unwrap();
+        assert_eq!(cpu.regs[10], MASK_SEIP);
+        assert_eq!(cpu.regs[11], MASK_SEIP);
+
+        // csrrsi zero, mip, 2 sets SSIP without latching the SEIP line of the PLIC.
+        cpu.execute(0x34416073).unwrap();
+        assert_eq!(cpu.csr.load(MIP), MASK_SSIP);
+        // Claiming the uart clears the line, and the SEIP bit that software sets is OR'd with it.
+        assert_eq!(cpu.bus.load(PLIC_CLAIM + PLIC_CONTEXT_STRIDE, 32).unwrap(), UART_IRQ);
+        cpu.execute(0x34402573).unwrap();
+        assert_eq!(cpu.regs[10], MASK_SSIP);
+        // csrrs zero, mip, t0
+        cpu.regs[5] = MASK_SEIP;
+        cpu.execute(0x3442a073).unwrap();
+        cpu.execute(0x34402573).unwrap();
+        assert_eq!(cpu.regs[10], MASK_SSIP | MASK_SEIP);
+        cpu.mode = Supervisor;
+        cpu.csr.store(SSTATUS, MASK_SIE);
+        cpu.csr.store(MIE, MASK_SEIP);
+        assert!(matches!(cpu.check_pending_interrupt(), Some(Interrupt::SupervisorExternalInterrupt)));
+
+        // A write of sip keeps the mip bits that are not delegated, e.g. the pending STIP.
+        cpu.csr.store(MIP, MASK_STIP);
+        cpu.csr.store(SIP, 0);
+        assert_eq!(cpu.csr.load(MIP), MASK_STIP);
+    }
+
+    #[test]
+    fn test_plic_claim_complete() {
+        let code = "
+            jal   t0, setup         # t0 = handler
+        handler:
+            li    t1, 0xc200004     # claim/complete of context 0
+            lw    t2, 0(t1)
+            add   s1, s1, t2
+            addi  s0, s0, 1
+            sw    t2, 0(t1)
+            mret
+        setup:
+            csrw  mtvec, t0
+            li    t1, 0xc000004     # priority of the virtio disk
+            li    t2, 1
+            sw    t2, 0(t1)
+            li    t1, 0xc002000     # enable of context 0
+            li    t2, 0x2
+            sw    t2, 0(t1)
+            li    t1, 1
+            slli  t1, t1, 11        # MEIE
+            csrw  mie, t1
+            csrsi mstatus, 8        # MIE
+        park:
+            j     park
+        ";
+        // The uart may be interrupted by the stdin of the test, so the disk irq is used instead.
+        let mut cpu = Cpu::new(compile(code), vec![]);
+        assert!(matches!(cpu.run(Some(50), None).reason, StopReason::InstLimit));
+        assert_eq!(cpu.reg("s0").unwrap(), 0);
+
+        cpu.bus.plic().raise(VIRTIO_IRQ);
+        assert!(matches!(cpu.run(Some(50), None).reason, StopReason::InstLimit));
+        assert_eq!(cpu.csr.load(MCAUSE), (1 << 63) | 11);
+        assert_eq!((cpu.reg("s0").unwrap(), cpu.reg("s1").unwrap()), (1, VIRTIO_IRQ));
+        // The claim cleared MEIP, so the handler ran once.
+        assert_eq!(cpu.csr.load(MIP) & MASK_MEIP, 0);
+
+        // It was completed, so the next one is delivered too.
+        cpu.bus.plic().raise(VIRTIO_IRQ);
+        assert!(matches!(cpu.run(Some(50), None).reason, StopReason::InstLimit));
+        assert_eq!((cpu.reg("s0").unwrap(), cpu.reg("s1").unwrap()), (2, 2 * VIRTIO_IRQ));
+    }
+
+    /// Queue a request to read `sector` into a buffer in the dram, and return the buffer address.
+    fn queue_disk_read(cpu: &mut Cpu, sector: u64) -> u64 {
+        // The queue starts at the second page of dram, followed by a request and a buffer.
+        let queue = DRAM_BASE + PAGE_SIZE;
+        let req = queue + 2 * PAGE_SIZE;
+        let buf = req + 0x100;
+        cpu.bus.store(VIRTIO_GUEST_PAGE_SIZE, 32, PAGE_SIZE).unwrap();
+        cpu.bus.store(VIRTIO_QUEUE_PFN, 32, queue / PAGE_SIZE).unwrap();
+
+        // desc[0] -> the request, desc[1] -> the buffer.
+        cpu.bus.store(queue, 64, req).unwrap();
+        cpu.bus.store(queue + 8, 32, 16).unwrap();
+        cpu.bus.store(queue + 14, 16, 1).unwrap();
+        cpu.bus.store(queue + 16, 64, buf).unwrap();
+        cpu.bus.store(queue + 24, 32, SECTOR_SIZE).unwrap();
+        // avail.idx = 0 and avail.ring[0] = 0.
+        let avail = queue + DESC_NUM as u64 * 16;
+        cpu.bus.store(avail + 2, 16, 0).unwrap();
+        cpu.bus.store(avail + 4, 16, 0).unwrap();
+        cpu.bus.store(req, 32, VIRTIO_BLK_T_IN as u64).unwrap();
+        cpu.bus.store(req + 8, 64, sector).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_disk_read() {
+        let mut disk = vec![0; 2 * SECTOR_SIZE as usize];
+        for (i, byte) in disk.iter_mut().enumerate() {
+            *byte = i as u8 ^ 0x5a;
+        }
+        let mut cpu = Cpu::new(vec![], disk.clone());
+        let buf = queue_disk_read(&mut cpu, 1);
+
+        cpu.disk_access().unwrap();
+        for i in 0..SECTOR_SIZE {
+            let expect = disk[(SECTOR_SIZE + i) as usize] as u64;
+            assert_eq!(cpu.bus.load(buf + i, 8).unwrap(), expect);
+        }
+        assert_eq!(cpu.bus.load(DRAM_BASE + 2 * PAGE_SIZE + 2, 16).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_disk_read_file() {
+        let disk: Vec<u8> = (0..3 * SECTOR_SIZE).map(|i| (i * 7) as u8).collect();
+        let path = std::env::temp_dir().join(format!("rvemu_disk_{}", std::process::id()));
+        std::fs::write(&path, &disk).unwrap();
+        let mut cpu = Cpu::new(vec![], vec![]);
+        *cpu.bus.virtio_blk() = crate::virtio::VirtioBlock::from_file(VIRTIO_BASE, std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cpu.bus.virtio_blk().disk_size(), 3 * SECTOR_SIZE);
+
+        let buf = queue_disk_read(&mut cpu, 2);
+        cpu.disk_access().unwrap();
+        let mut data = vec![0; SECTOR_SIZE as usize];
+        cpu.read_phys(buf, &mut data).unwrap();
+        assert_eq!(data, &disk[2 * SECTOR_SIZE as usize..]);
+
+        // The request must still fit in the image.
+        queue_disk_read(&mut cpu, 3);
+        assert_eq!(cpu.disk_access(), Err(DiskError::DiskOutOfRange { offset: 3 * SECTOR_SIZE, len: SECTOR_SIZE }));
+    }
+
+    #[test]
+    fn test_disk_bad_next() {
+        let mut cpu = Cpu::new(vec![], vec![0; SECTOR_SIZE as usize]);
+        let queue = DRAM_BASE + PAGE_SIZE;
+        let req = queue + 2 * PAGE_SIZE;
+        cpu.bus.store(VIRTIO_GUEST_PAGE_SIZE, 32, PAGE_SIZE).unwrap();
+        cpu.bus.store(VIRTIO_QUEUE_PFN, 32, queue / PAGE_SIZE).unwrap();
+
+        // desc[0] -> the request, whose next field is past the end of the descriptor table.
+        cpu.bus.store(queue, 64, req).unwrap();
+        cpu.bus.store(queue + 8, 32, 16).unwrap();
+        cpu.bus.store(queue + 14, 16, 0xffff).unwrap();
+        cpu.bus.store(req, 32, VIRTIO_BLK_T_IN as u64).unwrap();
+        assert_eq!(cpu.disk_access(), Err(DiskError::DescIndex(0xffff)));
+
+        // A notify of the malformed request is reported instead of interrupting the guest.
+        cpu.bus.store(VIRTIO_QUEUE_NOTIFY, 32, 0).unwrap();
+        cpu.check_pending_interrupt();
+        assert_eq!(cpu.device_error, Some(DiskError::DescIndex(0xffff)));
+        assert_eq!(cpu.bus.load(queue + PAGE_SIZE + 2, 16).unwrap(), 0);
+        assert!(matches!(cpu.run(Some(1), None).reason, StopReason::DeviceError(DiskError::DescIndex(0xffff))));
+
+        // The buffer of a valid descriptor must still fit in the dram.
+        cpu.bus.store(queue + 14, 16, 1).unwrap();
+        cpu.bus.store(queue + 16, 64, DRAM_END - 4).unwrap();
+        cpu.bus.store(queue + 24, 32, SECTOR_SIZE).unwrap();
+        assert_eq!(cpu.disk_access(), Err(DiskError::BufferOutOfRange { addr: DRAM_END - 4, len: SECTOR_SIZE }));
+    }
+
+    #[test]
+    fn test_disk_uninit_ring() {
+        // A ring the guest never wrote is reported instead of panicking on the failed load.
+        let mut cpu = CpuBuilder::new(vec![]).disk_image(vec![0; SECTOR_SIZE as usize]).detect_uninit().build();
+        let queue = DRAM_BASE + PAGE_SIZE;
+        cpu.bus.store(VIRTIO_GUEST_PAGE_SIZE, 32, PAGE_SIZE).unwrap();
+        cpu.bus.store(VIRTIO_QUEUE_PFN, 32, queue / PAGE_SIZE).unwrap();
+        let avail_idx = queue + DESC_NUM as u64 * size_of::<VirtqDesc>() as u64 + 2;
+        assert_eq!(cpu.disk_access(), Err(DiskError::GuestMemory(avail_idx)));
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_execute() {
+        // cargo test --release bench_execute -- --ignored --nocapture
+        let code = "
+            addi t0, zero, 0
+        loop:
+            addi t0, t0, 1
+            xor  t1, t0, t2
+            add  t2, t1, t0
+            slli t3, t2, 3
+            sub  t4, t3, t1
+            j    loop
+        ";
+        let n_clock = 50_000_000;
+        let mut cpu = compile_and_run(code, 0);
+        crate::coverage::set_enabled(false);
+        let start = Instant::now();
+        for _i in 0..n_clock {
+            let inst = cpu.fetch().unwrap();
+            cpu.pc = cpu.execute(inst).unwrap();
+        }
+        let elapsed = start.elapsed();
+        println!("{} instructions in {:?}, {:.2} MIPS", n_clock, elapsed, n_clock as f64 / elapsed.as_secs_f64() / 1e6);
+    }
+
     #[test]
     fn compile_hello_world() {
         // You should run it by
@@ -1,11 +1,31 @@
+use alloc::{format, string::String, vec::Vec};
+
+use crate::interrupt::MASK_INTERRUPT_BIT;
+
 #[allow(dead_code)]
 
 pub const NUM_CSRS: usize = 4096;
+// Unprivileged counters.
+/// Cycle counter for RDCYCLE instruction.
+pub const CYCLE: usize = 0xc00;
+/// Timer for RDTIME instruction. It is read from mtime in the CLINT instead of the csrs.
+pub const TIME: usize = 0xc01;
+/// Instructions-retired counter for RDINSTRET instruction.
+pub const INSTRET: usize = 0xc02;
+
 // Machine-level CSRs.
+/// Vendor ID.
+pub const MVENDORID: usize = 0xf11;
+/// Architecture ID.
+pub const MARCHID: usize = 0xf12;
+/// Implementation ID.
+pub const MIMPID: usize = 0xf13;
 /// Hardware thread ID.
 pub const MHARTID: usize = 0xf14;
 /// Machine status register.
 pub const MSTATUS: usize = 0x300;
+/// ISA and extensions.
+pub const MISA: usize = 0x301;
 /// Machine exception delefation register.
 pub const MEDELEG: usize = 0x302;
 /// Machine interrupt delefation register.
@@ -26,6 +46,10 @@ pub const MCAUSE: usize = 0x342;
 pub const MTVAL: usize = 0x343;
 /// Machine interrupt pending.
 pub const MIP: usize = 0x344;
+/// Physical memory protection configuration, pmpcfg0 to pmpcfg15.
+pub const PMPCFG0: usize = 0x3a0;
+/// Physical memory protection address register, pmpaddr0 to pmpaddr63.
+pub const PMPADDR0: usize = 0x3b0;
 
 // Supervisor-level CSRs.
 /// Supervisor status register.
@@ -82,9 +106,76 @@ pub const MASK_MTIP: u64 = 1 << 7;
 pub const MASK_SEIP: u64 = 1 << 9;
 pub const MASK_MEIP: u64 = 1 << 11;
 
+// The exceptions that can be delegated to S-mode. An ecall from M-mode (11) always traps to
+// M-mode, and the reserved codes 10 and 14 are read-only zero.
+pub const MASK_MEDELEG: u64 = 0b1011_0011_1111_1111;
+// Only the S-mode interrupts can be delegated: SSI, STI and SEI.
+pub const MASK_MIDELEG: u64 = MASK_SSIP | MASK_STIP | MASK_SEIP;
+
 // SATP field
 pub const MASK_PPN:  u64 = (1 << 44) - 1;
 
+// MISA extension bits, one per letter from A (bit 0) to Z (bit 25).
+pub const EXT_A: u64 = 1 << 0;
+pub const EXT_C: u64 = 1 << 2;
+pub const EXT_D: u64 = 1 << 3;
+pub const EXT_F: u64 = 1 << 5;
+pub const EXT_I: u64 = 1 << 8;
+pub const EXT_M: u64 = 1 << 12;
+// The MXL field of misa, which is 2 for XLEN=64.
+pub const MISA_MXL_64: u64 = 2 << 62;
+// The extensions that are implemented.
+pub const MISA_EXTENSIONS: u64 = EXT_I | EXT_M | EXT_A | EXT_C;
+
+
+/// Return true if the csr at `addr` exists. Accessing any other csr raises an illegal instruction.
+/// The PMP csrs exist so that firmware can set them up, but they do not restrict any access.
+pub fn is_implemented(addr: usize) -> bool {
+    matches!(addr,
+        CYCLE | TIME | INSTRET
+        | SSTATUS | SIE | STVEC | SSCRATCH | SEPC | SCAUSE | STVAL | SIP | SATP
+        | MSTATUS | MISA | MEDELEG | MIDELEG | MIE | MTVEC | MCOUNTEREN
+        | MSCRATCH | MEPC | MCAUSE | MTVAL | MIP
+        | MVENDORID | MARCHID | MIMPID | MHARTID)
+        || (PMPCFG0..PMPCFG0 + 16).contains(&addr)
+        || (PMPADDR0..PMPADDR0 + 64).contains(&addr)
+}
+
+/// Decode an mcause/scause value, e.g. "Interrupt: MachineTimer" or "Exception: LoadPageFault".
+pub fn decode_cause(cause: u64) -> String {
+    let code = cause & !MASK_INTERRUPT_BIT;
+    let name = if cause & MASK_INTERRUPT_BIT != 0 {
+        match code {
+            1 => "SupervisorSoftware",
+            3 => "MachineSoftware",
+            5 => "SupervisorTimer",
+            7 => "MachineTimer",
+            9 => "SupervisorExternal",
+            11 => "MachineExternal",
+            _ => return format!("Interrupt: Unknown({})", code),
+        }
+    } else {
+        match code {
+            0 => "InstructionAddrMisaligned",
+            1 => "InstructionAccessFault",
+            2 => "IllegalInstruction",
+            3 => "Breakpoint",
+            4 => "LoadAccessMisaligned",
+            5 => "LoadAccessFault",
+            6 => "StoreAMOAddrMisaligned",
+            7 => "StoreAMOAccessFault",
+            8 => "EnvironmentCallFromUMode",
+            9 => "EnvironmentCallFromSMode",
+            11 => "EnvironmentCallFromMMode",
+            12 => "InstructionPageFault",
+            13 => "LoadPageFault",
+            15 => "StoreAMOPageFault",
+            _ => return format!("Exception: Unknown({})", code),
+        }
+    };
+    let kind = if cause & MASK_INTERRUPT_BIT != 0 { "Interrupt" } else { "Exception" };
+    format!("{}: {}", kind, name)
+}
 
 pub struct Csr {
     csrs: [u64; NUM_CSRS],
@@ -92,9 +183,12 @@ pub struct Csr {
 
 impl Csr {
     pub fn new() -> Csr {
-        Self { csrs: [0; NUM_CSRS] }
+        let mut csrs = [0; NUM_CSRS];
+        csrs[MISA] = MISA_MXL_64 | MISA_EXTENSIONS;
+        Self { csrs }
     }
 
+    #[cfg(feature = "std")]
     pub fn dump_csrs(&self) {
         println!("{:-^80}", "control status registers");
         let output = format!(
@@ -115,6 +209,23 @@ impl Csr {
             ),
         );
         println!("{}", output);
+        println!("mcause: {}", decode_cause(self.load(MCAUSE)));
+        println!("scause: {}\n", decode_cause(self.load(SCAUSE)));
+    }
+
+    /// Append the raw value of every csr to `data` as little-endian u64s.
+    pub fn snapshot(&self, data: &mut Vec<u8>) {
+        for csr in self.csrs.iter() {
+            data.extend_from_slice(&csr.to_le_bytes());
+        }
+    }
+
+    /// Restore every csr from `data`, which holds `NUM_CSRS` little-endian u64s.
+    pub fn restore(&mut self, data: &[u8]) {
+        assert_eq!(data.len(), NUM_CSRS * 8, "Invalid csr snapshot");
+        for (csr, bytes) in self.csrs.iter_mut().zip(data.chunks_exact(8)) {
+            *csr = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
     }
 
     pub fn load(&self, addr: usize) -> u64 {
@@ -129,8 +240,13 @@ impl Csr {
     pub fn store(&mut self, addr: usize, value: u64) {
         match addr {
             SIE => self.csrs[MIE] = (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]),
-            SIP => self.csrs[MIP] = (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]),
+            SIP => self.csrs[MIP] = (self.csrs[MIP] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]),
             SSTATUS => self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !MASK_SSTATUS) | (value & MASK_SSTATUS),
+            MEDELEG => self.csrs[MEDELEG] = value & MASK_MEDELEG,
+            MIDELEG => self.csrs[MIDELEG] = value & MASK_MIDELEG,
+            // misa is WARL, and none of its fields can be changed: every implemented extension is
+            // always enabled, and an unimplemented one cannot be.
+            MISA => (),
             _ => self.csrs[addr] = value,
         }
     }
@@ -144,4 +260,19 @@ impl Csr {
     pub fn is_midelegated(&self, cause: u64) -> bool {
         (self.csrs[MIDELEG].wrapping_shr(cause as u32) & 1) == 1
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_cause() {
+        assert_eq!(decode_cause(MASK_INTERRUPT_BIT | 7), "Interrupt: MachineTimer");
+        assert_eq!(decode_cause(MASK_INTERRUPT_BIT | 5), "Interrupt: SupervisorTimer");
+        assert_eq!(decode_cause(13), "Exception: LoadPageFault");
+        assert_eq!(decode_cause(15), "Exception: StoreAMOPageFault");
+        assert_eq!(decode_cause(10), "Exception: Unknown(10)");
+    }
+}
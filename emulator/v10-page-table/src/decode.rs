@@ -0,0 +1,199 @@
+//! The decode module splits an instruction into its fields and tells whether it is one of the
+//! instructions `Cpu::execute` implements. Decoding reads no state and changes none, and any
+//! input decodes to a result or an error, which makes it safe to feed arbitrary words, e.g. from
+//! a fuzzer or a disassembler scanning data. The instruction is recognized by
+//! `instruction::decode`, so a word is legal here exactly when `Cpu::execute` decodes it.
+
+use crate::instruction;
+use crate::rvc;
+
+/// The instruction formats, which tell the operands an instruction has and where its immediate
+/// is. See 2.2 Base Instruction Formats and 2.3 Immediate Encoding Variants.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    R,
+    I,
+    S,
+    B,
+    U,
+    J,
+}
+
+/// A legal instruction. A compressed instruction is decoded as the 32-bit instruction it expands
+/// to, with `len` 2.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DecodedInst {
+    /// The mnemonic, e.g. "addi".
+    pub name: &'static str,
+    pub format: Format,
+    pub opcode: u64,
+    /// 0 for the formats without a funct3, U and J.
+    pub funct3: u64,
+    /// 0 for the formats other than R.
+    pub funct7: u64,
+    /// The register fields. A field the format does not have is 0. The csr instructions with an
+    /// immediate keep their zimm in `rs1`.
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+    /// The sign-extended immediate, or the csr address of a csr instruction. A branch or a jump
+    /// holds the offset from its pc.
+    pub imm: i64,
+    /// The length in bytes, 2 or 4.
+    pub len: u64,
+}
+
+/// Decode `inst`. The lowest 16 bits are a compressed instruction unless the lowest two bits are
+/// 0b11, in which case the lowest 32 bits are a regular instruction, and the higher bits are
+/// ignored either way. Whether the extension of the instruction is enabled, or a csr exists, is
+/// up to the cpu, so both are not checked here.
+// There is only one way to fail, an illegal encoding, so the error carries nothing.
+#[allow(clippy::result_unit_err)]
+pub fn decode(inst: u64) -> Result<DecodedInst, ()> {
+    let (inst, len) = if inst & 0b11 != 0b11 {
+        (rvc::expand(inst as u16).ok_or(())? as u64, 2)
+    } else {
+        (inst & 0xffff_ffff, 4)
+    };
+    let opcode = inst & 0x7f;
+    let rd = ((inst >> 7) & 0x1f) as usize;
+    let funct3 = (inst >> 12) & 0x7;
+    let rs1 = ((inst >> 15) & 0x1f) as usize;
+    let rs2 = ((inst >> 20) & 0x1f) as usize;
+    let funct7 = inst >> 25;
+    let funct12 = inst >> 20;
+
+    // The legality and the mnemonic come from the decoder of `Cpu::execute`.
+    let instruction = instruction::decode(inst).map_err(|_| ())?;
+    let (name, format) = (instruction.name(), instruction.format());
+
+    use Format::*;
+    let imm = match format {
+        R => 0,
+        // The csr address is unsigned.
+        I if opcode == 0x73 => funct12 as i64,
+        I => decode_i_imm(inst),
+        S => decode_s_imm(inst),
+        B => decode_b_imm(inst),
+        U => decode_u_imm(inst),
+        J => decode_j_imm(inst),
+    };
+    let has = |field: usize, formats: &[Format]| if formats.contains(&format) { field } else { 0 };
+    Ok(DecodedInst {
+        name,
+        format,
+        opcode,
+        funct3: if matches!(format, U | J) { 0 } else { funct3 },
+        funct7: if format == R { funct7 } else { 0 },
+        rd: has(rd, &[R, I, U, J]),
+        rs1: has(rs1, &[R, I, S, B]),
+        rs2: has(rs2, &[R, S, B]),
+        imm,
+        len,
+    })
+}
+
+/// The sign-extended immediate of the I-type instruction `inst`.
+pub(crate) fn decode_i_imm(inst: u64) -> i64 {
+    // imm[11:0] = inst[31:20]
+    (inst as i32 as i64) >> 20
+}
+
+/// The sign-extended immediate of the S-type instruction `inst`.
+pub(crate) fn decode_s_imm(inst: u64) -> i64 {
+    // imm[11:5|4:0] = inst[31:25|11:7]
+    ((inst & 0xfe000000) as i32 as i64 >> 20) | ((inst >> 7) & 0x1f) as i64
+}
+
+/// The sign-extended offset of the B-type instruction `inst`. Bit 0 is always 0.
+pub(crate) fn decode_b_imm(inst: u64) -> i64 {
+    // imm[12|10:5|4:1|11] = inst[31|30:25|11:8|7]
+    ((inst & 0x80000000) as i32 as i64 >> 19)
+        | ((inst & 0x80) << 4) as i64 // imm[11]
+        | ((inst >> 20) & 0x7e0) as i64 // imm[10:5]
+        | ((inst >> 7) & 0x1e) as i64 // imm[4:1]
+}
+
+/// The sign-extended immediate of the U-type instruction `inst`, already shifted to bits 31:12.
+pub(crate) fn decode_u_imm(inst: u64) -> i64 {
+    // imm[31:12] = inst[31:12]
+    (inst & 0xfffff000) as i32 as i64
+}
+
+/// The sign-extended offset of the J-type instruction `inst`. Bit 0 is always 0.
+pub(crate) fn decode_j_imm(inst: u64) -> i64 {
+    // imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
+    ((inst & 0x80000000) as i32 as i64 >> 11) // imm[20]
+        | (inst & 0xff000) as i64 // imm[19:12]
+        | ((inst >> 9) & 0x800) as i64 // imm[11]
+        | ((inst >> 20) & 0x7fe) as i64 // imm[10:1]
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::coverage;
+    use crate::cpu::Cpu;
+    use crate::preview::PreviewResult;
+
+    #[test]
+    fn test_decode_fields() {
+        // sd a1, -8(sp)
+        let sd = decode(0xfeb13c23).unwrap();
+        assert_eq!((sd.name, sd.format, sd.rs1, sd.rs2, sd.imm, sd.len), ("sd", Format::S, 2, 11, -8, 4));
+        // beq a0, a1, -4
+        let beq = decode(0xfeb50ee3).unwrap();
+        assert_eq!((beq.name, beq.rs1, beq.rs2, beq.imm), ("beq", 10, 11, -4));
+        // csrrs a0, mstatus, zero
+        let csrr = decode(0x30002573).unwrap();
+        assert_eq!((csrr.name, csrr.rd, csrr.imm), ("csrrs", 10, 0x300));
+        // c.li a0, -1 is addi a0, zero, -1.
+        let li = decode(0x557d).unwrap();
+        assert_eq!((li.name, li.rd, li.rs1, li.imm, li.len), ("addi", 10, 0, -1, 2));
+        // a store with funct3=0x7, the all-zero compressed word, and slli with funct6 set.
+        for inst in [0x0000f023, 0x0000, 0x40051513] {
+            assert!(decode(inst).is_err(), "{:#x}", inst);
+        }
+    }
+
+    #[test]
+    fn test_imm_helpers() {
+        // addi a0, a0, -1 and addi a0, zero, 2047
+        assert_eq!(decode_i_imm(0xfff50513), -1);
+        assert_eq!(decode_i_imm(0x7ff00513), 2047);
+        // sd a1, -8(sp) and sw a0, 2047(t0)
+        assert_eq!(decode_s_imm(0xfeb13c23), -8);
+        assert_eq!(decode_s_imm(0x7ea2afa3), 2047);
+        // beq a0, a1, -4 and beq zero, zero, -4096
+        assert_eq!(decode_b_imm(0xfeb50ee3), -4);
+        assert_eq!(decode_b_imm(0x80000063), -4096);
+        // lui a0, 0x12345 and lui a0, 0x80000
+        assert_eq!(decode_u_imm(0x12345537), 0x12345000);
+        assert_eq!(decode_u_imm(0x80000537), -0x8000_0000);
+        // jal zero, -4, jal ra, 2048 and jal zero, -1 MiB
+        assert_eq!(decode_j_imm(0xffdff06f), -4);
+        assert_eq!(decode_j_imm(0x001000ef), 2048);
+        assert_eq!(decode_j_imm(0x8000006f), -0x10_0000);
+    }
+
+    #[test]
+    fn test_decode_random_words() {
+        // A word is legal to the disassembler, to `preview` and to `execute` alike.
+        let cpu = Cpu::new(vec![], vec![]);
+        let mut x: u64 = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..1_000_000 {
+            // xorshift64
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let inst = if x & 0b11 != 0b11 { rvc::expand(x as u16).map(u64::from) } else { Some(x & 0xffff_ffff) };
+            let executed = inst.and_then(|inst| instruction::decode(inst).ok());
+            assert_eq!(decode(x).ok().map(|decoded| decoded.name), executed.map(|i| i.name()), "{:#x}", x);
+            match executed {
+                Some(instruction) => assert!(coverage::IMPLEMENTED.contains(&instruction.name()), "{:#x}", x),
+                None => assert_eq!(cpu.preview(x), PreviewResult::default(), "{:#x}", x),
+            }
+        }
+    }
+}
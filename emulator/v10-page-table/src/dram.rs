@@ -1,27 +1,76 @@
 //! The dram module contains a dram structure and implementation for dram access.
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
 use crate::param::{DRAM_SIZE, DRAM_BASE};
 use crate::exception::Exception;
+use crate::memory::Memory;
 
 use Exception::*;
 
 pub struct Dram {
     pub dram: Vec<u8>,
+    /// One bit per byte that has been written since `detect_uninit` was enabled, or None if it is
+    /// disabled.
+    written: Option<Vec<u64>>,
 }
 
 impl Dram {
     pub fn new(code: Vec<u8>) -> Dram {
         let mut dram = vec![0; DRAM_SIZE as usize];
         dram.splice(..code.len(), code.into_iter());
-        Self { dram }
+        Self { dram, written: None }
+    }
+
+    fn mark_written(&mut self, range: Range<usize>) {
+        if let Some(written) = &mut self.written {
+            for i in range {
+                written[i / 64] |= 1 << (i % 64);
+            }
+        }
+    }
+
+    fn is_written(&self, range: Range<usize>) -> bool {
+        match &self.written {
+            Some(written) => range.into_iter().all(|i| written[i / 64] & (1 << (i % 64)) != 0),
+            None => true,
+        }
+    }
+
+    /// The indices of `len` bytes at `addr`, or None if they are not all in the dram.
+    fn range(&self, addr: u64, len: usize) -> Option<Range<usize>> {
+        let start = addr.checked_sub(DRAM_BASE)? as usize;
+        let end = start.checked_add(len)?;
+        if end <= self.dram.len() { Some(start..end) } else { None }
+    }
+}
+
+impl Memory for Dram {
+    /// Copy the bytes at `addr` into `buf`.
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        let range = self.range(addr, buf.len()).ok_or(LoadAccessFault(addr))?;
+        buf.copy_from_slice(&self.dram[range]);
+        Ok(())
+    }
+
+    /// Copy `buf` to the bytes at `addr`.
+    fn write(&mut self, addr: u64, buf: &[u8]) -> Result<(), Exception> {
+        let range = self.range(addr, buf.len()).ok_or(StoreAMOAccessFault(addr))?;
+        self.dram[range.clone()].copy_from_slice(buf);
+        self.mark_written(range);
+        Ok(())
     }
 
     // addr/size must be valid. Check in bus
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+    fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
         if ![8, 16, 32, 64].contains(&size) {
             return Err(LoadAccessFault(addr));
         }
         let nbytes = size / 8;
         let index = (addr - DRAM_BASE) as usize;
+        if !self.is_written(index..index + nbytes as usize) {
+            return Err(LoadAccessFault(addr));
+        }
         let mut code = self.dram[index] as u64;
         for i in 1..nbytes {
             code |= (self.dram[index + i as usize] as u64) << (i * 8);
@@ -31,7 +80,7 @@ impl Dram {
     }
 
     // addr/size must be valid. Check in bus
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if ![8, 16, 32, 64].contains(&size) {
             return Err(StoreAMOAccessFault(addr));
         }
@@ -41,6 +90,29 @@ impl Dram {
             let offset = 8 * i as usize;
             self.dram[index + i as usize] = ((value >> offset) & 0xff) as u8;
         }
+        self.mark_written(index..index + nbytes as usize);
         return Ok(())
     }
+
+    /// Append the dram contents to `data`, prefixed by their length as a little-endian u64.
+    fn snapshot(&self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&(self.dram.len() as u64).to_le_bytes());
+        data.extend_from_slice(&self.dram);
+    }
+
+    /// Restore the dram contents from `data` produced by `snapshot`.
+    fn restore(&mut self, data: &[u8]) {
+        assert!(data.len() >= 8, "Invalid dram snapshot");
+        let (len, contents) = data.split_at(8);
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        assert!(len == self.dram.len() as u64 && len == contents.len() as u64, "Invalid dram snapshot");
+        self.dram.copy_from_slice(contents);
+        self.mark_written(0..self.dram.len());
+    }
+
+    /// Make a load fail with an access fault if it reads a byte that has not been written since.
+    /// The bytes written before, including the loaded code, count as uninitialized.
+    fn detect_uninit(&mut self, enable: bool) {
+        self.written = enable.then(|| vec![0; self.dram.len().div_ceil(64)]);
+    }
 }
@@ -0,0 +1,148 @@
+//! The elf module contains a minimal loader for little-endian ELF64 RISC-V executables.
+//! Only the program headers are read: every PT_LOAD segment is copied to its physical address.
+//!
+//! The ELF spec:
+//! https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+
+use std::io;
+
+use crate::param::*;
+
+pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 0xf3;
+const PT_LOAD: u32 = 1;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+pub struct Elf {
+    /// The address of the first instruction to execute.
+    pub entry: u64,
+    /// The memory image of all PT_LOAD segments. It starts at DRAM_BASE.
+    pub image: Vec<u8>,
+}
+
+/// Return true if `bytes` starts with the ELF magic number.
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ELF_MAGIC)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+impl Elf {
+    /// Parse an ELF executable and lay out its loadable segments in a dram image.
+    pub fn parse(bytes: &[u8]) -> io::Result<Elf> {
+        if bytes.len() < EHDR_SIZE || !is_elf(bytes) {
+            return Err(invalid("not an ELF file".to_string()));
+        }
+        if bytes[4] != ELFCLASS64 || bytes[5] != ELFDATA2LSB {
+            return Err(invalid("only little-endian ELF64 is supported".to_string()));
+        }
+        if read_u16(bytes, 18) != EM_RISCV {
+            return Err(invalid("not a RISC-V ELF file".to_string()));
+        }
+        let entry = read_u64(bytes, 24);
+        let phoff = read_u64(bytes, 32) as usize;
+        let phentsize = read_u16(bytes, 54) as usize;
+        let phnum = read_u16(bytes, 56) as usize;
+        if phentsize < PHDR_SIZE || phoff.saturating_add(phentsize * phnum) > bytes.len() {
+            return Err(invalid("program headers out of range".to_string()));
+        }
+
+        let mut image = Vec::new();
+        for i in 0..phnum {
+            let ph = &bytes[phoff + i * phentsize..];
+            if read_u32(ph, 0) != PT_LOAD {
+                continue;
+            }
+            let offset = read_u64(ph, 8) as usize;
+            let paddr = read_u64(ph, 24);
+            let filesz = read_u64(ph, 32) as usize;
+            let memsz = read_u64(ph, 40);
+            if memsz == 0 {
+                continue;
+            }
+            if offset.saturating_add(filesz) > bytes.len() || filesz as u64 > memsz {
+                return Err(invalid(format!("segment {} out of range", i)));
+            }
+            if paddr < DRAM_BASE || paddr.saturating_add(memsz - 1) > DRAM_END {
+                return Err(invalid(format!("segment {} at {:#x} is outside of dram", i, paddr)));
+            }
+            // The bytes between filesz and memsz (e.g. .bss) are zero.
+            let start = (paddr - DRAM_BASE) as usize;
+            let end = start + memsz as usize;
+            if image.len() < end {
+                image.resize(end, 0);
+            }
+            image[start..start + filesz].copy_from_slice(&bytes[offset..offset + filesz]);
+        }
+        Ok(Elf { entry, image })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build_elf(entry: u64, paddr: u64, code: &[u8], memsz: u64) -> Vec<u8> {
+        let mut elf = Vec::new();
+        elf.extend_from_slice(&ELF_MAGIC);
+        elf.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, 1, 0]);
+        elf.extend_from_slice(&[0; 8]);
+        elf.extend_from_slice(&2u16.to_le_bytes()); // e_type: executable
+        elf.extend_from_slice(&EM_RISCV.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&entry.to_le_bytes());
+        elf.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        elf.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        elf.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+        elf.extend_from_slice(&[0; 6]);
+        let offset = (EHDR_SIZE + PHDR_SIZE) as u64;
+        elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+        elf.extend_from_slice(&5u32.to_le_bytes()); // p_flags: r-x
+        for field in [offset, paddr, paddr, code.len() as u64, memsz, 0x1000] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        elf.extend_from_slice(code);
+        elf
+    }
+
+    #[test]
+    fn test_parse() {
+        let code = [0x93, 0x0f, 0xa0, 0x02];
+        let elf = build_elf(DRAM_BASE + 0x100, DRAM_BASE + 0x100, &code, 8);
+        let elf = Elf::parse(&elf).unwrap();
+        assert_eq!(elf.entry, DRAM_BASE + 0x100);
+        assert_eq!(elf.image.len(), 0x108);
+        assert_eq!(&elf.image[0x100..0x104], &code);
+        assert_eq!(&elf.image[0x104..], &[0; 4]);
+    }
+
+    #[test]
+    fn test_outside_dram() {
+        let elf = build_elf(0x1000, 0x1000, &[0; 4], 4);
+        assert!(Elf::parse(&elf).is_err());
+        assert!(Elf::parse(&[0; 4]).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt;
+use core::fmt;
 
 #[derive(Debug, Copy, Clone)]
 pub enum Exception {
@@ -22,27 +22,32 @@ pub enum Exception {
 use Exception::*;
 impl fmt::Display for Exception {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            InstructionAddrMisaligned(addr) => write!(f, "Instruction address misaligned {:#x}", addr),
-            InstructionAccessFault(addr) => write!(f, "Instruction access fault {:#x}", addr),
-            IllegalInstruction(inst) => write!(f, "Illegal instruction {:#x}, opcode = {:#x}", inst, inst & 0x7f),
-            Breakpoint(pc) => write!(f, "Breakpoint {:#x}", pc),
-            LoadAccessMisaligned(addr) => write!(f, "Load access {:#x}", addr),
-            LoadAccessFault(addr) => write!(f, "Load access fault {:#x}", addr),
-            StoreAMOAddrMisaligned(addr) => write!(f, "Store or AMO address misaliged {:#x}", addr),
-            StoreAMOAccessFault(addr) => write!(f, "Store or AMO access fault {:#x}", addr),
-            EnvironmentCallFromUMode(pc) => write!(f, "Environment call from U-mode {:#x}", pc),
-            EnvironmentCallFromSMode(pc) => write!(f, "Environment call from S-mode {:#x}", pc),
-            EnvironmentCallFromMMode(pc) => write!(f, "Environment call from M-mode {:#x}", pc),
-            InstructionPageFault(addr) => write!(f, "Instruction page fault {:#x}", addr),
-            LoadPageFault(addr) => write!(f, "Load page fault {:#x}", addr),
-            StoreAMOPageFault(addr) => write!(f, "Store or AMO page fault {:#x}", addr),
-        }
+        write!(f, "{} (code={}, tval={:#x})", self.name(), self.code(), self.value())
     }
 }
 
 
 impl Exception {
+    /// The name of the exception in the table 3.6 of the privileged spec.
+    pub fn name(&self) -> &'static str {
+        match self {
+            InstructionAddrMisaligned(_) => "Instruction address misaligned",
+            InstructionAccessFault(_) => "Instruction access fault",
+            IllegalInstruction(_) => "Illegal instruction",
+            Breakpoint(_) => "Breakpoint",
+            LoadAccessMisaligned(_) => "Load address misaligned",
+            LoadAccessFault(_) => "Load access fault",
+            StoreAMOAddrMisaligned(_) => "Store/AMO address misaligned",
+            StoreAMOAccessFault(_) => "Store/AMO access fault",
+            EnvironmentCallFromUMode(_) => "Environment call from U-mode",
+            EnvironmentCallFromSMode(_) => "Environment call from S-mode",
+            EnvironmentCallFromMMode(_) => "Environment call from M-mode",
+            InstructionPageFault(_) => "Instruction page fault",
+            LoadPageFault(_) => "Load page fault",
+            StoreAMOPageFault(_) => "Store/AMO page fault",
+        }
+    }
+
     pub fn value(self) -> u64 {
         match self {
             InstructionAddrMisaligned(addr) => addr,
@@ -106,4 +111,13 @@ mod test {
         assert_eq!(e.value(), 0);
         assert_eq!(e.code(), 2);
     }
+
+    #[test]
+    fn test_display() {
+        let e = Exception::LoadPageFault(0x1000);
+        assert_eq!(e.name(), "Load page fault");
+        assert_eq!(e.to_string(), "Load page fault (code=13, tval=0x1000)");
+        let e = Exception::IllegalInstruction(0xffffffff);
+        assert_eq!(e.to_string(), "Illegal instruction (code=2, tval=0xffffffff)");
+    }
 }
\ No newline at end of file
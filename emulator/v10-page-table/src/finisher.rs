@@ -0,0 +1,90 @@
+//! The finisher module contains the SiFive test finisher, a tiny device that lets a guest (e.g.
+//! riscv-tests) report pass or fail and power off the machine. A 32-bit store of `0x5555` means
+//! pass, and `0x3333 | (code << 16)` means fail with an exit code.
+
+use alloc::vec::Vec;
+
+use crate::bus::*;
+use crate::exception::*;
+use crate::param::*;
+
+use Exception::*;
+
+pub struct TestFinisher {
+    base: u64,
+    exit_code: Option<i64>,
+}
+
+impl TestFinisher {
+    /// Create a test finisher at `base`. The default address is `TEST_FINISHER_BASE`.
+    pub fn new(base: u64) -> Self {
+        Self { base, exit_code: None }
+    }
+
+    /// The exit code the guest has reported, if any.
+    pub fn exit_code(&self) -> Option<i64> {
+        self.exit_code
+    }
+}
+
+impl Device for TestFinisher {
+    fn base(&self) -> u64 {
+        self.base
+    }
+
+    fn size(&self) -> u64 {
+        TEST_FINISHER_SIZE
+    }
+
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        match size {
+            32 => Ok(0),
+            _ => Err(LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 || addr != self.base {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        match value & 0xffff {
+            FINISHER_PASS => self.exit_code = Some(0),
+            FINISHER_FAIL => self.exit_code = Some(((value as u32) >> 16) as i64),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self, data: &mut Vec<u8>) {
+        put_word(data, self.exit_code.is_some() as u64);
+        put_word(data, self.exit_code.unwrap_or(0) as u64);
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let mut reader = SnapshotReader::new(data);
+        let (is_some, code) = (reader.word() != 0, reader.word() as i64);
+        self.exit_code = is_some.then_some(code);
+    }
+
+    fn reset(&mut self) {
+        self.exit_code = None;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_finisher_protocol() {
+        let mut finisher = TestFinisher::new(TEST_FINISHER_BASE);
+        finisher.store(TEST_FINISHER_BASE, 32, 0x1234).unwrap();
+        assert_eq!(finisher.exit_code(), None);
+        finisher.store(TEST_FINISHER_BASE, 32, (3 << 16) | FINISHER_FAIL).unwrap();
+        assert_eq!(finisher.exit_code(), Some(3));
+        finisher.store(TEST_FINISHER_BASE, 32, FINISHER_PASS).unwrap();
+        assert_eq!(finisher.exit_code(), Some(0));
+        assert!(finisher.store(TEST_FINISHER_BASE, 64, FINISHER_PASS).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! The gdb module contains a minimal server of the GDB remote serial protocol (RSP), so that a
+//! guest can be debugged by `gdb-multiarch` with `target remote :<port>`. It supports reading and
+//! writing registers and memory, single step, continue, software breakpoints and watchpoints.
+//!
+//! The protocol:
+//! https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+
+use std::collections::HashSet;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::*;
+
+/// The number of registers in a `g` packet: x0-x31 and pc, as gdb numbers them for RISC-V.
+const NUM_REGS: usize = 33;
+
+/// The largest packet gdb may send or expect, as told by the reply to `qSupported`.
+const PACKET_SIZE: usize = 0x4000;
+
+/// Wait for gdb to connect on `port` and serve it until it detaches or kills the guest.
+pub fn listen(cpu: &mut Cpu, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for gdb on port {}", port);
+    let (stream, _) = listener.accept()?;
+    serve(cpu, stream)
+}
+
+/// Serve one gdb connection.
+pub fn serve(cpu: &mut Cpu, stream: TcpStream) -> io::Result<()> {
+    // Packets are small and every one waits for a reply.
+    stream.set_nodelay(true)?;
+    let mut stub = GdbStub { stream, breakpoints: HashSet::new() };
+    while let Some(packet) = stub.read_packet()? {
+        let reply = match packet.as_bytes().first() {
+            Some(b'k') => return Ok(()),
+            Some(b'D') => {
+                stub.write_packet("OK")?;
+                return Ok(());
+            }
+            _ => stub.handle(cpu, &packet)?,
+        };
+        stub.write_packet(&reply)?;
+    }
+    Ok(())
+}
+
+fn reg_number(n: usize) -> Option<Reg> {
+    match n {
+        0..=31 => Some(Reg::X(n as u8)),
+        32 => Some(Reg::Pc),
+        _ => None,
+    }
+}
+
+/// Encode a register as gdb expects: 8 bytes in target (little-endian) order.
+fn encode_reg(value: u64) -> String {
+    encode_hex(&value.to_le_bytes())
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn decode_reg(hex: &str) -> Option<u64> {
+    let bytes = decode_hex(hex)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Parse "addr,len" of `m`, `M`, `Z` and `z` packets.
+fn parse_addr_len(args: &str) -> Option<(u64, u64)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((u64::from_str_radix(addr, 16).ok()?, u64::from_str_radix(len, 16).ok()?))
+}
+
+struct GdbStub {
+    stream: TcpStream,
+    /// The pcs of the software breakpoints. They are checked before each instruction instead of
+    /// being patched into the guest memory.
+    breakpoints: HashSet<u64>,
+}
+
+impl GdbStub {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Read a packet `$<data>#<checksum>` and acknowledge it. Return None when gdb hangs up.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip the acknowledgements of our replies and anything else before a packet.
+            match self.read_byte()? {
+                Some(b'$') => (),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expect = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+            if expect == Some(data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()
+    }
+
+    /// Return true if gdb has sent an interrupt (ctrl-c) while the guest is running.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = match self.stream.read(&mut byte) {
+            Ok(n) => Ok(n == 1 && byte[0] == 0x03),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    /// Step one instruction and return the stop reply, or None if the guest can keep going.
+    fn step(&mut self, cpu: &mut Cpu) -> Option<String> {
+        if cpu.step().is_err() || cpu.device_error.is_some() {
+            // SIGSEGV
+            return Some("S0b".to_string());
+        }
+        if let Some(code) = cpu.exit_code {
+            return Some(format!("W{:02x}", code as u8));
+        }
+        if let Some(hit) = cpu.watch_hit.take() {
+            let kind = if hit.kind == WatchKind::Write { "watch" } else { "rwatch" };
+            return Some(format!("T05{}:{:x};", kind, hit.addr));
+        }
+        None
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, packet: &str) -> io::Result<String> {
+        // An empty packet has no command, which is not supported either.
+        let Some(first) = packet.chars().next() else {
+            return Ok(String::new());
+        };
+        let (command, args) = packet.split_at(first.len_utf8());
+        let reply = match command {
+            // SIGTRAP
+            "?" => "S05".to_string(),
+            "g" => (0..NUM_REGS).map(|n| encode_reg(cpu.read(reg_number(n).unwrap()))).collect(),
+            "G" => {
+                let values: Option<Vec<u64>> =
+                    (0..NUM_REGS).map(|n| decode_reg(args.get(16 * n..16 * (n + 1))?)).collect();
+                match values {
+                    Some(values) => {
+                        for (n, value) in values.into_iter().enumerate() {
+                            cpu.write(reg_number(n).unwrap(), value);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(reg_number) {
+                Some(r) => encode_reg(cpu.read(r)),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, value)| {
+                    Some((reg_number(usize::from_str_radix(n, 16).ok()?)?, decode_reg(value)?))
+                });
+                match parsed {
+                    Some((r, value)) => {
+                        cpu.write(r, value);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "m" => match parse_addr_len(args) {
+                // The reply has two hex digits per byte, and must fit in a packet.
+                Some((addr, len)) if len <= (PACKET_SIZE / 2) as u64 => {
+                    let mut buf = vec![0; len as usize];
+                    match cpu.read_virt(addr, &mut buf) {
+                        Ok(()) => encode_hex(&buf),
+                        Err(_) => "E14".to_string(),
+                    }
+                }
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, hex)| {
+                    let (addr, len) = parse_addr_len(range)?;
+                    let bytes = decode_hex(hex)?;
+                    if bytes.len() as u64 == len { Some((addr, bytes)) } else { None }
+                });
+                match parsed {
+                    Some((addr, bytes)) => match cpu.write_virt(addr, &bytes) {
+                        Ok(()) => "OK".to_string(),
+                        Err(_) => "E14".to_string(),
+                    },
+                    None => "E01".to_string(),
+                }
+            }
+            "s" => self.step(cpu).unwrap_or_else(|| "S05".to_string()),
+            "c" => {
+                // Step first so that a continue from a breakpoint does not stop right away.
+                let mut count: u64 = 0;
+                loop {
+                    if let Some(reply) = self.step(cpu) {
+                        break reply;
+                    }
+                    if self.breakpoints.contains(&cpu.pc) {
+                        break "S05".to_string();
+                    }
+                    count += 1;
+                    if count.is_multiple_of(4096) && self.interrupted()? {
+                        // SIGINT
+                        break "S02".to_string();
+                    }
+                }
+            }
+            "Z" | "z" if args.starts_with("0,") => match parse_addr_len(&args[2..]) {
+                Some((addr, _kind)) => {
+                    if command == "Z" {
+                        self.breakpoints.insert(addr);
+                    } else {
+                        self.breakpoints.remove(&addr);
+                    }
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            },
+            // Write, read and access watchpoints.
+            "Z" | "z" if matches!(args.get(..2), Some("2," | "3," | "4,")) => match parse_addr_len(&args[2..]) {
+                Some((addr, _len)) => {
+                    let kind = match &args[..1] {
+                        "2" => WatchKind::Write,
+                        "3" => WatchKind::Read,
+                        _ => WatchKind::Access,
+                    };
+                    cpu.watchpoints.retain(|&watch| watch != (addr, kind));
+                    if command == "Z" {
+                        cpu.watchpoints.push((addr, kind));
+                    }
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            },
+            "H" => "OK".to_string(),
+            "q" if args.starts_with("Supported") => format!("PacketSize={:x}", PACKET_SIZE),
+            "q" if args == "Attached" => "1".to_string(),
+            // An empty reply means the packet is not supported.
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    use crate::param::*;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        /// Send a packet and return the reply.
+        fn send(&mut self, data: &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+');
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            let mut reply = Vec::new();
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_rsp_session() {
+        // addi a0, zero, 1; addi a0, a0, 1; addi a0, a0, 1; j .
+        let insts: [u32; 4] = [0x00100513, 0x00150513, 0x00150513, 0x0000006f];
+        let code: Vec<u8> = insts.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut cpu = Cpu::new(code, vec![]);
+            serve(&mut cpu, stream).unwrap();
+        });
+        let mut gdb = Client { stream: TcpStream::connect(("127.0.0.1", port)).unwrap() };
+        gdb.stream.set_nodelay(true).unwrap();
+
+        assert_eq!(gdb.send("qSupported:swbreak+"), "PacketSize=4000");
+        assert_eq!(gdb.send("?"), "S05");
+        assert_eq!(gdb.send("s"), "S05");
+        let regs = gdb.send("g");
+        assert_eq!(regs.len(), NUM_REGS * 16);
+        assert_eq!(&regs[10 * 16..11 * 16], encode_reg(1));
+        assert_eq!(&regs[32 * 16..], encode_reg(DRAM_BASE + 4));
+
+        // Stop at the third instruction.
+        assert_eq!(gdb.send("Z0,80000008,4"), "OK");
+        assert_eq!(gdb.send("c"), "S05");
+        assert_eq!(gdb.send("p20"), encode_reg(DRAM_BASE + 8));
+        assert_eq!(gdb.send("pa"), encode_reg(2));
+        assert_eq!(gdb.send("z0,80000008,4"), "OK");
+
+        assert_eq!(gdb.send(&format!("Pa={}", encode_reg(42))), "OK");
+        assert_eq!(gdb.send("pa"), encode_reg(42));
+        assert_eq!(gdb.send("M80001000,4:deadbeef"), "OK");
+        assert_eq!(gdb.send("m80001000,4"), "deadbeef");
+        assert_eq!(gdb.send("m80000000,4"), "13051000");
+        assert_eq!(gdb.send("m0,4"), "E14");
+        assert_eq!(gdb.send("vMustReplyEmpty"), "");
+        // A read must fit in a reply packet.
+        assert_eq!(gdb.send("m80000000,2000").len(), 0x4000);
+        assert_eq!(gdb.send("m80000000,2001"), "E01");
+        assert_eq!(gdb.send("m80000000,ffffffffffffffff"), "E01");
+        // Packets without a command, or with one that is not ASCII, are not supported.
+        assert_eq!(gdb.send(""), "");
+        assert_eq!(gdb.send("\u{e9}"), "");
+
+        write!(gdb.stream, "$k#6b").unwrap();
+        server.join().unwrap();
+    }
+}
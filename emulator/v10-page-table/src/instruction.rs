@@ -0,0 +1,329 @@
+//! The instruction module contains `Instruction`, an instruction with its operands decoded, and
+//! `decode` which produces it. `Cpu::execute` decodes an instruction once and then matches on the
+//! variant, so the fields are never extracted by the handlers themselves.
+
+use crate::decode::*;
+use crate::exception::Exception;
+
+/// A 32-bit instruction that `Cpu::execute` implements. Register operands are indices into the
+/// integer registers, immediates are sign-extended, and the offset of a branch or jump is relative
+/// to its pc.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    // RV64I
+    Lb { rd: usize, rs1: usize, imm: i64 },
+    Lh { rd: usize, rs1: usize, imm: i64 },
+    Lw { rd: usize, rs1: usize, imm: i64 },
+    Ld { rd: usize, rs1: usize, imm: i64 },
+    Lbu { rd: usize, rs1: usize, imm: i64 },
+    Lhu { rd: usize, rs1: usize, imm: i64 },
+    Lwu { rd: usize, rs1: usize, imm: i64 },
+    Fence,
+    FenceI,
+    Addi { rd: usize, rs1: usize, imm: i64 },
+    Slli { rd: usize, rs1: usize, shamt: u32 },
+    Slti { rd: usize, rs1: usize, imm: i64 },
+    Sltiu { rd: usize, rs1: usize, imm: i64 },
+    Xori { rd: usize, rs1: usize, imm: i64 },
+    Srli { rd: usize, rs1: usize, shamt: u32 },
+    Srai { rd: usize, rs1: usize, shamt: u32 },
+    Ori { rd: usize, rs1: usize, imm: i64 },
+    Andi { rd: usize, rs1: usize, imm: i64 },
+    Auipc { rd: usize, imm: i64 },
+    Addiw { rd: usize, rs1: usize, imm: i64 },
+    Slliw { rd: usize, rs1: usize, shamt: u32 },
+    Srliw { rd: usize, rs1: usize, shamt: u32 },
+    Sraiw { rd: usize, rs1: usize, shamt: u32 },
+    Sb { rs1: usize, rs2: usize, imm: i64 },
+    Sh { rs1: usize, rs2: usize, imm: i64 },
+    Sw { rs1: usize, rs2: usize, imm: i64 },
+    Sd { rs1: usize, rs2: usize, imm: i64 },
+    Add { rd: usize, rs1: usize, rs2: usize },
+    Sub { rd: usize, rs1: usize, rs2: usize },
+    Sll { rd: usize, rs1: usize, rs2: usize },
+    Slt { rd: usize, rs1: usize, rs2: usize },
+    Sltu { rd: usize, rs1: usize, rs2: usize },
+    Xor { rd: usize, rs1: usize, rs2: usize },
+    Srl { rd: usize, rs1: usize, rs2: usize },
+    Sra { rd: usize, rs1: usize, rs2: usize },
+    Or { rd: usize, rs1: usize, rs2: usize },
+    And { rd: usize, rs1: usize, rs2: usize },
+    Lui { rd: usize, imm: i64 },
+    Addw { rd: usize, rs1: usize, rs2: usize },
+    Subw { rd: usize, rs1: usize, rs2: usize },
+    Sllw { rd: usize, rs1: usize, rs2: usize },
+    Srlw { rd: usize, rs1: usize, rs2: usize },
+    Sraw { rd: usize, rs1: usize, rs2: usize },
+    Beq { rs1: usize, rs2: usize, imm: i64 },
+    Bne { rs1: usize, rs2: usize, imm: i64 },
+    Blt { rs1: usize, rs2: usize, imm: i64 },
+    Bge { rs1: usize, rs2: usize, imm: i64 },
+    Bltu { rs1: usize, rs2: usize, imm: i64 },
+    Bgeu { rs1: usize, rs2: usize, imm: i64 },
+    Jalr { rd: usize, rs1: usize, imm: i64 },
+    Jal { rd: usize, imm: i64 },
+    // RV64M
+    Mul { rd: usize, rs1: usize, rs2: usize },
+    Divw { rd: usize, rs1: usize, rs2: usize },
+    Divuw { rd: usize, rs1: usize, rs2: usize },
+    Remw { rd: usize, rs1: usize, rs2: usize },
+    Remuw { rd: usize, rs1: usize, rs2: usize },
+    // RV64A. aq and rl are ignored, since there is only one hart running at a time.
+    AmoaddW { rd: usize, rs1: usize, rs2: usize },
+    AmoaddD { rd: usize, rs1: usize, rs2: usize },
+    AmoswapW { rd: usize, rs1: usize, rs2: usize },
+    AmoswapD { rd: usize, rs1: usize, rs2: usize },
+    // Zicsr. The immediate forms keep their zimm in `uimm`.
+    Csrrw { rd: usize, rs1: usize, csr: usize },
+    Csrrs { rd: usize, rs1: usize, csr: usize },
+    Csrrc { rd: usize, rs1: usize, csr: usize },
+    Csrrwi { rd: usize, uimm: u64, csr: usize },
+    Csrrsi { rd: usize, uimm: u64, csr: usize },
+    Csrrci { rd: usize, uimm: u64, csr: usize },
+    // The privileged instructions.
+    Ecall,
+    Ebreak,
+    Sret,
+    Mret,
+    Wfi,
+    SfenceVma { rs1: usize, rs2: usize },
+}
+
+impl Instruction {
+    /// The mnemonic, e.g. "addi".
+    pub fn name(&self) -> &'static str {
+        use Instruction::*;
+        match self {
+            Lb { .. } => "lb",
+            Lh { .. } => "lh",
+            Lw { .. } => "lw",
+            Ld { .. } => "ld",
+            Lbu { .. } => "lbu",
+            Lhu { .. } => "lhu",
+            Lwu { .. } => "lwu",
+            Fence => "fence",
+            FenceI => "fence.i",
+            Addi { .. } => "addi",
+            Slli { .. } => "slli",
+            Slti { .. } => "slti",
+            Sltiu { .. } => "sltiu",
+            Xori { .. } => "xori",
+            Srli { .. } => "srli",
+            Srai { .. } => "srai",
+            Ori { .. } => "ori",
+            Andi { .. } => "andi",
+            Auipc { .. } => "auipc",
+            Addiw { .. } => "addiw",
+            Slliw { .. } => "slliw",
+            Srliw { .. } => "srliw",
+            Sraiw { .. } => "sraiw",
+            Sb { .. } => "sb",
+            Sh { .. } => "sh",
+            Sw { .. } => "sw",
+            Sd { .. } => "sd",
+            Add { .. } => "add",
+            Sub { .. } => "sub",
+            Sll { .. } => "sll",
+            Slt { .. } => "slt",
+            Sltu { .. } => "sltu",
+            Xor { .. } => "xor",
+            Srl { .. } => "srl",
+            Sra { .. } => "sra",
+            Or { .. } => "or",
+            And { .. } => "and",
+            Lui { .. } => "lui",
+            Addw { .. } => "addw",
+            Subw { .. } => "subw",
+            Sllw { .. } => "sllw",
+            Srlw { .. } => "srlw",
+            Sraw { .. } => "sraw",
+            Beq { .. } => "beq",
+            Bne { .. } => "bne",
+            Blt { .. } => "blt",
+            Bge { .. } => "bge",
+            Bltu { .. } => "bltu",
+            Bgeu { .. } => "bgeu",
+            Jalr { .. } => "jalr",
+            Jal { .. } => "jal",
+            Mul { .. } => "mul",
+            Divw { .. } => "divw",
+            Divuw { .. } => "divuw",
+            Remw { .. } => "remw",
+            Remuw { .. } => "remuw",
+            AmoaddW { .. } => "amoadd.w",
+            AmoaddD { .. } => "amoadd.d",
+            AmoswapW { .. } => "amoswap.w",
+            AmoswapD { .. } => "amoswap.d",
+            Csrrw { .. } => "csrrw",
+            Csrrs { .. } => "csrrs",
+            Csrrc { .. } => "csrrc",
+            Csrrwi { .. } => "csrrwi",
+            Csrrsi { .. } => "csrrsi",
+            Csrrci { .. } => "csrrci",
+            Ecall => "ecall",
+            Ebreak => "ebreak",
+            Sret => "sret",
+            Mret => "mret",
+            Wfi => "wfi",
+            SfenceVma { .. } => "sfence.vma",
+        }
+    }
+
+    /// The format of the encoding. The system instructions other than sfence.vma are I-type.
+    pub fn format(&self) -> Format {
+        use Instruction::*;
+        match self {
+            Auipc { .. } | Lui { .. } => Format::U,
+            Jal { .. } => Format::J,
+            Sb { .. } | Sh { .. } | Sw { .. } | Sd { .. } => Format::S,
+            Beq { .. } | Bne { .. } | Blt { .. } | Bge { .. } | Bltu { .. } | Bgeu { .. } => Format::B,
+            Add { .. } | Sub { .. } | Sll { .. } | Slt { .. } | Sltu { .. } | Xor { .. } | Srl { .. }
+            | Sra { .. } | Or { .. } | And { .. } | Addw { .. } | Subw { .. } | Sllw { .. }
+            | Srlw { .. } | Sraw { .. } | Mul { .. } | Divw { .. } | Divuw { .. } | Remw { .. }
+            | Remuw { .. } | AmoaddW { .. } | AmoaddD { .. } | AmoswapW { .. } | AmoswapD { .. }
+            | SfenceVma { .. } => Format::R,
+            _ => Format::I,
+        }
+    }
+}
+
+/// Decode the 32-bit instruction `inst`. A compressed instruction has to be expanded by
+/// `rvc::expand` first. Whether its extension is enabled is up to the cpu.
+pub fn decode(inst: u64) -> Result<Instruction, Exception> {
+    use Instruction::*;
+    let rd = ((inst >> 7) & 0x1f) as usize;
+    let rs1 = ((inst >> 15) & 0x1f) as usize;
+    let rs2 = ((inst >> 20) & 0x1f) as usize;
+    let funct3 = (inst >> 12) & 0x7;
+    let funct7 = (inst >> 25) & 0x7f;
+    let imm = decode_i_imm(inst);
+    // "The shift amount is encoded in the lower 6 bits of the I-immediate field for RV64I."
+    let shamt = (imm & 0x3f) as u32;
+    let csr = ((inst >> 20) & 0xfff) as usize;
+    let illegal = Err(Exception::IllegalInstruction(inst));
+
+    Ok(match (inst & 0x7f, funct3) {
+        (0x03, 0x0) => Lb { rd, rs1, imm },
+        (0x03, 0x1) => Lh { rd, rs1, imm },
+        (0x03, 0x2) => Lw { rd, rs1, imm },
+        (0x03, 0x3) => Ld { rd, rs1, imm },
+        (0x03, 0x4) => Lbu { rd, rs1, imm },
+        (0x03, 0x5) => Lhu { rd, rs1, imm },
+        (0x03, 0x6) => Lwu { rd, rs1, imm },
+        (0x0f, 0x0) => Fence,
+        (0x0f, 0x1) => FenceI,
+        (0x13, 0x0) => Addi { rd, rs1, imm },
+        (0x13, 0x1) if funct7 >> 1 == 0x00 => Slli { rd, rs1, shamt },
+        (0x13, 0x2) => Slti { rd, rs1, imm },
+        (0x13, 0x3) => Sltiu { rd, rs1, imm },
+        (0x13, 0x4) => Xori { rd, rs1, imm },
+        (0x13, 0x5) if funct7 >> 1 == 0x00 => Srli { rd, rs1, shamt },
+        (0x13, 0x5) if funct7 >> 1 == 0x10 => Srai { rd, rs1, shamt },
+        (0x13, 0x6) => Ori { rd, rs1, imm },
+        (0x13, 0x7) => Andi { rd, rs1, imm },
+        (0x17, _) => Auipc { rd, imm: decode_u_imm(inst) },
+        (0x1b, 0x0) => Addiw { rd, rs1, imm },
+        // "SLLIW, SRLIW, and SRAIW encodings with imm[5] ̸= 0 are reserved."
+        (0x1b, 0x1) if funct7 == 0x00 => Slliw { rd, rs1, shamt },
+        (0x1b, 0x5) if funct7 == 0x00 => Srliw { rd, rs1, shamt },
+        (0x1b, 0x5) if funct7 == 0x20 => Sraiw { rd, rs1, shamt: shamt & 0x1f },
+        (0x23, 0x0) => Sb { rs1, rs2, imm: decode_s_imm(inst) },
+        (0x23, 0x1) => Sh { rs1, rs2, imm: decode_s_imm(inst) },
+        (0x23, 0x2) => Sw { rs1, rs2, imm: decode_s_imm(inst) },
+        (0x23, 0x3) => Sd { rs1, rs2, imm: decode_s_imm(inst) },
+        (0x2f, 0x2) if funct7 >> 2 == 0x00 => AmoaddW { rd, rs1, rs2 },
+        (0x2f, 0x3) if funct7 >> 2 == 0x00 => AmoaddD { rd, rs1, rs2 },
+        (0x2f, 0x2) if funct7 >> 2 == 0x01 => AmoswapW { rd, rs1, rs2 },
+        (0x2f, 0x3) if funct7 >> 2 == 0x01 => AmoswapD { rd, rs1, rs2 },
+        (0x33, _) => match (funct3, funct7) {
+            (0x0, 0x00) => Add { rd, rs1, rs2 },
+            (0x0, 0x01) => Mul { rd, rs1, rs2 },
+            (0x0, 0x20) => Sub { rd, rs1, rs2 },
+            (0x1, 0x00) => Sll { rd, rs1, rs2 },
+            (0x2, 0x00) => Slt { rd, rs1, rs2 },
+            (0x3, 0x00) => Sltu { rd, rs1, rs2 },
+            (0x4, 0x00) => Xor { rd, rs1, rs2 },
+            (0x5, 0x00) => Srl { rd, rs1, rs2 },
+            (0x5, 0x20) => Sra { rd, rs1, rs2 },
+            (0x6, 0x00) => Or { rd, rs1, rs2 },
+            (0x7, 0x00) => And { rd, rs1, rs2 },
+            _ => return illegal,
+        },
+        (0x37, _) => Lui { rd, imm: decode_u_imm(inst) },
+        (0x3b, _) => match (funct3, funct7) {
+            (0x0, 0x00) => Addw { rd, rs1, rs2 },
+            (0x0, 0x20) => Subw { rd, rs1, rs2 },
+            (0x1, 0x00) => Sllw { rd, rs1, rs2 },
+            (0x5, 0x00) => Srlw { rd, rs1, rs2 },
+            (0x5, 0x20) => Sraw { rd, rs1, rs2 },
+            (0x4, 0x01) => Divw { rd, rs1, rs2 },
+            (0x5, 0x01) => Divuw { rd, rs1, rs2 },
+            (0x6, 0x01) => Remw { rd, rs1, rs2 },
+            (0x7, 0x01) => Remuw { rd, rs1, rs2 },
+            _ => return illegal,
+        },
+        (0x63, 0x0) => Beq { rs1, rs2, imm: decode_b_imm(inst) },
+        (0x63, 0x1) => Bne { rs1, rs2, imm: decode_b_imm(inst) },
+        (0x63, 0x4) => Blt { rs1, rs2, imm: decode_b_imm(inst) },
+        (0x63, 0x5) => Bge { rs1, rs2, imm: decode_b_imm(inst) },
+        (0x63, 0x6) => Bltu { rs1, rs2, imm: decode_b_imm(inst) },
+        (0x63, 0x7) => Bgeu { rs1, rs2, imm: decode_b_imm(inst) },
+        (0x67, _) => Jalr { rd, rs1, imm },
+        (0x6f, _) => Jal { rd, imm: decode_j_imm(inst) },
+        (0x73, 0x0) => match (rs2, funct7) {
+            (0x0, 0x00) => Ecall,
+            (0x1, 0x00) => Ebreak,
+            (0x2, 0x08) => Sret,
+            (0x2, 0x18) => Mret,
+            (0x5, 0x08) => Wfi,
+            (_, 0x09) => SfenceVma { rs1, rs2 },
+            _ => return illegal,
+        },
+        (0x73, 0x1) => Csrrw { rd, rs1, csr },
+        (0x73, 0x2) => Csrrs { rd, rs1, csr },
+        (0x73, 0x3) => Csrrc { rd, rs1, csr },
+        (0x73, 0x5) => Csrrwi { rd, uimm: rs1 as u64, csr },
+        (0x73, 0x6) => Csrrsi { rd, uimm: rs1 as u64, csr },
+        (0x73, 0x7) => Csrrci { rd, uimm: rs1 as u64, csr },
+        _ => return illegal,
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Instruction::*;
+
+    #[test]
+    fn test_decode_formats() {
+        let cases = [
+            // add a0, a1, a2
+            (0x00c58533, Add { rd: 10, rs1: 11, rs2: 12 }),
+            // addi a0, a0, -1
+            (0xfff50513, Addi { rd: 10, rs1: 10, imm: -1 }),
+            // srai a0, a1, 63
+            (0x43f5d513, Srai { rd: 10, rs1: 11, shamt: 63 }),
+            // sd a1, -8(sp)
+            (0xfeb13c23, Sd { rs1: 2, rs2: 11, imm: -8 }),
+            // beq a0, a1, -4
+            (0xfeb50ee3, Beq { rs1: 10, rs2: 11, imm: -4 }),
+            // lui a0, 0x80000
+            (0x80000537, Lui { rd: 10, imm: -0x8000_0000 }),
+            // jal ra, 2048
+            (0x001000ef, Jal { rd: 1, imm: 2048 }),
+            // csrrs a0, mstatus, zero
+            (0x30002573, Csrrs { rd: 10, rs1: 0, csr: 0x300 }),
+            // amoswap.w.aqrl a0, a2, (a1)
+            (0x0ec5a52f, AmoswapW { rd: 10, rs1: 11, rs2: 12 }),
+            (0x10500073, Wfi),
+        ];
+        for (inst, expected) in cases {
+            assert_eq!(decode(inst).ok(), Some(expected), "{:#x}", inst);
+        }
+        // A store with funct3=0x7, slliw with shamt[5] set and slli with imm[11:6] set are illegal.
+        for inst in [0x00b57023, 0x0215151b, 0x40051513] {
+            assert!(matches!(decode(inst), Err(Exception::IllegalInstruction(i)) if i == inst));
+        }
+    }
+}
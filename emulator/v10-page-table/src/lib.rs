@@ -0,0 +1,62 @@
+//! The emulator core. It is `no_std` (with `alloc`) unless the `std` feature, on by default, is
+//! enabled.
+//!
+//! With `std`, the bus has a uart on stdin/stdout, ELF executables can be loaded, `run` honours
+//! its timeout, and the `dump_*` functions print the machine state. The gdb stub also needs it.
+//! Without `std`, the decode/execute loop, the csrs, the dram and the other devices are the same,
+//! but there is no uart: an embedder registers its own console with `Cpu::register_device`.
+//! The tests need `std`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod bus;
+pub mod clint;
+pub mod cpu;
+pub mod dram;
+pub mod memory;
+pub mod plic;
+#[cfg(feature = "std")]
+pub mod uart;
+pub mod virtio;
+pub mod virtqueue;
+pub mod param;
+pub mod csr;
+pub mod exception;
+pub mod interrupt;
+#[cfg(feature = "std")]
+pub mod elf;
+pub mod finisher;
+pub mod rng;
+#[cfg(feature = "std")]
+pub mod gdb;
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod trace;
+pub mod rvc;
+pub mod decode;
+pub mod instruction;
+pub mod preview;
+#[cfg(test)]
+mod testutil;
+#[cfg(test)]
+mod coverage;
+
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::*;
+
+    #[test]
+    fn test_run_without_host() {
+        // addi a0, zero, 42, then report pass to the test finisher, which needs no host I/O.
+        let code: Vec<u8> = [0x02a00513u32, 0x001002b7, 0x00005337, 0x55530313, 0x0062a023]
+            .iter()
+            .flat_map(|inst| inst.to_le_bytes())
+            .collect();
+        let mut cpu = Cpu::new(code, vec![]);
+        assert!(matches!(cpu.run(Some(100), None).reason, StopReason::Halted(0)));
+        assert_eq!(cpu.reg("a0"), Ok(42));
+    }
+}
@@ -1,75 +1,144 @@
-mod bus;
-mod clint;
-mod cpu;
-mod dram;
-mod plic;
-mod uart;
-mod virtio;
-mod virtqueue;
-mod param;
-mod csr;
-mod exception;
-mod interrupt;
-
 use std::env;
-use std::fs::File;
-use std::io;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter};
 use std::io::prelude::*;
 
-use crate::cpu::*;
+use v10_page_tables::bus::Device;
+use v10_page_tables::cpu::*;
+use v10_page_tables::elf::is_elf;
+use v10_page_tables::gdb;
+use v10_page_tables::param::DTB_BASE;
+use v10_page_tables::virtio::VirtioBlock;
+
+/// Parse a hexadecimal number with a 0x prefix, or a decimal one.
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // `--gdb <port>` waits for gdb to connect instead of running the guest right away.
+    let mut gdb_port = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--gdb") {
+        let port = args.get(i + 1).and_then(|port| port.parse::<u16>().ok());
+        if port.is_none() {
+            panic!("Usage: --gdb <port>");
+        }
+        gdb_port = port;
+        args.drain(i..i + 2);
+    }
+
+    // `--dump <addr>:<len>` prints a hexdump of the dram after the run.
+    let mut dump = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--dump") {
+        let range = args.get(i + 1).and_then(|range| {
+            let (addr, len) = range.split_once(':')?;
+            Some((parse_number(addr)?, parse_number(len)?))
+        });
+        if range.is_none() {
+            panic!("Usage: --dump <addr>:<len>");
+        }
+        dump = range;
+        args.drain(i..i + 2);
+    }
+
+    // `--load-offset <offset>` loads a flat binary at DRAM_BASE + offset and starts there.
+    let mut load_offset = 0;
+    if let Some(i) = args.iter().position(|arg| arg == "--load-offset") {
+        match args.get(i + 1).and_then(|offset| parse_number(offset)) {
+            Some(offset) => load_offset = offset,
+            None => panic!("Usage: --load-offset <offset>"),
+        }
+        args.drain(i..i + 2);
+    }
 
-    if (args.len() != 2) && (args.len() != 3) {
-        panic!("Usage: rvemu-for-book <filename> <(option) image>");
+    // `--trace <path>` writes a binary record of every executed instruction to the file.
+    let mut trace_path = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--trace") {
+        if i + 1 >= args.len() {
+            panic!("Usage: --trace <path>");
+        }
+        trace_path = Some(args[i + 1].clone());
+        args.drain(i..i + 2);
+    }
+
+    // `--disk-file` reads and writes the image file on demand instead of loading it into memory.
+    let disk_file = match args.iter().position(|arg| arg == "--disk-file") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+
+    if args.len() < 2 || args.len() > 4 {
+        panic!("Usage: rvemu-for-book [--gdb <port>] [--dump <addr>:<len>] [--disk-file] [--load-offset <offset>] [--trace <path>] <filename> <(option) image> <(option) dtb>");
     }
     let mut file = File::open(&args[1])?;
     let mut binary = Vec::new();
     file.read_to_end(&mut binary)?;
 
     let mut disk_image = Vec::new();
-    if args.len() == 3 {
+    if args.len() >= 3 && !disk_file {
         let mut file = File::open(&args[2])?;
         file.read_to_end(&mut disk_image)?;
     }
 
-    let mut cpu = Cpu::new(binary, disk_image);
-
-    loop {
-        let inst = match cpu.fetch() {
-            // Break the loop if an error occurs.
-            Ok(inst) => inst,
-            Err(e) => {
-                cpu.handle_exception(e);
-                if e.is_fatal() {
-                    println!("{}", e);
-                    break;
-                }
-                continue;
-            }
-        };
-
-        match cpu.execute(inst) {
-            // Break the loop if an error occurs.
-            Ok(new_pc) => cpu.pc = new_pc,
-            Err(e) => {
-                cpu.handle_exception(e);
-                if e.is_fatal() {
-                    println!("{}", e);
-                    break;
-                }
-            }
-        };
+    // An ELF executable is loaded by its program headers, anything else is a flat binary
+    // starting at DRAM_BASE.
+    let mut cpu = if is_elf(&binary) {
+        Cpu::from_elf(&binary, disk_image)?
+    } else {
+        CpuBuilder::new(binary).disk_image(disk_image).load_offset(load_offset).build()
+    };
 
-        match cpu.check_pending_interrupt() {
-            Some(interrupt) => cpu.handle_interrupt(interrupt),
-            None => (),
+    if args.len() >= 3 && disk_file {
+        let file = OpenOptions::new().read(true).write(true).open(&args[2])?;
+        let base = cpu.bus.virtio_blk().base();
+        *cpu.bus.virtio_blk() = VirtioBlock::from_file(base, file)?;
+    }
+
+    if args.len() == 4 {
+        let mut file = File::open(&args[3])?;
+        let mut dtb = Vec::new();
+        file.read_to_end(&mut dtb)?;
+        if let Err(e) = cpu.load_dtb(&dtb, DTB_BASE) {
+            panic!("Cannot load the device tree blob: {}", e);
         }
     }
+
+    if let Some(path) = trace_path {
+        cpu.trace_file = Some(BufWriter::new(File::create(path)?));
+    }
+
+    match gdb_port {
+        Some(port) => gdb::listen(&mut cpu, port)?,
+        None => {
+            match cpu.run(None, None).reason {
+                StopReason::FatalException(e) => println!("{}", e),
+                StopReason::DeviceError(e) => println!("Disk error: {}", e),
+                _ => (),
+            }
+        }
+    }
+    if let Some(mut trace) = cpu.trace_file.take() {
+        trace.flush()?;
+    }
     cpu.dump_registers();
     cpu.dump_csrs();
     cpu.dump_pc();
+    cpu.dump_mode();
+    cpu.dump_stats();
+    if let Some((addr, len)) = dump {
+        print!("{}", cpu.hexdump(addr, len));
+    }
 
+    if let Some(code) = cpu.exit_code {
+        std::process::exit(code as i32);
+    }
     Ok(())
 }
@@ -0,0 +1,177 @@
+//! The memory module contains the `Memory` trait, which backs the physical range
+//! DRAM_BASE..=DRAM_END on the bus, and `SparseMemory`, a backing that only allocates the pages
+//! that are written. `Dram` is the default backing.
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
+
+use crate::param::{DRAM_BASE, DRAM_END, DRAM_SIZE, PAGE_SIZE};
+use crate::exception::Exception;
+
+use Exception::*;
+
+/// The backing of the dram. The bus calls `load` and `store` for an address in
+/// DRAM_BASE..=DRAM_END, with a size of 8, 16, 32 or 64 bits.
+pub trait Memory {
+    fn load(&self, addr: u64, size: u64) -> Result<u64, Exception>;
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception>;
+    /// Copy the bytes at `addr` into `buf`.
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception>;
+    /// Copy `buf` to the bytes at `addr`.
+    fn write(&mut self, addr: u64, buf: &[u8]) -> Result<(), Exception>;
+    /// Append the contents to `data`, prefixed by their length as a little-endian u64.
+    fn snapshot(&self, data: &mut Vec<u8>);
+    /// Restore the contents from `data` produced by `snapshot`.
+    fn restore(&mut self, data: &[u8]);
+    /// Make a load fail with an access fault if it reads a byte that has not been written since.
+    /// The bytes written before count as uninitialized.
+    fn detect_uninit(&mut self, enable: bool);
+}
+
+const PAGE: usize = PAGE_SIZE as usize;
+
+/// A memory that allocates a page on its first write, so that the dram only costs the host the
+/// pages in use. A byte that was never written reads as 0. A snapshot has the same layout as the
+/// one of `Dram`, so it can be restored into either.
+#[derive(Default)]
+pub struct SparseMemory {
+    /// The pages by physical page number.
+    pages: HashMap<u64, [u8; PAGE]>,
+    /// One bit per byte of the pages that have been written since `detect_uninit` was enabled, or
+    /// None if it is disabled.
+    written: Option<HashMap<u64, [u64; PAGE / 64]>>,
+}
+
+impl SparseMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return true if the `len` bytes at `addr` are all in the dram.
+    fn contains(addr: u64, len: u64) -> bool {
+        addr >= DRAM_BASE && addr.checked_add(len).is_some_and(|end| end <= DRAM_END + 1)
+    }
+
+    fn byte(&self, addr: u64) -> u8 {
+        self.pages.get(&(addr / PAGE_SIZE)).map_or(0, |page| page[(addr % PAGE_SIZE) as usize])
+    }
+
+    fn set_byte(&mut self, addr: u64, value: u8) {
+        let (number, offset) = (addr / PAGE_SIZE, (addr % PAGE_SIZE) as usize);
+        self.pages.entry(number).or_insert([0; PAGE])[offset] = value;
+        if let Some(written) = &mut self.written {
+            written.entry(number).or_insert([0; PAGE / 64])[offset / 64] |= 1 << (offset % 64);
+        }
+    }
+
+    fn is_written(&self, addr: u64) -> bool {
+        let (number, offset) = (addr / PAGE_SIZE, (addr % PAGE_SIZE) as usize);
+        match &self.written {
+            Some(written) => written.get(&number).is_some_and(|bits| bits[offset / 64] & (1 << (offset % 64)) != 0),
+            None => true,
+        }
+    }
+}
+
+impl Memory for SparseMemory {
+    fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let nbytes = size / 8;
+        if ![8, 16, 32, 64].contains(&size)
+            || !Self::contains(addr, nbytes)
+            || !(addr..addr + nbytes).all(|a| self.is_written(a))
+        {
+            return Err(LoadAccessFault(addr));
+        }
+        Ok((0..nbytes).fold(0, |value, i| value | (self.byte(addr + i) as u64) << (i * 8)))
+    }
+
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let nbytes = size / 8;
+        if ![8, 16, 32, 64].contains(&size) || !Self::contains(addr, nbytes) {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        for i in 0..nbytes {
+            self.set_byte(addr + i, (value >> (i * 8)) as u8);
+        }
+        Ok(())
+    }
+
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        if !Self::contains(addr, buf.len() as u64) {
+            return Err(LoadAccessFault(addr));
+        }
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.byte(addr + i as u64);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u64, buf: &[u8]) -> Result<(), Exception> {
+        if !Self::contains(addr, buf.len() as u64) {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        for (i, &byte) in buf.iter().enumerate() {
+            self.set_byte(addr + i as u64, byte);
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&DRAM_SIZE.to_le_bytes());
+        for number in DRAM_BASE / PAGE_SIZE..=DRAM_END / PAGE_SIZE {
+            data.extend_from_slice(self.pages.get(&number).unwrap_or(&[0; PAGE]));
+        }
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        assert!(data.len() >= 8, "Invalid dram snapshot");
+        let (len, contents) = data.split_at(8);
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        assert!(len == DRAM_SIZE && len == contents.len() as u64, "Invalid dram snapshot");
+        // A page of zeros reads the same whether it is allocated or not.
+        self.pages.clear();
+        for (i, page) in contents.chunks_exact(PAGE).enumerate() {
+            if page.iter().any(|&byte| byte != 0) {
+                self.pages.insert(DRAM_BASE / PAGE_SIZE + i as u64, page.try_into().unwrap());
+            }
+        }
+        // Like a dram, every restored byte counts as written.
+        if let Some(written) = &mut self.written {
+            for number in DRAM_BASE / PAGE_SIZE..=DRAM_END / PAGE_SIZE {
+                written.insert(number, [!0; PAGE / 64]);
+            }
+        }
+    }
+
+    fn detect_uninit(&mut self, enable: bool) {
+        self.written = enable.then(HashMap::new);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sparse_memory() {
+        let mut memory = SparseMemory::new();
+        // A store across a page boundary allocates both pages, the rest read as 0.
+        memory.store(DRAM_BASE + 0x1ffc, 64, 0x1122334455667788).unwrap();
+        assert_eq!(memory.pages.len(), 2);
+        assert!(matches!(memory.load(DRAM_BASE + 0x1ffc, 64), Ok(0x1122334455667788)));
+        assert!(matches!(memory.load(DRAM_BASE + 0x2000, 32), Ok(0x11223344)));
+        assert!(matches!(memory.load(DRAM_END - 7, 64), Ok(0)));
+        // An access that runs past the dram faults.
+        assert!(matches!(memory.load(DRAM_END - 3, 64), Err(LoadAccessFault(_))));
+        assert!(matches!(memory.store(DRAM_END, 16, 0), Err(StoreAMOAccessFault(_))));
+
+        memory.detect_uninit(true);
+        assert!(matches!(memory.load(DRAM_BASE, 8), Err(LoadAccessFault(_))));
+        memory.write(DRAM_BASE, &[1, 2]).unwrap();
+        assert!(matches!(memory.load(DRAM_BASE, 16), Ok(0x0201)));
+        assert!(matches!(memory.load(DRAM_BASE, 32), Err(LoadAccessFault(_))));
+    }
+}
@@ -3,6 +3,19 @@
 pub const DRAM_BASE: u64 = 0x8000_0000;
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 pub const DRAM_END: u64 = DRAM_SIZE + DRAM_BASE - 1;
+// The default address of a device tree blob, 2 MiB below the end of the dram so that it stays
+// clear of the initial stack at DRAM_END.
+pub const DTB_BASE: u64 = DRAM_END + 1 - 0x20_0000;
+
+// The SiFive test finisher. A guest stores to it to report pass or fail and power off.
+pub const TEST_FINISHER_BASE: u64 = 0x10_0000;
+pub const TEST_FINISHER_SIZE: u64 = 0x1000;
+pub const FINISHER_PASS: u64 = 0x5555;
+pub const FINISHER_FAIL: u64 = 0x3333;
+
+// A seeded pseudo-random number generator. A guest loads from it to get the next value.
+pub const RNG_BASE: u64 = 0x10_1000;
+pub const RNG_SIZE: u64 = 0x1000;
 
 // The address which the core-local interruptor (CLINT) starts. It contains the timer and
 // generates per-hart software interrupts and timer interrupts.
@@ -10,8 +23,13 @@ pub const CLINT_BASE: u64 = 0x200_0000;
 pub const CLINT_SIZE: u64 = 0x10000;
 pub const CLINT_END: u64 = CLINT_BASE + CLINT_SIZE - 1;
 
+// The maximum number of harts. Each hart has a 4-byte software interrupt register (msip) in the CLINT.
+pub const MAX_HARTS: usize = 8;
+pub const CLINT_MSIP: u64 = CLINT_BASE;
 pub const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
 pub const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
+// The frequency of mtime in Hz, the timebase-frequency of the device tree. It is the same as QEMU.
+pub const TIMEBASE_FREQ: u64 = 10_000_000;
 
 // The address which the platform-level interrupt controller (PLIC) starts. The PLIC connects all external interrupts in the
 // system to all hart contexts in the system, via the external interrupt source in each hart.
@@ -19,10 +37,18 @@ pub const PLIC_BASE: u64 = 0xc00_0000;
 pub const PLIC_SIZE: u64 = 0x4000000;
 pub const PLIC_END: u64 = PLIC_BASE + PLIC_SIZE - 1;
 
+// The PLIC has a priority register per interrupt source and, per context, an enable bitmap, a
+// priority threshold and a claim/complete register. Hart n has context 2n for M-mode and 2n+1 for
+// S-mode, like QEMU.
+pub const PLIC_NUM_SOURCES: usize = 32;
+pub const PLIC_NUM_CONTEXTS: usize = 2 * MAX_HARTS;
+pub const PLIC_PRIORITY: u64 = PLIC_BASE;
 pub const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
-pub const PLIC_SENABLE: u64 = PLIC_BASE + 0x2000;
-pub const PLIC_SPRIORITY: u64 = PLIC_BASE + 0x201000;
-pub const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
+pub const PLIC_ENABLE: u64 = PLIC_BASE + 0x2000;
+pub const PLIC_ENABLE_STRIDE: u64 = 0x80;
+pub const PLIC_THRESHOLD: u64 = PLIC_BASE + 0x200000;
+pub const PLIC_CLAIM: u64 = PLIC_BASE + 0x200004;
+pub const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
 
 // UART
 pub const UART_BASE: u64 = 0x1000_0000;
@@ -4,6 +4,9 @@
 //! It's the global interrupt controller in a RISC-V system.
 
 use crate::param::*;
+use alloc::vec::Vec;
+
+use crate::bus::{put_word, Device, SnapshotReader};
 use crate::exception::Exception;
 
 use Exception::*;
@@ -11,40 +14,216 @@ use Exception::*;
 
 
 pub struct Plic {
-    pending: u64,
-    senable: u64,
-    spriority: u64,
-    sclaim: u64,
+    base: u64,
+    /// The priority of each interrupt source. Source 0 does not exist.
+    priority: [u32; PLIC_NUM_SOURCES],
+    /// One bit per source that is waiting to be claimed.
+    pending: u32,
+    /// One bit per source that has been claimed but not completed. It is masked until completed.
+    claimed: u32,
+    /// The sources each context is interested in.
+    enable: [u32; PLIC_NUM_CONTEXTS],
+    /// A context is only interrupted by a source whose priority is greater than its threshold.
+    threshold: [u32; PLIC_NUM_CONTEXTS],
 }
 
 impl Plic {
-    pub fn new() -> Self {
-        Self {pending: 0, senable: 0, spriority: 0, sclaim: 0}
+    /// Create a PLIC at `base`. The default address is `PLIC_BASE`.
+    pub fn new(base: u64) -> Self {
+        Self {
+            base,
+            priority: [0; PLIC_NUM_SOURCES],
+            pending: 0,
+            claimed: 0,
+            enable: [0; PLIC_NUM_CONTEXTS],
+            threshold: [0; PLIC_NUM_CONTEXTS],
+        }
+    }
+
+    /// Mark the interrupt source `irq` as pending.
+    pub fn raise(&mut self, irq: u64) {
+        self.pending |= 1 << irq;
+    }
+
+    /// Return true if `context` has an interrupt to claim.
+    pub fn is_interrupting(&self, context: usize) -> bool {
+        self.best(context) != 0
+    }
+
+    /// The pending, enabled and unclaimed source with the highest priority above the threshold of
+    /// `context`. Ties go to the lowest source id, and 0 means there is none.
+    fn best(&self, context: usize) -> u32 {
+        let candidates = self.pending & self.enable[context] & !self.claimed;
+        if candidates == 0 {
+            return 0;
+        }
+        let mut best = 0;
+        let mut max_priority = self.threshold[context];
+        for irq in 1..PLIC_NUM_SOURCES {
+            if candidates & (1 << irq) != 0 && self.priority[irq] > max_priority {
+                best = irq as u32;
+                max_priority = self.priority[irq];
+            }
+        }
+        best
+    }
+
+    /// Claim the best interrupt of `context`. It stays masked until it is completed.
+    fn claim(&mut self, context: usize) -> u32 {
+        let irq = self.best(context);
+        if irq != 0 {
+            self.pending &= !(1 << irq);
+            self.claimed |= 1 << irq;
+        }
+        irq
+    }
+
+    fn complete(&mut self, irq: u32) {
+        if (irq as usize) < PLIC_NUM_SOURCES {
+            self.claimed &= !(1 << irq);
+        }
+    }
+}
+
+/// The context whose enable bitmap is at `addr`.
+fn enable_context(addr: u64) -> Option<usize> {
+    let offset = addr.checked_sub(PLIC_ENABLE)?;
+    let context = (offset / PLIC_ENABLE_STRIDE) as usize;
+    match offset % PLIC_ENABLE_STRIDE {
+        0 if context < PLIC_NUM_CONTEXTS => Some(context),
+        _ => None,
+    }
+}
+
+/// The context and the register offset (threshold or claim) of `addr`.
+fn context_register(addr: u64) -> Option<(usize, u64)> {
+    let offset = addr.checked_sub(PLIC_THRESHOLD)?;
+    let context = (offset / PLIC_CONTEXT_STRIDE) as usize;
+    match PLIC_THRESHOLD + offset % PLIC_CONTEXT_STRIDE {
+        reg @ (PLIC_THRESHOLD | PLIC_CLAIM) if context < PLIC_NUM_CONTEXTS => Some((context, reg)),
+        _ => None,
+    }
+}
+
+impl Device for Plic {
+    fn base(&self) -> u64 {
+        self.base
+    }
+
+    fn size(&self) -> u64 {
+        PLIC_SIZE
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         if size != 32 {
             return Err(LoadAccessFault(addr));
         }
-        match addr {
-            PLIC_PENDING => Ok(self.pending),
-            PLIC_SENABLE => Ok(self.senable),
-            PLIC_SPRIORITY => Ok(self.spriority),
-            PLIC_SCLAIM => Ok(self.sclaim),
-            _ => Ok(0),
+        // The register constants name the addresses in the default map.
+        let addr = addr - self.base + PLIC_BASE;
+        if addr < PLIC_PRIORITY + 4 * PLIC_NUM_SOURCES as u64 {
+            return Ok(self.priority[((addr - PLIC_PRIORITY) / 4) as usize] as u64);
+        }
+        if addr == PLIC_PENDING {
+            return Ok(self.pending as u64);
+        }
+        if let Some(context) = enable_context(addr) {
+            return Ok(self.enable[context] as u64);
+        }
+        match context_register(addr) {
+            Some((context, PLIC_THRESHOLD)) => Ok(self.threshold[context] as u64),
+            Some((context, _)) => Ok(self.claim(context) as u64),
+            None => Ok(0),
         }
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if size != 32 {
             return Err(StoreAMOAccessFault(addr));
         }
-        match addr {
-            PLIC_PENDING => Ok(self.pending = value),
-            PLIC_SENABLE => Ok(self.senable = value),
-            PLIC_SPRIORITY => Ok(self.spriority = value),
-            PLIC_SCLAIM => Ok(self.sclaim = value),
-            _ => Ok(()),
+        let addr = addr - self.base + PLIC_BASE;
+        let value = value as u32;
+        if addr < PLIC_PRIORITY + 4 * PLIC_NUM_SOURCES as u64 {
+            return Ok(self.priority[((addr - PLIC_PRIORITY) / 4) as usize] = value);
+        }
+        // The pending bits are read-only, they are only set by devices.
+        if let Some(context) = enable_context(addr) {
+            return Ok(self.enable[context] = value);
+        }
+        match context_register(addr) {
+            Some((context, PLIC_THRESHOLD)) => Ok(self.threshold[context] = value),
+            Some((_, _)) => Ok(self.complete(value)),
+            None => Ok(()),
+        }
+    }
+
+    fn snapshot(&self, data: &mut Vec<u8>) {
+        let state = [self.pending, self.claimed];
+        let words = self.priority.iter().chain(state.iter()).chain(self.enable.iter()).chain(self.threshold.iter());
+        for &word in words {
+            put_word(data, word as u64);
+        }
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let mut reader = SnapshotReader::new(data);
+        for priority in self.priority.iter_mut() {
+            *priority = reader.word() as u32;
+        }
+        self.pending = reader.word() as u32;
+        self.claimed = reader.word() as u32;
+        for word in self.enable.iter_mut().chain(self.threshold.iter_mut()) {
+            *word = reader.word() as u32;
         }
     }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.base);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // hart 0, S-mode.
+    const CONTEXT: u64 = 1;
+
+    fn claim(plic: &mut Plic) -> u64 {
+        plic.load(PLIC_CLAIM + CONTEXT * PLIC_CONTEXT_STRIDE, 32).unwrap()
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut plic = Plic::new(PLIC_BASE);
+        plic.store(PLIC_PRIORITY + 4 * VIRTIO_IRQ, 32, 1).unwrap();
+        plic.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 5).unwrap();
+        let enable = (1 << VIRTIO_IRQ) | (1 << UART_IRQ);
+        plic.store(PLIC_ENABLE + CONTEXT * PLIC_ENABLE_STRIDE, 32, enable).unwrap();
+        plic.raise(VIRTIO_IRQ);
+        plic.raise(UART_IRQ);
+        assert!(plic.is_interrupting(CONTEXT as usize));
+        assert!(!plic.is_interrupting(0));
+
+        // The uart has the higher priority, and stays masked after it is claimed.
+        assert_eq!(claim(&mut plic), UART_IRQ);
+        plic.raise(UART_IRQ);
+        assert_eq!(claim(&mut plic), VIRTIO_IRQ);
+        assert_eq!(claim(&mut plic), 0);
+        plic.store(PLIC_CLAIM + CONTEXT * PLIC_CONTEXT_STRIDE, 32, UART_IRQ).unwrap();
+        assert_eq!(claim(&mut plic), UART_IRQ);
+    }
+
+    #[test]
+    fn test_threshold() {
+        let mut plic = Plic::new(PLIC_BASE);
+        plic.store(PLIC_PRIORITY + 4 * UART_IRQ, 32, 5).unwrap();
+        plic.store(PLIC_ENABLE + CONTEXT * PLIC_ENABLE_STRIDE, 32, 1 << UART_IRQ).unwrap();
+        plic.store(PLIC_THRESHOLD + CONTEXT * PLIC_CONTEXT_STRIDE, 32, 5).unwrap();
+        plic.raise(UART_IRQ);
+        assert!(!plic.is_interrupting(CONTEXT as usize));
+        assert_eq!(claim(&mut plic), 0);
+        plic.store(PLIC_THRESHOLD + CONTEXT * PLIC_CONTEXT_STRIDE, 32, 4).unwrap();
+        assert_eq!(claim(&mut plic), UART_IRQ);
+    }
 }
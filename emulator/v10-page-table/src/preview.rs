@@ -0,0 +1,157 @@
+//! The preview module decodes an instruction into the state it would read and write, without
+//! executing it. It is meant for analysis tools, e.g. a disassembler that follows both sides of a
+//! branch before the program gets there.
+
+use alloc::{vec, vec::Vec};
+
+use crate::cpu::*;
+use crate::csr::*;
+use crate::decode::Format;
+use crate::instruction::{self, Instruction};
+use crate::rvc;
+
+/// A memory access of `size` bits at the virtual address `addr`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemAccess {
+    pub addr: u64,
+    pub size: u64,
+    pub write: bool,
+}
+
+/// The effect an instruction would have if it were executed at the current pc.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreviewResult {
+    /// The registers read, in operand order.
+    pub reads: Vec<usize>,
+    /// The register written. Writes to x0 are not reported.
+    pub write: Option<usize>,
+    /// The csr read or written.
+    pub csr: Option<usize>,
+    /// The memory access of a load, store or AMO.
+    pub mem: Option<MemAccess>,
+    /// The pc of the next instruction, or None if the instruction would raise an exception, e.g.
+    /// it is illegal, an `ecall`, or a jump to a misaligned target.
+    pub next_pc: Option<u64>,
+}
+
+impl Cpu {
+    /// Report what `inst` would do at the current pc, without changing any state. An instruction
+    /// that `execute` would reject as illegal reports nothing. Memory accesses are reported by
+    /// virtual address, since translating them could fault.
+    pub fn preview(&self, inst: u64) -> PreviewResult {
+        let (inst, len) = if inst & 0b11 != 0b11 {
+            match rvc::expand(inst as u16) {
+                Some(expanded) if self.has_extension(EXT_C) => (expanded as u64, 2),
+                _ => return PreviewResult::default(),
+            }
+        } else {
+            (inst & 0xffff_ffff, 4)
+        };
+        if !self.has_extension(required_extension(inst)) {
+            return PreviewResult::default();
+        }
+        let Ok(instruction) = instruction::decode(inst) else {
+            return PreviewResult::default();
+        };
+
+        let rd = ((inst & 0x00000f80) >> 7) as usize;
+        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
+        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
+        // x0 may hold a value written through `regs`.
+        let reg = |i: usize| if i == 0 { 0 } else { self.regs[i] };
+        let next = self.pc.wrapping_add(len);
+        let load = |imm: i64, size: u64| MemAccess { addr: reg(rs1).wrapping_add(imm as u64), size, write: false };
+        let store = |imm: i64, size: u64| MemAccess { addr: reg(rs1).wrapping_add(imm as u64), size, write: true };
+        let branch = |taken: bool, imm: i64| {
+            if taken { self.jump_target(self.pc.wrapping_add(imm as u64)).ok() } else { Some(next) }
+        };
+
+        use Instruction::*;
+        // The operands follow the format, except for the instructions that only change the state
+        // of the hart and the csr instructions with an immediate.
+        let (reads, write) = match instruction {
+            Fence | FenceI | Ecall | Ebreak | Sret | Mret | Wfi => (vec![], false),
+            SfenceVma { .. } => (vec![rs1, rs2], false),
+            Csrrwi { .. } | Csrrsi { .. } | Csrrci { .. } => (vec![], true),
+            _ => match instruction.format() {
+                Format::R => (vec![rs1, rs2], true),
+                Format::I => (vec![rs1], true),
+                Format::S | Format::B => (vec![rs1, rs2], false),
+                Format::U | Format::J => (vec![], true),
+            },
+        };
+        let write = Some(rd).filter(|&rd| write && rd != 0);
+        let mut result = PreviewResult { reads, write, next_pc: Some(next), ..Default::default() };
+        match instruction {
+            Lb { imm, .. } | Lbu { imm, .. } => result.mem = Some(load(imm, 8)),
+            Lh { imm, .. } | Lhu { imm, .. } => result.mem = Some(load(imm, 16)),
+            Lw { imm, .. } | Lwu { imm, .. } => result.mem = Some(load(imm, 32)),
+            Ld { imm, .. } => result.mem = Some(load(imm, 64)),
+            Sb { imm, .. } => result.mem = Some(store(imm, 8)),
+            Sh { imm, .. } => result.mem = Some(store(imm, 16)),
+            Sw { imm, .. } => result.mem = Some(store(imm, 32)),
+            Sd { imm, .. } => result.mem = Some(store(imm, 64)),
+            AmoaddW { .. } | AmoswapW { .. } | AmoaddD { .. } | AmoswapD { .. } => {
+                let size = if matches!(instruction, AmoaddW { .. } | AmoswapW { .. }) { 32 } else { 64 };
+                let addr = reg(rs1);
+                result.mem = Some(MemAccess { addr, size, write: true });
+                result.next_pc = (addr % (size / 8) == 0).then_some(next);
+            }
+            Beq { imm, .. } => result.next_pc = branch(reg(rs1) == reg(rs2), imm),
+            Bne { imm, .. } => result.next_pc = branch(reg(rs1) != reg(rs2), imm),
+            Blt { imm, .. } => result.next_pc = branch((reg(rs1) as i64) < (reg(rs2) as i64), imm),
+            Bge { imm, .. } => result.next_pc = branch((reg(rs1) as i64) >= (reg(rs2) as i64), imm),
+            Bltu { imm, .. } => result.next_pc = branch(reg(rs1) < reg(rs2), imm),
+            Bgeu { imm, .. } => result.next_pc = branch(reg(rs1) >= reg(rs2), imm),
+            Jalr { imm, .. } => result.next_pc = self.jump_target(reg(rs1).wrapping_add(imm as u64) & !1).ok(),
+            Jal { imm, .. } => result.next_pc = self.jump_target(self.pc.wrapping_add(imm as u64)).ok(),
+            Csrrw { csr, .. } | Csrrs { csr, .. } | Csrrc { csr, .. } | Csrrwi { csr, .. }
+            | Csrrsi { csr, .. } | Csrrci { csr, .. } => result.csr = Some(csr),
+            Sret => (result.csr, result.next_pc) = (Some(SEPC), Some(self.csr.load(SEPC) & self.epc_mask())),
+            Mret => (result.csr, result.next_pc) = (Some(MEPC), Some(self.csr.load(MEPC) & self.epc_mask())),
+            // ecall and ebreak trap, unless a breakpoint hook takes the ebreak.
+            Ecall | Ebreak => result.next_pc = None,
+            _ => (),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::param::*;
+
+    #[test]
+    fn test_preview_branch() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.regs[10] = 5;
+        cpu.regs[11] = 5;
+        // beq a0, a1, 16
+        let preview = cpu.preview(0x00b50863);
+        assert_eq!(preview.next_pc, Some(DRAM_BASE + 16));
+        assert_eq!(preview.reads, vec![10, 11]);
+        assert_eq!(preview.write, None);
+        assert_eq!(cpu.pc, DRAM_BASE);
+
+        cpu.regs[11] = 6;
+        assert_eq!(cpu.preview(0x00b50863).next_pc, Some(DRAM_BASE + 4));
+    }
+
+    #[test]
+    fn test_preview_memory() {
+        let mut cpu = Cpu::new(vec![], vec![]);
+        cpu.regs[2] = DRAM_BASE + 0x100;
+        // sd ra, 8(sp)
+        let preview = cpu.preview(0x00113423);
+        assert_eq!(preview.mem, Some(MemAccess { addr: DRAM_BASE + 0x108, size: 64, write: true }));
+        assert_eq!(preview.reads, vec![2, 1]);
+        // c.lwsp a0, 4(sp) => lw a0, 4(sp)
+        let preview = cpu.preview(0x4512);
+        assert_eq!(preview.mem, Some(MemAccess { addr: DRAM_BASE + 0x104, size: 32, write: false }));
+        assert_eq!((preview.write, preview.next_pc), (Some(10), Some(DRAM_BASE + 2)));
+        // ecall
+        assert_eq!(cpu.preview(0x00000073).next_pc, None);
+        assert_eq!(cpu.bus.load(DRAM_BASE + 0x108, 64).unwrap(), 0);
+    }
+}
@@ -0,0 +1,94 @@
+//! The rng module contains a pseudo-random number generator device. Every read returns the next
+//! value of a xorshift64 sequence, so a guest gets entropy while a run stays reproducible for a
+//! given seed.
+
+use alloc::vec::Vec;
+
+use crate::bus::*;
+use crate::exception::*;
+use crate::param::*;
+
+use Exception::*;
+
+/// The seed used until the host sets one. xorshift gets stuck at 0, so it is never the state.
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+pub struct Rng {
+    base: u64,
+    /// The seed that `reset` restarts the sequence from.
+    seed: u64,
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator at `base`. The default address is `RNG_BASE`.
+    pub fn new(base: u64) -> Self {
+        Self { base, seed: DEFAULT_SEED, state: DEFAULT_SEED }
+    }
+
+    /// Restart the sequence from `seed`. A seed of 0 is replaced by the default seed.
+    pub fn seed(&mut self, seed: u64) {
+        self.seed = if seed == 0 { DEFAULT_SEED } else { seed };
+        self.state = self.seed;
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+impl Device for Rng {
+    fn base(&self) -> u64 {
+        self.base
+    }
+
+    fn size(&self) -> u64 {
+        RNG_SIZE
+    }
+
+    /// A 32-bit read returns the low half of the next value.
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        match size {
+            32 => Ok(self.next() & 0xffff_ffff),
+            64 => Ok(self.next()),
+            _ => Err(LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+        Err(StoreAMOAccessFault(addr))
+    }
+
+    fn snapshot(&self, data: &mut Vec<u8>) {
+        put_word(data, self.state);
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        self.state = SnapshotReader::new(data).word();
+    }
+
+    fn reset(&mut self) {
+        self.state = self.seed;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rng_seed() {
+        let mut rng = Rng::new(RNG_BASE);
+        rng.seed(0);
+        let first = rng.load(RNG_BASE, 64).unwrap();
+        assert_ne!(first, 0);
+        rng.seed(DEFAULT_SEED);
+        assert_eq!(rng.load(RNG_BASE, 64).unwrap(), first);
+        assert!(rng.load(RNG_BASE, 8).is_err());
+        assert!(rng.store(RNG_BASE, 64, 1).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+//! The rvc module contains the decoder of the compressed (C) extension. Every 16-bit instruction
+//! is expanded to the 32-bit instruction it stands for, which is then executed as usual.
+//!
+//! See the chapter "C" Standard Extension for Compressed Instructions of the unprivileged spec,
+//! table 16.5 to 16.7 for the opcode map.
+
+/// Return the bits `hi..=lo` of `inst`, shifted down to bit 0.
+fn bits(inst: u16, hi: u32, lo: u32) -> u32 {
+    (inst as u32 >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Sign-extend the lowest `width` bits of `value`.
+fn sext(value: u32, width: u32) -> u32 {
+    let shift = 32 - width;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (((imm >> 5) & 0x7f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | opcode
+}
+
+fn b_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    (((imm >> 12) & 1) << 31)
+        | (((imm >> 5) & 0x3f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (((imm >> 1) & 0xf) << 8)
+        | (((imm >> 11) & 1) << 7)
+        | 0x63
+}
+
+fn j_type(imm: u32, rd: u32) -> u32 {
+    (((imm >> 20) & 1) << 31)
+        | (((imm >> 1) & 0x3ff) << 21)
+        | (((imm >> 11) & 1) << 20)
+        | (((imm >> 12) & 0xff) << 12)
+        | (rd << 7)
+        | 0x6f
+}
+
+/// Expand a compressed instruction to its 32-bit equivalent. Return None if it is illegal or
+/// reserved.
+pub fn expand(inst: u16) -> Option<u32> {
+    // rd'/rs1'/rs2' name x8-x15.
+    let rd_ = bits(inst, 4, 2) + 8;
+    let rs1_ = bits(inst, 9, 7) + 8;
+    let rd = bits(inst, 11, 7);
+    let rs2 = bits(inst, 6, 2);
+    // imm[5|4:0] = inst[12|6:2], used by most of quadrant 1 and 2.
+    let imm6 = (bits(inst, 12, 12) << 5) | bits(inst, 6, 2);
+    let funct3 = bits(inst, 15, 13);
+
+    let expanded = match (inst & 0b11, funct3) {
+        (0b00, 0b000) => {
+            // c.addi4spn: nzuimm[5:4|9:6|2|3] = inst[12:11|10:7|6|5]
+            let imm = (bits(inst, 12, 11) << 4) | (bits(inst, 10, 7) << 6)
+                | (bits(inst, 6, 6) << 2) | (bits(inst, 5, 5) << 3);
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm, 2, 0x0, rd_, 0x13)
+        }
+        (0b00, 0b001) | (0b00, 0b011) => {
+            // c.fld, c.ld: uimm[5:3|7:6] = inst[12:10|6:5]
+            let imm = (bits(inst, 12, 10) << 3) | (bits(inst, 6, 5) << 6);
+            let opcode = if funct3 == 0b001 { 0x07 } else { 0x03 };
+            i_type(imm, rs1_, 0x3, rd_, opcode)
+        }
+        (0b00, 0b010) => {
+            // c.lw: uimm[5:3|2|6] = inst[12:10|6|5]
+            let imm = (bits(inst, 12, 10) << 3) | (bits(inst, 6, 6) << 2) | (bits(inst, 5, 5) << 6);
+            i_type(imm, rs1_, 0x2, rd_, 0x03)
+        }
+        (0b00, 0b101) | (0b00, 0b111) => {
+            // c.fsd, c.sd
+            let imm = (bits(inst, 12, 10) << 3) | (bits(inst, 6, 5) << 6);
+            let opcode = if funct3 == 0b101 { 0x27 } else { 0x23 };
+            s_type(imm, rd_, rs1_, 0x3, opcode)
+        }
+        (0b00, 0b110) => {
+            // c.sw
+            let imm = (bits(inst, 12, 10) << 3) | (bits(inst, 6, 6) << 2) | (bits(inst, 5, 5) << 6);
+            s_type(imm, rd_, rs1_, 0x2, 0x23)
+        }
+        (0b01, 0b000) => {
+            // c.addi, c.nop
+            i_type(sext(imm6, 6), rd, 0x0, rd, 0x13)
+        }
+        (0b01, 0b001) => {
+            // c.addiw
+            if rd == 0 {
+                return None;
+            }
+            i_type(sext(imm6, 6), rd, 0x0, rd, 0x1b)
+        }
+        (0b01, 0b010) => {
+            // c.li
+            i_type(sext(imm6, 6), 0, 0x0, rd, 0x13)
+        }
+        (0b01, 0b011) if rd == 2 => {
+            // c.addi16sp: nzimm[9|4|6|8:7|5] = inst[12|6|5|4:3|2]
+            let imm = (bits(inst, 12, 12) << 9) | (bits(inst, 6, 6) << 4) | (bits(inst, 5, 5) << 6)
+                | (bits(inst, 4, 3) << 7) | (bits(inst, 2, 2) << 5);
+            if imm == 0 {
+                return None;
+            }
+            i_type(sext(imm, 10), 2, 0x0, 2, 0x13)
+        }
+        (0b01, 0b011) => {
+            // c.lui: nzimm[17|16:12] = inst[12|6:2]
+            if imm6 == 0 {
+                return None;
+            }
+            (sext(imm6, 6) << 12) | (rd << 7) | 0x37
+        }
+        (0b01, 0b100) => {
+            let rd = rs1_;
+            match (bits(inst, 11, 10), bits(inst, 12, 12), bits(inst, 6, 5)) {
+                // c.srli, c.srai
+                (0b00, _, _) => i_type(imm6, rd, 0x5, rd, 0x13),
+                (0b01, _, _) => i_type(imm6 | 0x400, rd, 0x5, rd, 0x13),
+                // c.andi
+                (0b10, _, _) => i_type(sext(imm6, 6), rd, 0x7, rd, 0x13),
+                // c.sub, c.xor, c.or, c.and
+                (0b11, 0, 0b00) => r_type(0x20, rd_, rd, 0x0, rd, 0x33),
+                (0b11, 0, 0b01) => r_type(0x00, rd_, rd, 0x4, rd, 0x33),
+                (0b11, 0, 0b10) => r_type(0x00, rd_, rd, 0x6, rd, 0x33),
+                (0b11, 0, 0b11) => r_type(0x00, rd_, rd, 0x7, rd, 0x33),
+                // c.subw, c.addw
+                (0b11, 1, 0b00) => r_type(0x20, rd_, rd, 0x0, rd, 0x3b),
+                (0b11, 1, 0b01) => r_type(0x00, rd_, rd, 0x0, rd, 0x3b),
+                _ => return None,
+            }
+        }
+        (0b01, 0b101) => {
+            // c.j: offset[11|4|9:8|10|6|7|3:1|5] = inst[12|11|10:9|8|7|6|5:3|2]
+            let imm = (bits(inst, 12, 12) << 11) | (bits(inst, 11, 11) << 4) | (bits(inst, 10, 9) << 8)
+                | (bits(inst, 8, 8) << 10) | (bits(inst, 7, 7) << 6) | (bits(inst, 6, 6) << 7)
+                | (bits(inst, 5, 3) << 1) | (bits(inst, 2, 2) << 5);
+            j_type(sext(imm, 12), 0)
+        }
+        (0b01, 0b110) | (0b01, 0b111) => {
+            // c.beqz, c.bnez: offset[8|4:3|7:6|2:1|5] = inst[12|11:10|6:5|4:3|2]
+            let imm = (bits(inst, 12, 12) << 8) | (bits(inst, 11, 10) << 3) | (bits(inst, 6, 5) << 6)
+                | (bits(inst, 4, 3) << 1) | (bits(inst, 2, 2) << 5);
+            b_type(sext(imm, 9), 0, rs1_, funct3 - 0b110)
+        }
+        (0b10, 0b000) => {
+            // c.slli
+            i_type(imm6, rd, 0x1, rd, 0x13)
+        }
+        (0b10, 0b001) | (0b10, 0b011) => {
+            // c.fldsp, c.ldsp: uimm[5|4:3|8:6] = inst[12|6:5|4:2]
+            if funct3 == 0b011 && rd == 0 {
+                return None;
+            }
+            let imm = (bits(inst, 12, 12) << 5) | (bits(inst, 6, 5) << 3) | (bits(inst, 4, 2) << 6);
+            let opcode = if funct3 == 0b001 { 0x07 } else { 0x03 };
+            i_type(imm, 2, 0x3, rd, opcode)
+        }
+        (0b10, 0b010) => {
+            // c.lwsp: uimm[5|4:2|7:6] = inst[12|6:4|3:2]
+            if rd == 0 {
+                return None;
+            }
+            let imm = (bits(inst, 12, 12) << 5) | (bits(inst, 6, 4) << 2) | (bits(inst, 3, 2) << 6);
+            i_type(imm, 2, 0x2, rd, 0x03)
+        }
+        (0b10, 0b100) => match (bits(inst, 12, 12), rd, rs2) {
+            // c.jr
+            (0, 0, 0) => return None,
+            (0, rs1, 0) => i_type(0, rs1, 0x0, 0, 0x67),
+            // c.mv
+            (0, rd, rs2) => r_type(0x00, rs2, 0, 0x0, rd, 0x33),
+            // c.ebreak
+            (1, 0, 0) => 0x00100073,
+            // c.jalr
+            (1, rs1, 0) => i_type(0, rs1, 0x0, 1, 0x67),
+            // c.add
+            (_, rd, rs2) => r_type(0x00, rs2, rd, 0x0, rd, 0x33),
+        },
+        (0b10, 0b101) | (0b10, 0b111) => {
+            // c.fsdsp, c.sdsp: uimm[5:3|8:6] = inst[12:10|9:7]
+            let imm = (bits(inst, 12, 10) << 3) | (bits(inst, 9, 7) << 6);
+            let opcode = if funct3 == 0b101 { 0x27 } else { 0x23 };
+            s_type(imm, rs2, 2, 0x3, opcode)
+        }
+        (0b10, 0b110) => {
+            // c.swsp: uimm[5:2|7:6] = inst[12:9|8:7]
+            let imm = (bits(inst, 12, 9) << 2) | (bits(inst, 8, 7) << 6);
+            s_type(imm, rs2, 2, 0x2, 0x23)
+        }
+        _ => return None,
+    };
+    Some(expanded)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        // c.li a0, 10 => addi a0, zero, 10
+        assert_eq!(expand(0x4529), Some(0x00a00513));
+        // c.addi sp, -16 => addi sp, sp, -16
+        assert_eq!(expand(0x1141), Some(0xff010113));
+        // c.addi16sp sp, -64 => addi sp, sp, -64
+        assert_eq!(expand(0x7139), Some(0xfc010113));
+        // c.addi4spn a0, sp, 16 => addi a0, sp, 16
+        assert_eq!(expand(0x0808), Some(0x01010513));
+        // c.lui a0, 0xfffe1 => lui a0, 0xfffe1
+        assert_eq!(expand(0x7505), Some(0xfffe1537));
+        // c.sdsp ra, 8(sp) => sd ra, 8(sp)
+        assert_eq!(expand(0xe406), Some(0x00113423));
+        // c.ldsp ra, 8(sp) => ld ra, 8(sp)
+        assert_eq!(expand(0x60a2), Some(0x00813083));
+        // c.lw a0, 4(a1) => lw a0, 4(a1)
+        assert_eq!(expand(0x41c8), Some(0x0045a503));
+        // c.sd a0, 8(a1) => sd a0, 8(a1)
+        assert_eq!(expand(0xe588), Some(0x00a5b423));
+        // c.srai a0, 1 => srai a0, a0, 1
+        assert_eq!(expand(0x8505), Some(0x40155513));
+        // c.sub a0, a1 => sub a0, a0, a1
+        assert_eq!(expand(0x8d0d), Some(0x40b50533));
+        // c.addw a0, a1 => addw a0, a0, a1
+        assert_eq!(expand(0x9d2d), Some(0x00b5053b));
+        // c.j -2 => jal zero, -2
+        assert_eq!(expand(0xbffd), Some(0xfffff06f));
+        // c.bnez a0, -4 => bne a0, zero, -4
+        assert_eq!(expand(0xfd75), Some(0xfe051ee3));
+        // c.mv a0, a1, c.add a0, a1
+        assert_eq!(expand(0x852e), Some(0x00b00533));
+        assert_eq!(expand(0x952e), Some(0x00b50533));
+        // c.jr ra, c.jalr a0, c.ebreak
+        assert_eq!(expand(0x8082), Some(0x00008067));
+        assert_eq!(expand(0x9502), Some(0x000500e7));
+        assert_eq!(expand(0x9002), Some(0x00100073));
+    }
+
+    #[test]
+    fn test_reserved() {
+        // all zeros, c.addi16sp 0, c.lui with 0, c.lwsp to x0, c.jr x0
+        for inst in [0x0000, 0x6101, 0x6501, 0x4002, 0x8002] {
+            assert_eq!(expand(inst), None, "{:#x}", inst);
+        }
+    }
+}
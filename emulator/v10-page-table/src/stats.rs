@@ -0,0 +1,74 @@
+//! The stats module counts the executed instructions by category, for a rough performance model.
+
+use crate::instruction::Instruction;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub loads: u64,
+    pub stores: u64,
+    pub branches_taken: u64,
+    pub branches_not_taken: u64,
+    /// jal and jalr.
+    pub jumps: u64,
+    /// Integer computation, including lui and auipc.
+    pub alu: u64,
+    /// The M extension.
+    pub mul_div: u64,
+    pub csr: u64,
+    pub amo: u64,
+    /// fence, ecall, ebreak, xret, wfi and sfence.vma.
+    pub system: u64,
+}
+
+impl Stats {
+    /// Count an instruction that executed without an exception. `taken` tells whether a branch
+    /// jumped.
+    pub fn record(&mut self, instruction: Instruction, taken: bool) {
+        use Instruction::*;
+        match instruction {
+            Lb { .. } | Lh { .. } | Lw { .. } | Ld { .. } | Lbu { .. } | Lhu { .. } | Lwu { .. } => {
+                self.loads += 1
+            }
+            Sb { .. } | Sh { .. } | Sw { .. } | Sd { .. } => self.stores += 1,
+            Beq { .. } | Bne { .. } | Blt { .. } | Bge { .. } | Bltu { .. } | Bgeu { .. } if taken => {
+                self.branches_taken += 1
+            }
+            Beq { .. } | Bne { .. } | Blt { .. } | Bge { .. } | Bltu { .. } | Bgeu { .. } => {
+                self.branches_not_taken += 1
+            }
+            Jalr { .. } | Jal { .. } => self.jumps += 1,
+            Mul { .. } | Divw { .. } | Divuw { .. } | Remw { .. } | Remuw { .. } => self.mul_div += 1,
+            AmoaddW { .. } | AmoaddD { .. } | AmoswapW { .. } | AmoswapD { .. } => self.amo += 1,
+            Csrrw { .. } | Csrrs { .. } | Csrrc { .. } | Csrrwi { .. } | Csrrsi { .. } | Csrrci { .. } => {
+                self.csr += 1
+            }
+            Fence | FenceI | Ecall | Ebreak | Sret | Mret | Wfi | SfenceVma { .. } => self.system += 1,
+            // The rest compute on the integer registers.
+            _ => self.alu += 1,
+        }
+    }
+
+    /// The number of all counted instructions.
+    pub fn total(&self) -> u64 {
+        self.loads + self.stores + self.branches_taken + self.branches_not_taken + self.jumps
+            + self.alu + self.mul_div + self.csr + self.amo + self.system
+    }
+
+    #[cfg(feature = "std")]
+    pub fn dump(&self) {
+        println!("{:-^80}", "instruction statistics");
+        println!(
+            "total = {:<12} alu = {:<12} mul/div = {:<12} loads = {:<12}",
+            self.total(), self.alu, self.mul_div, self.loads,
+        );
+        println!(
+            "stores = {:<11} amo = {:<12} jumps = {:<14} csr = {:<14}",
+            self.stores, self.amo, self.jumps, self.csr,
+        );
+        println!(
+            "branches = {:<9} taken = {:<10} not taken = {:<10} system = {:<11}\n",
+            self.branches_taken + self.branches_not_taken, self.branches_taken,
+            self.branches_not_taken, self.system,
+        );
+    }
+}
@@ -0,0 +1,36 @@
+//! The testutil module runs the test programs assembled by the rv-testutil crate on a fresh
+//! `Cpu`. Tests of hand-encoded instructions run without the toolchain.
+
+pub use rv_testutil::*;
+
+use crate::cpu::*;
+
+/// Lay out hand-encoded instructions (or data words) as a flat binary, for the tests that run
+/// without a RISC-V toolchain.
+pub fn encode(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// Run `clocks` instructions of `code` on a new `Cpu`. An exception is printed instead of being
+/// trapped, and a fetch error ends the run early.
+pub fn compile_and_run(code: &str, clocks: usize) -> Cpu {
+    run_binary(compile(code), clocks)
+}
+
+/// Run `clocks` instructions of the flat binary `binary` like `compile_and_run`.
+pub fn run_binary(binary: Vec<u8>, clocks: usize) -> Cpu {
+    let mut cpu = Cpu::new(binary, vec![]);
+
+    for _i in 0..clocks {
+        let inst = match cpu.fetch() {
+            Ok(inst) => inst,
+            Err(_err) => break,
+        };
+        match cpu.execute(inst) {
+            Ok(new_pc) => cpu.pc = new_pc,
+            Err(err) => println!("{}", err),
+        };
+    }
+
+    cpu
+}
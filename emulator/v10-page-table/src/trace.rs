@@ -0,0 +1,49 @@
+//! The trace module contains the binary trace format. When `Cpu::trace_file` is set, every
+//! executed instruction appends one fixed-width record, so a trace of millions of instructions
+//! is cheap to write and easy to parse offline.
+//!
+//! A record is RECORD_SIZE bytes, little-endian:
+//!
+//! | offset | size | field  |
+//! |--------|------|--------|
+//! | 0      | 8    | pc     |
+//! | 8      | 4    | inst   |
+//! | 12     | 1    | hartid |
+//! | 13     | 1    | rd     |
+//! | 14     | 2    | 0      |
+//! | 16     | 8    | value  |
+
+/// The size in bytes of a record.
+pub const RECORD_SIZE: usize = 24;
+
+/// An executed instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u64,
+    /// The raw instruction, whose upper half is 0 if it is compressed.
+    pub inst: u32,
+    pub hartid: u8,
+    /// The integer register whose value the instruction changed, or 0 if none did.
+    pub rd: u8,
+    /// The new value of `rd`, or 0.
+    pub value: u64,
+}
+
+impl TraceRecord {
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.inst.to_le_bytes());
+        bytes[12] = self.hartid;
+        bytes[13] = self.rd;
+        bytes[16..24].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Self {
+        let word = |range: core::ops::Range<usize>| {
+            bytes[range].iter().rev().fold(0, |value, &byte| (value << 8) | byte as u64)
+        };
+        Self { pc: word(0..8), inst: word(8..12) as u32, hartid: bytes[12], rd: bytes[13], value: word(16..24) }
+    }
+}
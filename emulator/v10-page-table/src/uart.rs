@@ -17,6 +17,7 @@ use crate::exception::*;
 use crate::param::*;
 
 pub struct Uart {
+    base: u64,
     /// Pair of an array for UART buffer and a conditional variable.
     uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
     /// Bit if an interrupt happens.
@@ -25,8 +26,8 @@ pub struct Uart {
 
 
 impl Uart {
-    /// Create a new `Uart` object.
-    pub fn new() -> Self {
+    /// Create a new `Uart` object at `base`. The default address is `UART_BASE`.
+    pub fn new(base: u64) -> Self {
         let mut array = [0; UART_SIZE as usize];
         array[UART_LSR as usize] |= MASK_UART_LSR_TX;
 
@@ -56,7 +57,7 @@ impl Uart {
             }
         });
         
-        Self { uart, interrupt }
+        Self { base, uart, interrupt }
     }
 
     /// Return true if an interrupt is pending. Clear the interrupt flag by swapping a value.
@@ -64,14 +65,29 @@ impl Uart {
         self.interrupt.swap(false, Ordering::Acquire)
     }
 
+    /// Raise the interrupt like a received byte does, for a test that cannot write to stdin.
+    #[cfg(test)]
+    pub fn raise_interrupt(&self) {
+        self.interrupt.store(true, Ordering::Release);
+    }
+}
+
+impl Device for Uart {
+    fn base(&self) -> u64 {
+        self.base
+    }
 
-    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+    fn size(&self) -> u64 {
+        UART_SIZE
+    }
+
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         if size != 8 {
             return Err(Exception::LoadAccessFault(addr));
         }
         let (uart, cvar) = &*self.uart;
         let mut array = uart.lock().unwrap(); 
-        let index = addr - UART_BASE;
+        let index = addr - self.base;
         // a read happens
         match index {
             UART_RHR => {
@@ -83,13 +99,13 @@ impl Uart {
         } 
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if size != 8 {
             return Err(Exception::StoreAMOAccessFault(addr));
         }
         let (uart, cvar) = &*self.uart;
         let mut array = uart.lock().unwrap();
-        let index = addr - UART_BASE;
+        let index = addr - self.base;
         match index {
             UART_THR => {
                 print!("{}", value as u8 as char);
@@ -102,4 +118,29 @@ impl Uart {
             }
         }
     }
+
+    fn snapshot(&self, data: &mut Vec<u8>) {
+        let (uart, _) = &*self.uart;
+        data.extend_from_slice(&*uart.lock().unwrap());
+        put_word(data, self.interrupt.load(Ordering::Acquire) as u64);
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let mut reader = SnapshotReader::new(data);
+        let (uart, cvar) = &*self.uart;
+        uart.lock().unwrap().copy_from_slice(reader.bytes(UART_SIZE as usize));
+        self.interrupt.store(reader.word() != 0, Ordering::Release);
+        // The receiving thread waits until RX is clear, which the restored LSR may say.
+        cvar.notify_one();
+    }
+
+    fn reset(&mut self) {
+        let (uart, cvar) = &*self.uart;
+        let mut array = uart.lock().unwrap();
+        *array = [0; UART_SIZE as usize];
+        array[UART_LSR as usize] |= MASK_UART_LSR_TX;
+        self.interrupt.store(false, Ordering::Release);
+        // A byte that was not read yet is dropped, so the receiving thread may go on.
+        cvar.notify_one();
+    }
 }
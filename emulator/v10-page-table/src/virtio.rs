@@ -4,6 +4,13 @@
 //! The virtio spec:
 //! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
 
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use alloc::{collections::{btree_map::Entry, BTreeMap}, vec};
+#[cfg(feature = "std")]
+use std::{fs::File, io::{self, Read, Seek, SeekFrom, Write}};
+
 use crate::cpu::*;
 use crate::exception::*;
 use crate::param::*;
@@ -12,6 +19,7 @@ use Exception::*;
 
 
 pub struct VirtioBlock {
+    base: u64,
     id: u64,
     driver_features: u32,
     page_size: u32,
@@ -20,17 +28,77 @@ pub struct VirtioBlock {
     queue_pfn: u32,
     queue_notify: u32,
     status: u32,
-    disk: Vec<u8>,
+    disk: Disk,
+}
+
+/// The storage behind the disk.
+enum Disk {
+    /// The whole image is held in memory.
+    Memory(Vec<u8>),
+    /// The image file is read on demand, so it does not have to fit in memory. Written sectors
+    /// are kept in `dirty` until `flush` writes them back.
+    #[cfg(feature = "std")]
+    File { file: File, size: u64, dirty: BTreeMap<u64, Vec<u8>> },
 }
 
 const MAX_BLOCK_QUEUE: u32 = 1;
 
+/// A malformed request in the virtqueue. The request is dropped instead of accessing memory out of
+/// bounds, and `run` stops with the error.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DiskError {
+    /// A descriptor index that is not less than DESC_NUM.
+    DescIndex(u64),
+    /// A buffer of `len` bytes at `addr` that is not inside the dram.
+    BufferOutOfRange { addr: u64, len: u64 },
+    /// A transfer of `len` bytes at byte `offset` that goes past the end of the disk image.
+    DiskOutOfRange { offset: u64, len: u64 },
+    /// A request type other than VIRTIO_BLK_T_IN and VIRTIO_BLK_T_OUT.
+    IoType(u32),
+    /// An access to the dram at this address that failed, e.g. a read of a byte that was never
+    /// written while uninitialized reads are detected.
+    GuestMemory(u64),
+    /// The image file of a file-backed disk could not be read or written.
+    #[cfg(feature = "std")]
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskError::DescIndex(index) => write!(f, "Invalid descriptor index {}", index),
+            DiskError::BufferOutOfRange { addr, len } => {
+                write!(f, "Buffer {:#x} of {} bytes is out of the dram", addr, len)
+            }
+            DiskError::DiskOutOfRange { offset, len } => {
+                write!(f, "Transfer at {:#x} of {} bytes is out of the disk", offset, len)
+            }
+            DiskError::IoType(iotype) => write!(f, "Invalid request type {}", iotype),
+            DiskError::GuestMemory(addr) => write!(f, "Cannot access the dram at {:#x}", addr),
+            #[cfg(feature = "std")]
+            DiskError::Io(kind) => write!(f, "Cannot access the disk image: {}", kind),
+        }
+    }
+}
+
 impl VirtioBlock {
-    pub fn new(disk_image: Vec<u8>) -> Self {
-        let mut disk = Vec::new();
-        disk.extend(disk_image.into_iter());
+    /// Create a disk at `base` that holds `disk_image` in memory. The default address is
+    /// `VIRTIO_BASE`.
+    pub fn new(base: u64, disk_image: Vec<u8>) -> Self {
+        Self::with_disk(base, Disk::Memory(disk_image))
+    }
+
+    /// Back the disk with an image file instead of reading it into memory. Writes reach the file
+    /// when the disk is flushed, which happens after every write request and on drop.
+    #[cfg(feature = "std")]
+    pub fn from_file(base: u64, file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        Ok(Self::with_disk(base, Disk::File { file, size, dirty: BTreeMap::new() }))
+    }
 
+    fn with_disk(base: u64, disk: Disk) -> Self {
         Self {
+            base,
             id: 0, 
             driver_features: 0,
             page_size: 0,
@@ -51,12 +119,136 @@ impl VirtioBlock {
         return false;
     }
     
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+
+    pub fn get_new_id(&mut self) -> u64 {
+        self.id = self.id.wrapping_add(1);
+        return self.id;
+    }
+
+    pub fn desc_addr(&self) -> u64 {
+        self.queue_pfn as u64 * self.page_size as u64
+    }
+
+    /// The size of the disk image in bytes.
+    pub fn disk_size(&self) -> u64 {
+        match &self.disk {
+            Disk::Memory(disk) => disk.len() as u64,
+            #[cfg(feature = "std")]
+            Disk::File { size, .. } => *size,
+        }
+    }
+
+    /// Copy the bytes of the disk at byte `offset` into `buf`. The range must be inside the disk.
+    pub fn read_disk_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), DiskError> {
+        match &self.disk {
+            Disk::Memory(disk) => {
+                buf.copy_from_slice(&disk[offset as usize..offset as usize + buf.len()]);
+                Ok(())
+            }
+            #[cfg(feature = "std")]
+            Disk::File { file, dirty, .. } => {
+                read_file(file, offset, buf)?;
+                // The dirty sectors are newer than the file.
+                let end = offset + buf.len() as u64;
+                for (&sector, data) in dirty.range(offset / SECTOR_SIZE..end.div_ceil(SECTOR_SIZE)) {
+                    let start = sector * SECTOR_SIZE;
+                    let (from, to) = (start.max(offset), (start + data.len() as u64).min(end));
+                    buf[(from - offset) as usize..(to - offset) as usize]
+                        .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy `buf` to the disk at byte `offset`. The range must be inside the disk.
+    pub fn write_disk_bytes(&mut self, offset: u64, buf: &[u8]) -> Result<(), DiskError> {
+        match &mut self.disk {
+            Disk::Memory(disk) => {
+                disk[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+                Ok(())
+            }
+            #[cfg(feature = "std")]
+            Disk::File { file, size, dirty } => {
+                let end = offset + buf.len() as u64;
+                for sector in offset / SECTOR_SIZE..end.div_ceil(SECTOR_SIZE) {
+                    let start = sector * SECTOR_SIZE;
+                    // The last sector of an image whose size is not a multiple of SECTOR_SIZE is short.
+                    let data = match dirty.entry(sector) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            let mut data = vec![0; (*size - start).min(SECTOR_SIZE) as usize];
+                            read_file(file, start, &mut data)?;
+                            entry.insert(data)
+                        }
+                    };
+                    let (from, to) = (start.max(offset), (start + data.len() as u64).min(end));
+                    data[(from - start) as usize..(to - start) as usize]
+                        .copy_from_slice(&buf[(from - offset) as usize..(to - offset) as usize]);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the dirty sectors back to the image file. An in-memory disk has nothing to flush.
+    pub fn flush(&mut self) -> Result<(), DiskError> {
+        match &mut self.disk {
+            Disk::Memory(_) => Ok(()),
+            #[cfg(feature = "std")]
+            Disk::File { file, dirty, .. } => {
+                for (&sector, data) in dirty.iter() {
+                    file.seek(SeekFrom::Start(sector * SECTOR_SIZE))
+                        .and_then(|_| file.write_all(data))
+                        .map_err(|e| DiskError::Io(e.kind()))?;
+                }
+                dirty.clear();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn read_disk(&self, addr: u64) -> u64 {
+        let mut byte = [0];
+        self.read_disk_bytes(addr, &mut byte).unwrap();
+        byte[0] as u64
+    }
+
+    pub fn write_disk(&mut self, addr: u64, value: u64) {
+        self.write_disk_bytes(addr, &[value as u8]).unwrap();
+    }
+}
+
+/// Read the bytes of `file` at `offset` into `buf`. A shared reference is enough to read a file,
+/// which keeps `read_disk` usable on `&self`.
+#[cfg(feature = "std")]
+fn read_file(mut file: &File, offset: u64, buf: &mut [u8]) -> Result<(), DiskError> {
+    file.seek(SeekFrom::Start(offset)).and_then(|_| file.read_exact(buf)).map_err(|e| DiskError::Io(e.kind()))
+}
+
+impl Drop for VirtioBlock {
+    fn drop(&mut self) {
+        // There is no one to report an error to.
+        let _ = self.flush();
+    }
+}
+
+impl Device for VirtioBlock {
+    fn base(&self) -> u64 {
+        self.base
+    }
+
+    fn size(&self) -> u64 {
+        VIRTIO_SIZE
+    }
+
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         if size != 32 {
             return Err(LoadAccessFault(addr));
         }
 
-        match addr {
+        // The register constants name the addresses in the default map.
+        match addr - self.base + VIRTIO_BASE {
             VIRTIO_MAGIC => Ok(0x74726976),
             VIRTIO_VERSION => Ok(0x1),
             VIRTIO_DEVICE_ID => Ok(0x2),
@@ -70,14 +262,14 @@ impl VirtioBlock {
         }
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if size != 32 {
             return Err(StoreAMOAccessFault(addr));
         }
 
         let value = value as u32;
         
-        match addr {
+        match addr - self.base + VIRTIO_BASE {
             VIRTIO_DEVICE_FEATURES => Ok(self.driver_features = value),
             VIRTIO_GUEST_PAGE_SIZE => Ok(self.page_size = value),
             VIRTIO_QUEUE_SEL => Ok(self.queue_sel = value),
@@ -89,20 +281,67 @@ impl VirtioBlock {
         }
     }
 
-    pub fn get_new_id(&mut self) -> u64 {
-        self.id = self.id.wrapping_add(1);
-        return self.id;
+    fn snapshot(&self, data: &mut Vec<u8>) {
+        put_word(data, self.id);
+        let registers = [self.driver_features, self.page_size, self.queue_sel, self.queue_num, self.queue_pfn, self.queue_notify, self.status];
+        for register in registers {
+            put_word(data, register as u64);
+        }
     }
 
-    pub fn desc_addr(&self) -> u64 {
-        self.queue_pfn as u64 * self.page_size as u64
+    fn restore(&mut self, data: &[u8]) {
+        let mut reader = SnapshotReader::new(data);
+        self.id = reader.word();
+        let registers = [
+            &mut self.driver_features, &mut self.page_size, &mut self.queue_sel, &mut self.queue_num,
+            &mut self.queue_pfn, &mut self.queue_notify, &mut self.status,
+        ];
+        for register in registers {
+            *register = reader.word() as u32;
+        }
     }
 
-    pub fn read_disk(&self, addr: u64) -> u64 {
-        self.disk[addr as usize] as u64
+    fn reset(&mut self) {
+        let disk = core::mem::replace(&mut self.disk, Disk::Memory(Vec::new()));
+        *self = Self::with_disk(self.base, disk);
     }
+}
 
-    pub fn write_disk(&mut self, addr: u64, value: u64) {
-        self.disk[addr as usize] = value as u8;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    fn test_flush() {
+        let path = std::env::temp_dir().join(format!("rvemu_flush_{}", std::process::id()));
+        // 2.5 sectors, so the last one is short.
+        fs::write(&path, vec![0; 5 * SECTOR_SIZE as usize / 2]).unwrap();
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut disk = VirtioBlock::from_file(VIRTIO_BASE, file).unwrap();
+
+        // Write across the end of sector 0 and into the short sector 2.
+        let data: Vec<u8> = (1..=16).collect();
+        disk.write_disk_bytes(SECTOR_SIZE - 8, &data).unwrap();
+        disk.write_disk_bytes(2 * SECTOR_SIZE + 0xf8, &data[..8]).unwrap();
+        let mut buf = vec![0; 16];
+        disk.read_disk_bytes(SECTOR_SIZE - 8, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        // Nothing reaches the file before the flush.
+        assert!(fs::read(&path).unwrap().iter().all(|&byte| byte == 0));
+
+        disk.flush().unwrap();
+        let image = fs::read(&path).unwrap();
+        assert_eq!(image.len(), 5 * SECTOR_SIZE as usize / 2);
+        assert_eq!(&image[SECTOR_SIZE as usize - 8..SECTOR_SIZE as usize + 8], &data[..]);
+        assert_eq!(&image[2 * SECTOR_SIZE as usize + 0xf8..], &data[..8]);
+        assert_eq!(image.iter().filter(|&&byte| byte != 0).count(), 24);
+
+        // Dropping the disk flushes the last writes.
+        disk.write_disk_bytes(0, &[0xaa]).unwrap();
+        drop(disk);
+        assert_eq!(fs::read(&path).unwrap()[0], 0xaa);
+        fs::remove_file(&path).unwrap();
     }
-}
\ No newline at end of file
+}
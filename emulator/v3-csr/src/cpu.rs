@@ -544,63 +544,11 @@ impl Cpu {
 
 #[cfg(test)]
 mod test {
-    use std::fs::File;
-    use std::io::{Write, Read};
-    use std::process::Command;
+    use rv_testutil::*;
     use super::*;
 
-    fn generate_rv_assembly(c_src: &str) {
-        let cc = "clang";
-        let output = Command::new(cc).arg("-S")
-                            .arg(c_src)
-                            .arg("-nostdlib")
-                            .arg("-march=rv64g")
-                            .arg("-mabi=lp64")
-                            .arg("--target=riscv64")
-                            .arg("-mno-relax")
-                            .output()
-                            .expect("Failed to generate rv assembly");
-        println!("{}", String::from_utf8_lossy(&output.stderr));
-    }
-
-    fn generate_rv_obj(assembly: &str) {
-        let cc = "clang";
-        let pieces: Vec<&str> = assembly.split(".").collect();
-        let output = Command::new(cc).arg("-Wl,-Ttext=0x0")
-                            .arg("-nostdlib")
-                            .arg("-march=rv64g")
-                            .arg("-mabi=lp64")
-                            .arg("--target=riscv64")
-                            .arg("-mno-relax")
-                            .arg("-o")
-                            .arg(&pieces[0])
-                            .arg(assembly)
-                            .output()
-                            .expect("Failed to generate rv object");
-        println!("{}", String::from_utf8_lossy(&output.stderr));
-    }
-
-    fn generate_rv_binary(obj: &str) {
-        let objcopy = "llvm-objcopy";
-        let output = Command::new(objcopy).arg("-O")
-                                .arg("binary")
-                                .arg(obj)
-                                .arg(obj.to_owned() + ".bin")
-                                .output()
-                                .expect("Failed to generate rv binary");
-        println!("{}", String::from_utf8_lossy(&output.stderr));
-    }
-
-    fn rv_helper(code: &str, testname: &str, n_clock: usize) -> Result<Cpu, std::io::Error> {
-        let filename = testname.to_owned() + ".s";
-        let mut file = File::create(&filename)?;
-        file.write(&code.as_bytes())?;
-        generate_rv_obj(&filename);
-        generate_rv_binary(testname);
-        let mut file_bin = File::open(testname.to_owned() + ".bin")?;
-        let mut code = Vec::new();
-        file_bin.read_to_end(&mut code)?;
-        let mut cpu = Cpu::new(code);
+    fn compile_and_run(code: &str, n_clock: usize) -> Cpu {
+        let mut cpu = Cpu::new(compile(code));
 
         for _i in 0..n_clock {
             let inst = match cpu.fetch() {
@@ -613,24 +561,20 @@ mod test {
             };
         }
 
-        return Ok(cpu);
+        cpu
     }
 
     macro_rules! riscv_test {
-        ( $code:expr, $name:expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
-            match rv_helper($code, $name, $clock) {
-                Ok(cpu) => { 
-                    $(assert_eq!(cpu.reg($real), $expect);)*
-                }
-                Err(e) => { println!("error: {}", e); assert!(false); }
-            } 
+        ( $code:expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
+            let cpu = compile_and_run($code, $clock);
+            $(assert_eq!(cpu.reg($real), $expect);)*
         };
     }
 
     #[test]
     fn test_addi() {
         let code = "addi x31, x0, 42";
-        riscv_test!(code, "test_addi", 1, "x31" => 42);
+        riscv_test!(code, 1, "x31" => 42);
     }
 
     #[test]
@@ -646,25 +590,25 @@ mod test {
             addi	sp,sp,16
             jr	ra
         ";
-        riscv_test!(code, "test_simple", 20, "a0" => 42);
+        riscv_test!(code, 20, "a0" => 42);
     }
 
     #[test]
     fn test_lui() {
         let code = "lui a0, 42";
-        riscv_test!(code, "test_lui", 1, "a0" => 42 << 12);
+        riscv_test!(code, 1, "a0" => 42 << 12);
     }
 
     #[test]
     fn test_auipc() {
         let code = "auipc a0, 42";
-        riscv_test!(code, "test_auipc", 1, "a0" => DRAM_BASE + (42 << 12));
+        riscv_test!(code, 1, "a0" => DRAM_BASE + (42 << 12));
     }
 
     #[test]
     fn test_jal() {
         let code = "jal a0, 42";
-        riscv_test!(code, "test_jal", 1, "a0" => DRAM_BASE + 4, "pc" => DRAM_BASE + 42);
+        riscv_test!(code, 1, "a0" => DRAM_BASE + 4, "pc" => DRAM_BASE + 42);
     }
 
     #[test]
@@ -673,7 +617,7 @@ mod test {
             addi a1, zero, 42
             jalr a0, -8(a1)
         ";
-        riscv_test!(code, "test_jalr", 2, "a0" => DRAM_BASE + 8, "pc" => 34);
+        riscv_test!(code, 2, "a0" => DRAM_BASE + 8, "pc" => 34);
     }
 
     #[test]
@@ -681,7 +625,7 @@ mod test {
         let code = "
             beq  x0, x0, 42
         ";
-        riscv_test!(code, "test_beq", 3, "pc" => DRAM_BASE + 42);
+        riscv_test!(code, 3, "pc" => DRAM_BASE + 42);
     }
 
     #[test]
@@ -690,7 +634,7 @@ mod test {
             addi x1, x0, 10
             bne  x0, x1, 42
         ";
-        riscv_test!(code, "test_bne", 5, "pc" => DRAM_BASE + 42 + 4);
+        riscv_test!(code, 5, "pc" => DRAM_BASE + 42 + 4);
     }
 
     #[test]
@@ -700,7 +644,7 @@ mod test {
             addi x2, x0, 20
             blt  x1, x2, 42
         ";
-        riscv_test!(code, "test_blt", 10, "pc" => DRAM_BASE + 42 + 8);
+        riscv_test!(code, 10, "pc" => DRAM_BASE + 42 + 8);
     }
 
     #[test]
@@ -710,7 +654,7 @@ mod test {
             addi x2, x0, 20
             bge  x2, x1, 42
         ";
-        riscv_test!(code, "test_bge", 10, "pc" => DRAM_BASE + 42 + 8);
+        riscv_test!(code, 10, "pc" => DRAM_BASE + 42 + 8);
     }
 
     #[test]
@@ -720,7 +664,7 @@ mod test {
             addi x2, x0, 20
             bltu x1, x2, 42
         ";
-        riscv_test!(code, "test_bltu", 10, "pc" => DRAM_BASE + 42 + 8);
+        riscv_test!(code, 10, "pc" => DRAM_BASE + 42 + 8);
     }
 
     #[test]
@@ -730,7 +674,7 @@ mod test {
             addi x2, x0, 20
             bgeu x2, x1, 42
         ";
-        riscv_test!(code, "test_bgeu", 10, "pc" => DRAM_BASE + 42 + 8);
+        riscv_test!(code, 10, "pc" => DRAM_BASE + 42 + 8);
     }
 
     #[test]
@@ -742,7 +686,7 @@ mod test {
             lb   t1, 8(sp)
             lh   t2, 8(sp)
         ";
-        riscv_test!(code, "test_store_load1", 10, "t1" => 0, "t2" => 256);
+        riscv_test!(code, 10, "t1" => 0, "t2" => 256);
     }
 
     #[test]
@@ -754,7 +698,7 @@ mod test {
             slti t3, t0, 42
             sltiu t4, t0, 84
         ";
-        riscv_test!(code, "test_slt", 7, "t2" => 1, "t3" => 1, "t4" => 1);
+        riscv_test!(code, 7, "t2" => 1, "t3" => 1, "t4" => 1);
     }
 
     #[test]
@@ -764,7 +708,7 @@ mod test {
             xori a1, a0, 0b01
             xor a2, a1, a1 
         ";
-        riscv_test!(code, "test_xor", 5, "a1" => 3, "a2" => 0);
+        riscv_test!(code, 5, "a1" => 3, "a2" => 0);
     }
 
     #[test]
@@ -774,7 +718,7 @@ mod test {
             ori  a1, a0, 0b01
             or   a2, a0, a0
         ";
-        riscv_test!(code, "test_or", 3, "a1" => 0b11, "a2" => 0b10);
+        riscv_test!(code, 3, "a1" => 0b11, "a2" => 0b10);
     }
 
     #[test]
@@ -784,7 +728,7 @@ mod test {
             andi a1, a0, 0b11
             and  a2, a0, a1
         ";
-        riscv_test!(code, "test_and", 3, "a1" => 0b10, "a2" => 0b10);
+        riscv_test!(code, 3, "a1" => 0b10, "a2" => 0b10);
     }
 
     #[test]
@@ -797,7 +741,7 @@ mod test {
             addi s0, zero, 64
             sll  a4, a0, s0
         ";
-        riscv_test!(code, "test_sll", 10, "a2" => 1 << 5, "a3" => 1 << 5, "a4" => 1);
+        riscv_test!(code, 10, "a2" => 1 << 5, "a3" => 1 << 5, "a4" => 1);
     }
 
     #[test]
@@ -810,7 +754,7 @@ mod test {
             srli a4, a0, 2
             srl  a5, a0, a1
         ";
-        riscv_test!(code, "test_sra_srl", 10, "a2" => -4 as i64 as u64, "a3" => -2 as i64 as u64, 
+        riscv_test!(code, 10, "a2" => -4 as i64 as u64, "a3" => -2 as i64 as u64, 
                                               "a4" => -8 as i64 as u64 >> 2, "a5" => -8 as i64 as u64 >> 1);
     }
 
@@ -821,7 +765,7 @@ mod test {
             lui  a1, 0x7f000
             addw a2, a0, a1
         ";
-        riscv_test!(code, "test_word_op", 29, "a2" => 0x7f00002a);
+        riscv_test!(code, 29, "a2" => 0x7f00002a);
     }
 
     #[test]
@@ -839,7 +783,7 @@ mod test {
             csrrwi zero, sepc, 6
             csrrci zero, sepc, 0 
         ";
-        riscv_test!(code, "test_csrs1", 20, "mstatus" => 1, "mtvec" => 2, "mepc" => 3,
+        riscv_test!(code, 20, "mstatus" => 1, "mtvec" => 2, "mepc" => 3,
                                             "sstatus" => 0, "stvec" => 5, "sepc" => 6);
     }
 }
\ No newline at end of file
@@ -29,7 +29,7 @@ fn main() -> io::Result<()> {
             // Break the loop if an error occurs.
             Ok(inst) => inst,
             Err(e) => {
-                println("{}", e);
+                println!("{}", e);
                 break;
             }
         };
@@ -38,7 +38,7 @@ fn main() -> io::Result<()> {
             // Break the loop if an error occurs.
             Ok(new_pc) => cpu.pc = new_pc,
             Err(e) => {
-                println("{}", e);
+                println!("{}", e);
                 break;
             }
         };